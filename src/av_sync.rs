@@ -0,0 +1,339 @@
+use crate::flv::TagInfo;
+use crate::flv_demuxer::{FlvHeader, RawTag, TAG_TYPE_AUDIO, TAG_TYPE_VIDEO};
+use crate::flv_writer::FlvWriter;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Result as IoResult;
+use std::io::Write;
+
+/// a step between two tags of a track longer than this is reported as a gap
+pub const DEFAULT_GAP_MS: u32 = 1000;
+/// the A/V offset is sampled once per this much video time
+const OFFSET_INTERVAL_MS: u32 = 10_000;
+/// how long tags are held back to sort them when re-interleaving
+pub const DEFAULT_WINDOW_MS: u32 = 1000;
+
+/// a step of one track's timestamps longer than the gap limit
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gap {
+    /// of the tag after the gap
+    pub offset: usize,
+    pub from: u32,
+    pub to: u32,
+}
+
+/// Timestamps of the audio or the video tags of a file
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TrackStats {
+    pub tags: usize,
+    /// first and last presentation time, dts + cts for video
+    pub first_pts: Option<i64>,
+    pub last_pts: i64,
+    /// first to last presentation plus the last frame's duration
+    pub duration_ms: i64,
+    /// dts steps going back
+    pub backward: usize,
+    pub gaps: Vec<Gap>,
+    // dts and step of the last tag
+    last_dts: Option<u32>,
+    last_step: i64,
+}
+
+impl TrackStats {
+    fn add(&mut self, tag: &TagInfo, gap_ms: u32) {
+        let pts = tag.timestamp as i64 + tag.composition_time.unwrap_or(0) as i64;
+        if let Some(last_dts) = self.last_dts {
+            let step = tag.timestamp as i64 - last_dts as i64;
+            if step < 0 {
+                self.backward += 1;
+            } else if step > gap_ms as i64 {
+                self.gaps.push(Gap {
+                    offset: tag.offset,
+                    from: last_dts,
+                    to: tag.timestamp,
+                });
+            } else if step > 0 {
+                self.last_step = step;
+            }
+        }
+        self.tags += 1;
+        self.last_dts = Some(tag.timestamp);
+        let first_pts = *self.first_pts.get_or_insert(pts);
+        self.last_pts = self.last_pts.max(pts);
+        self.duration_ms = self.last_pts - first_pts + self.last_step;
+    }
+}
+
+/// audio pts - video pts of the latest tags at one point of the file
+#[derive(Debug, Clone, PartialEq)]
+pub struct OffsetSample {
+    /// video dts
+    pub at: u32,
+    pub audio_minus_video: i64,
+}
+
+/// How audio and video of a file line up
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SyncReport {
+    pub audio: TrackStats,
+    pub video: TrackStats,
+    pub offsets: Vec<OffsetSample>,
+    /// how far the dts of one track ran ahead of the other's in file order
+    pub audio_ahead_ms: i64,
+    pub video_ahead_ms: i64,
+}
+
+impl SyncReport {
+    /// offset at the end minus offset at the start
+    pub fn drift_ms(&self) -> i64 {
+        match (self.offsets.first(), self.offsets.last()) {
+            (Some(first), Some(last)) => last.audio_minus_video - first.audio_minus_video,
+            _ => 0,
+        }
+    }
+}
+
+/// Go through the tags in file order
+pub fn analyze(tags: &[TagInfo], gap_ms: u32) -> SyncReport {
+    let mut report = SyncReport::default();
+    let mut next_sample = 0;
+    for tag in tags {
+        match tag.tag_type {
+            TAG_TYPE_AUDIO => report.audio.add(tag, gap_ms),
+            TAG_TYPE_VIDEO => report.video.add(tag, gap_ms),
+            _ => continue,
+        }
+        let (audio_dts, video_dts) = match (report.audio.last_dts, report.video.last_dts) {
+            (Some(audio_dts), Some(video_dts)) => (audio_dts as i64, video_dts as i64),
+            _ => continue,
+        };
+        report.audio_ahead_ms = report.audio_ahead_ms.max(audio_dts - video_dts);
+        report.video_ahead_ms = report.video_ahead_ms.max(video_dts - audio_dts);
+
+        if tag.tag_type == TAG_TYPE_VIDEO && video_dts >= next_sample {
+            report.offsets.push(OffsetSample {
+                at: video_dts as u32,
+                audio_minus_video: report.audio.last_pts - report.video.last_pts,
+            });
+            next_sample = video_dts + OFFSET_INTERVAL_MS as i64;
+        }
+    }
+    report
+}
+
+impl fmt::Display for TrackStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "tags:{} start:{} duration:{}ms backward:{} gaps:{}",
+            self.tags,
+            self.first_pts.unwrap_or(0),
+            self.duration_ms,
+            self.backward,
+            self.gaps.len()
+        )?;
+        for gap in &self.gaps {
+            write!(
+                f,
+                "\n    gap at offset {}: {} -> {} ({}ms)",
+                gap.offset,
+                gap.from,
+                gap.to,
+                gap.to - gap.from
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SyncReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "audio: {}", self.audio)?;
+        writeln!(f, "video: {}", self.video)?;
+        writeln!(
+            f,
+            "interleaving: audio ahead up to {}ms, video ahead up to {}ms",
+            self.audio_ahead_ms, self.video_ahead_ms
+        )?;
+        writeln!(
+            f,
+            "a/v offset (audio - video), drift {}ms:",
+            self.drift_ms()
+        )?;
+        for sample in &self.offsets {
+            writeln!(f, "  {:>10}ms {:>6}ms", sample.at, sample.audio_minus_video)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes an FLV file with the tags in strict dts order.
+/// Tags are held back for up to window_ms to sort them, tags later than that
+/// can not be moved further and get the timestamp of the tag before them.
+pub struct Reinterleaver<W: Write> {
+    out: FlvWriter<W>,
+    window_ms: u32,
+    // by dts, then arrival
+    pending: BTreeMap<(u32, u64), RawTag>,
+    arrived: u64,
+    newest: u32,
+    last_written: Option<u32>,
+    /// tags that arrived before tags with a larger dts
+    pub reordered: u64,
+    /// tags that came too late for the window
+    pub clamped: u64,
+}
+
+impl<W: Write> Reinterleaver<W> {
+    pub fn new(out: W, header: &FlvHeader, window_ms: u32) -> IoResult<Self> {
+        Ok(Self {
+            out: FlvWriter::new(out, header)?,
+            window_ms,
+            pending: BTreeMap::new(),
+            arrived: 0,
+            newest: 0,
+            last_written: None,
+            reordered: 0,
+            clamped: 0,
+        })
+    }
+
+    pub fn push(&mut self, tag: RawTag) -> IoResult<()> {
+        if self.arrived > 0 && tag.timestamp < self.newest {
+            self.reordered += 1;
+        }
+        self.newest = self.newest.max(tag.timestamp);
+        self.pending.insert((tag.timestamp, self.arrived), tag);
+        self.arrived += 1;
+
+        while let Some(entry) = self.pending.first_entry() {
+            if entry.key().0 as u64 + self.window_ms as u64 >= self.newest as u64 {
+                break;
+            }
+            let tag = entry.remove();
+            self.write_tag(tag)?;
+        }
+        Ok(())
+    }
+
+    /// write what is still held back
+    pub fn finish(mut self) -> IoResult<W> {
+        while let Some((_, tag)) = self.pending.pop_first() {
+            self.write_tag(tag)?;
+        }
+        self.out.finish()
+    }
+
+    fn write_tag(&mut self, mut tag: RawTag) -> IoResult<()> {
+        match self.last_written {
+            Some(last) if tag.timestamp < last => {
+                tag.set_timestamp(last);
+                self.clamped += 1;
+            }
+            _ => self.last_written = Some(tag.timestamp),
+        }
+        self.out.write_tag(&tag.data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flv_demuxer::{DemuxEvent, FlvDemuxer};
+
+    fn tag(offset: usize, tag_type: u8, timestamp: u32, cts: Option<i32>) -> TagInfo {
+        TagInfo {
+            offset,
+            tag_type,
+            timestamp,
+            data_size: 4,
+            frame_type: None,
+            codec: None,
+            packet_type: None,
+            composition_time: cts,
+        }
+    }
+
+    #[test]
+    fn test_analyze() {
+        let mut tags = Vec::new();
+        // video every 40ms with cts 80, audio every 20ms drifting 1ms per frame
+        for i in 0..500u32 {
+            tags.push(tag(i as usize * 100, TAG_TYPE_VIDEO, i * 40, Some(80)));
+            tags.push(tag(i as usize * 100 + 50, TAG_TYPE_AUDIO, i * 42, None));
+            tags.push(tag(
+                i as usize * 100 + 60,
+                TAG_TYPE_AUDIO,
+                i * 42 + 21,
+                None,
+            ));
+        }
+        // 3s without video
+        tags.push(tag(60_000, TAG_TYPE_VIDEO, 22_960, Some(0)));
+
+        let report = analyze(&tags, 1000);
+        assert_eq!(report.video.tags, 501);
+        assert_eq!(report.audio.tags, 1000);
+        assert_eq!(report.audio.duration_ms, 499 * 42 + 21 + 21);
+        assert_eq!(report.video.first_pts, Some(80));
+        assert_eq!(
+            report.video.gaps,
+            vec![Gap {
+                offset: 60_000,
+                from: 499 * 40,
+                to: 22_960
+            }]
+        );
+        assert_eq!(report.audio_ahead_ms, 499 * 42 + 21 - 499 * 40);
+        assert_eq!(report.video_ahead_ms, 22_960 - (499 * 42 + 21));
+        assert_eq!(
+            report.offsets[0],
+            OffsetSample {
+                at: 40,
+                audio_minus_video: 21 - 120,
+            }
+        );
+        // audio is 2ms longer per 40ms of video
+        assert_eq!(report.offsets[1].at, 10_040);
+        assert_eq!(report.offsets[1].audio_minus_video, 250 * 42 + 21 - 10_120);
+        assert_eq!(report.drift_ms(), (499 * 42 + 21 - 22_960) - (21 - 120));
+    }
+
+    fn raw_tag(tag_type: u8, timestamp: u32) -> RawTag {
+        RawTag::new(tag_type, timestamp, &[0xaf])
+    }
+
+    #[test]
+    fn test_reinterleave() {
+        let header = FlvHeader {
+            version: 1,
+            has_audio: true,
+            has_video: true,
+        };
+        let mut writer = Reinterleaver::new(Vec::new(), &header, 100).unwrap();
+        for &(tag_type, timestamp) in &[
+            (TAG_TYPE_VIDEO, 0),
+            (TAG_TYPE_VIDEO, 40),
+            (TAG_TYPE_VIDEO, 80),
+            (TAG_TYPE_AUDIO, 10),
+            (TAG_TYPE_VIDEO, 300),
+            (TAG_TYPE_AUDIO, 30),
+            (TAG_TYPE_AUDIO, 250),
+        ] {
+            writer.push(raw_tag(tag_type, timestamp)).unwrap();
+        }
+        assert_eq!((writer.reordered, writer.clamped), (3, 1));
+        let file = writer.finish().unwrap();
+
+        let mut demuxer = FlvDemuxer::new();
+        demuxer.feed(&file);
+        let mut timestamps = Vec::new();
+        while let Some(event) = demuxer.next_event().unwrap() {
+            if let DemuxEvent::Tag(tag) = event {
+                timestamps.push(tag.timestamp);
+            }
+        }
+        // 30 came after 80 was written
+        assert_eq!(timestamps, vec![0, 10, 40, 80, 80, 250, 300]);
+    }
+}
@@ -0,0 +1,349 @@
+use crate::epoller::{Interest, Trigger};
+use crate::slab::Token;
+use crate::uring::UringBackend;
+use std::io::Error;
+use std::io::IoSlice;
+use std::io::Result as IoResult;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+/// readiness of one registered fd, events are EPOLLIN/EPOLLOUT/... bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub token: Token,
+    pub events: u32,
+}
+
+/// The kernel interface behind an Epoller: it watches fds and reports
+/// readiness, the Epoller does the handle bookkeeping and callbacks.
+pub trait Backend {
+    fn name(&self) -> &'static str;
+    fn register(
+        &mut self,
+        raw_fd: RawFd,
+        token: Token,
+        interest: Interest,
+        trigger: Trigger,
+    ) -> IoResult<()>;
+    fn modify(
+        &mut self,
+        raw_fd: RawFd,
+        token: Token,
+        interest: Interest,
+        trigger: Trigger,
+    ) -> IoResult<()>;
+    fn deregister(&mut self, raw_fd: RawFd) -> IoResult<()>;
+    /// wait up to timeout ms (-1 forever) and append what is ready to events
+    fn poll(&mut self, events: &mut Vec<Event>, timeout: i32) -> IoResult<()>;
+    /// Send each iovec list to its fd without blocking, one result per entry,
+    /// WouldBlock when the socket takes nothing. The buffers are no longer
+    /// used once this returns, except those of sends failed with EINPROGRESS:
+    /// the backend gave up waiting for them and the kernel may still read them.
+    fn send_batch(&mut self, sends: &[(RawFd, &[IoSlice])]) -> Vec<IoResult<usize>>;
+    /// the backend to move every fd to once this one can no longer be used
+    fn fallback(&mut self) -> Option<IoResult<Box<dyn Backend>>> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    Epoll,
+    Uring,
+}
+
+impl BackendKind {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "epoll" => Some(BackendKind::Epoll),
+            "uring" | "io_uring" => Some(BackendKind::Uring),
+            _ => None,
+        }
+    }
+}
+
+/// backend of kind, taking up to event_buffer_size events per poll
+pub fn create(kind: BackendKind, event_buffer_size: usize) -> IoResult<Box<dyn Backend>> {
+    match kind {
+        BackendKind::Epoll => Ok(Box::new(EpollBackend::create(event_buffer_size)?)),
+        BackendKind::Uring => Ok(Box::new(UringBackend::create(event_buffer_size)?)),
+    }
+}
+
+pub struct EpollBackend {
+    fd: RawFd,
+    event_buffer: Vec<libc::epoll_event>,
+}
+
+impl EpollBackend {
+    pub fn create(event_buffer_size: usize) -> IoResult<Self> {
+        let res = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        match res {
+            -1 => Err(Error::last_os_error()),
+            _ => Ok(Self {
+                fd: res,
+                event_buffer: vec![
+                    libc::epoll_event { events: 0, u64: 0 };
+                    event_buffer_size.max(1)
+                ],
+            }),
+        }
+    }
+
+    fn epoll_ctl(
+        &self,
+        op: libc::c_int,
+        raw_fd: RawFd,
+        token: Token,
+        interest: Interest,
+        trigger: Trigger,
+    ) -> IoResult<()> {
+        let mut event = libc::epoll_event {
+            events: trigger.events(interest),
+            u64: token.0,
+        };
+        let res =
+            unsafe { libc::epoll_ctl(self.fd, op, raw_fd, &mut event as *mut libc::epoll_event) };
+        match res {
+            -1 => Err(Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Backend for EpollBackend {
+    fn name(&self) -> &'static str {
+        "epoll"
+    }
+
+    fn register(
+        &mut self,
+        raw_fd: RawFd,
+        token: Token,
+        interest: Interest,
+        trigger: Trigger,
+    ) -> IoResult<()> {
+        self.epoll_ctl(libc::EPOLL_CTL_ADD, raw_fd, token, interest, trigger)
+    }
+
+    fn modify(
+        &mut self,
+        raw_fd: RawFd,
+        token: Token,
+        interest: Interest,
+        trigger: Trigger,
+    ) -> IoResult<()> {
+        self.epoll_ctl(libc::EPOLL_CTL_MOD, raw_fd, token, interest, trigger)
+    }
+
+    fn deregister(&mut self, raw_fd: RawFd) -> IoResult<()> {
+        let res = unsafe { libc::epoll_ctl(self.fd, libc::EPOLL_CTL_DEL, raw_fd, ptr::null_mut()) };
+        match res {
+            -1 => Err(Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    fn poll(&mut self, events: &mut Vec<Event>, timeout: i32) -> IoResult<()> {
+        let ready_cnt = unsafe {
+            libc::epoll_wait(
+                self.fd,
+                self.event_buffer.as_mut_ptr(),
+                self.event_buffer.len() as i32,
+                timeout as libc::c_int,
+            )
+        };
+        if ready_cnt == -1 {
+            return Err(Error::last_os_error());
+        }
+        events.extend(
+            self.event_buffer[..ready_cnt as usize]
+                .iter()
+                .map(|event| Event {
+                    token: Token(event.u64),
+                    events: event.events,
+                }),
+        );
+        Ok(())
+    }
+
+    fn send_batch(&mut self, sends: &[(RawFd, &[IoSlice])]) -> Vec<IoResult<usize>> {
+        sends
+            .iter()
+            .map(|(raw_fd, data)| {
+                let header = msghdr(data);
+                let flags = libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL;
+                match unsafe { libc::sendmsg(*raw_fd, &header, flags) } {
+                    -1 => Err(Error::last_os_error()),
+                    sent_size => Ok(sent_size as usize),
+                }
+            })
+            .collect()
+    }
+}
+
+/// sendmsg header without address or control data for the iovecs of data
+pub(crate) fn msghdr(data: &[IoSlice]) -> libc::msghdr {
+    let mut header: libc::msghdr = unsafe { mem::zeroed() };
+    // IoSlice has the layout of iovec
+    header.msg_iov = data.as_ptr() as *mut libc::iovec;
+    header.msg_iovlen = data.len() as _;
+    header
+}
+
+impl Drop for EpollBackend {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epoller::{Epoller, RWHandle};
+    use std::any::Any;
+    use std::cell::Cell;
+    use std::io::ErrorKind;
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::AsRawFd;
+    use std::rc::Rc;
+    use std::thread;
+    use std::time::Instant;
+
+    const VIEWERS: usize = 64;
+    const CHUNK_SIZE: usize = 16 * 1024;
+    const ROUNDS: usize = 1000;
+
+    /// message: append one chunk to the output
+    struct Chunk(Rc<Vec<u8>>);
+
+    /// sending end of one loopback viewer
+    struct FanOut {
+        stream: TcpStream,
+        output: Vec<u8>,
+        // bytes queued but not sent, over all viewers
+        backlog: Rc<Cell<usize>>,
+    }
+
+    impl AsRawFd for FanOut {
+        fn as_raw_fd(&self) -> RawFd {
+            self.stream.as_raw_fd()
+        }
+    }
+
+    impl RWHandle for FanOut {
+        fn on_read(&mut self, _epoller: &mut Epoller) -> IoResult<()> {
+            Ok(())
+        }
+
+        fn on_write(&mut self, epoller: &mut Epoller) -> IoResult<()> {
+            epoller.set_interest(self.as_raw_fd(), Interest::Read)?;
+            epoller.send_batched(self.as_raw_fd());
+            Ok(())
+        }
+
+        fn on_message(&mut self, epoller: &mut Epoller, msg: Box<dyn Any>) -> IoResult<()> {
+            if let Ok(chunk) = msg.downcast::<Chunk>() {
+                self.output.extend_from_slice(&chunk.0);
+                epoller.send_batched(self.as_raw_fd());
+            }
+            Ok(())
+        }
+
+        fn batched_output(&self) -> Vec<IoSlice<'_>> {
+            vec![IoSlice::new(&self.output)]
+        }
+
+        fn on_batch_sent(
+            &mut self,
+            epoller: &mut Epoller,
+            result: IoResult<usize>,
+        ) -> IoResult<()> {
+            match result {
+                Ok(sent_size) => {
+                    self.output.drain(0..sent_size);
+                    self.backlog.set(self.backlog.get() - sent_size);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => (),
+                Err(err) => return Err(err),
+            }
+            if !self.output.is_empty() {
+                epoller.set_interest(self.as_raw_fd(), Interest::ReadWrite)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Send ROUNDS chunks to every viewer over loopback, a thread reads them all.
+    fn fan_out(kind: BackendKind) -> IoResult<()> {
+        let mut epoller = Epoller::with_backend(create(kind, 1024)?);
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let backlog = Rc::new(Cell::new(0));
+        let mut readers = Vec::new();
+        for _ in 0..VIEWERS {
+            readers.push(TcpStream::connect(listener.local_addr()?)?);
+            let (stream, _) = listener.accept()?;
+            stream.set_nonblocking(true)?;
+            let viewer = FanOut {
+                stream,
+                output: Vec::new(),
+                backlog: backlog.clone(),
+            };
+            if let Err((_, err)) = epoller.wait_read(viewer) {
+                return Err(err);
+            }
+        }
+
+        let start = Instant::now();
+        let reader = thread::spawn(move || -> IoResult<()> {
+            let mut buf = vec![0; CHUNK_SIZE];
+            for _ in 0..ROUNDS {
+                for stream in &mut readers {
+                    stream.read_exact(&mut buf)?;
+                }
+            }
+            Ok(())
+        });
+        let chunk = Rc::new(vec![0x5a; CHUNK_SIZE]);
+        for _ in 0..ROUNDS {
+            for token in epoller.tokens() {
+                epoller.send(token, Box::new(Chunk(chunk.clone())));
+            }
+            backlog.set(backlog.get() + VIEWERS * CHUNK_SIZE);
+            epoller.run(0)?;
+            // keep a few rounds in flight at most
+            while backlog.get() > 4 * VIEWERS * CHUNK_SIZE {
+                epoller.run(10)?;
+            }
+        }
+        while backlog.get() > 0 {
+            epoller.run(10)?;
+        }
+        reader.join().unwrap()?;
+
+        let elapsed = start.elapsed();
+        let total = (VIEWERS * CHUNK_SIZE * ROUNDS) as f64;
+        println!(
+            "{}: {} viewers {} MB in {:?}, {:.0} MB/s",
+            epoller.backend_name(),
+            VIEWERS,
+            total as usize / (1024 * 1024),
+            elapsed,
+            total / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+        );
+        Ok(())
+    }
+
+    /// cargo test --release -- --ignored --nocapture bench_fan_out
+    #[test]
+    #[ignore]
+    fn bench_fan_out() {
+        fan_out(BackendKind::Epoll).unwrap();
+        match fan_out(BackendKind::Uring) {
+            Ok(()) => (),
+            Err(err) => println!("io_uring skipped, {}", err),
+        }
+    }
+}
@@ -0,0 +1,682 @@
+use crate::av_sync::{analyze, Reinterleaver, DEFAULT_GAP_MS, DEFAULT_WINDOW_MS};
+use crate::edit::{concat, cut, split, split_part, Input, SplitLimit};
+use crate::elementary::{extract_audio, extract_video, import};
+use crate::flv::{read_tags, read_tags_recovering, TagInfo};
+use crate::flv_demuxer::{
+    RawTag, SOUND_FORMAT_AAC, SOUND_FORMAT_MP3, TAG_TYPE_AUDIO, TAG_TYPE_SCRIPT, TAG_TYPE_VIDEO,
+    VIDEO_CODEC_AVC,
+};
+use crate::flv_writer::{file_size, write_file};
+use crate::my_error::my_error;
+use crate::validate::{validate, Severity};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Result as IoResult;
+use std::io::{self, BufWriter, Write};
+
+const USAGE: &str = "usage:
+  flv-server <flv_file> [worker_count] [config_file]
+  flv-server inspect <file> [--video | --audio] [--keyframes] [--range from-to] [--json]
+  flv-server sync <file> [--gap ms] [--reinterleave out.flv] [--window ms]
+  flv-server validate <file> [--json] [--strict]
+  flv-server repair <file> <out.flv>
+  flv-server cut [--from time] [--to time] <file> <out.flv>
+  flv-server concat <file>... <out.flv>
+  flv-server split (--minutes n | --mb n) <file> <pattern, e.g. part_%03d.flv>
+  flv-server extract [--video out.h264] [--audio out.aac|out.mp3] <file>
+  flv-server import [--video in.h264 --fps n|n/d] [--audio in.aac] <out.flv>
+times are seconds or [hh:]mm:ss[.fff]";
+
+/// Run the subcommand named by the first argument,
+/// None when there is none and the server is to be started.
+pub fn run(args: &[String]) -> Option<IoResult<()>> {
+    let rest = args.get(2..).unwrap_or(&[]);
+    let result = match args.get(1)?.as_str() {
+        "inspect" => inspect(rest),
+        "sync" => sync(rest),
+        "validate" => validate_file(rest),
+        "repair" => repair(rest),
+        "cut" => cut_file(rest),
+        "concat" => concat_files(rest),
+        "split" => split_file(rest),
+        "extract" => extract(rest),
+        "import" => import_files(rest),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => return None,
+    };
+    Some(result)
+}
+
+/// positional arguments and --options of a subcommand
+struct Args {
+    positional: Vec<String>,
+    // flags are there with an empty value
+    options: BTreeMap<String, String>,
+}
+
+impl Args {
+    /// flags stand alone, valued options take the next argument
+    fn parse(args: &[String], flags: &[&str], valued: &[&str]) -> IoResult<Self> {
+        let mut parsed = Self {
+            positional: Vec::new(),
+            options: BTreeMap::new(),
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if flags.contains(&arg.as_str()) {
+                parsed.options.insert(arg.clone(), String::new());
+            } else if valued.contains(&arg.as_str()) {
+                let value = args
+                    .next()
+                    .ok_or_else(|| my_error(format!("{} needs a value\n{}", arg, USAGE)))?;
+                parsed.options.insert(arg.clone(), value.clone());
+            } else if arg.starts_with("--") {
+                return Err(my_error(format!("unknown option {}\n{}", arg, USAGE)));
+            } else {
+                parsed.positional.push(arg.clone());
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.options.contains_key(name)
+    }
+
+    fn value(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(String::as_str)
+    }
+
+    fn ms(&self, name: &str, default: u32) -> IoResult<u32> {
+        match self.value(name) {
+            Some(value) => value
+                .parse()
+                .map_err(|_| my_error(format!("{} {} is not a number of ms", name, value))),
+            None => Ok(default),
+        }
+    }
+
+    /// the positional arguments, exactly count of them
+    fn files(&self, count: usize) -> IoResult<&[String]> {
+        if self.positional.len() != count {
+            return Err(my_error(USAGE));
+        }
+        Ok(&self.positional)
+    }
+
+    /// the positional arguments, at least count of them
+    fn files_min(&self, count: usize) -> IoResult<&[String]> {
+        if self.positional.len() < count {
+            return Err(my_error(USAGE));
+        }
+        Ok(&self.positional)
+    }
+}
+
+/// a positive number, fractions allowed
+fn parse_amount(name: &str, value: &str) -> IoResult<f64> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|amount| amount.is_finite() && *amount > 0.0)
+        .ok_or_else(|| my_error(format!("{} {} is not a positive number", name, value)))
+}
+
+/// a positive number or a fraction of two, like 30000/1001
+fn parse_rate(name: &str, value: &str) -> IoResult<f64> {
+    match value.split_once('/') {
+        Some((numerator, denominator)) => {
+            Ok(parse_amount(name, numerator)? / parse_amount(name, denominator)?)
+        }
+        None => parse_amount(name, value),
+    }
+}
+
+/// the pattern with its %d, or %0<width>d, replaced by number
+fn part_name(pattern: &str, number: usize) -> IoResult<String> {
+    let bad = || my_error(format!("name pattern {} needs one %d or %03d", pattern));
+    let (prefix, rest) = pattern.split_once('%').ok_or_else(bad)?;
+    let (width, suffix) = rest.split_once('d').ok_or_else(bad)?;
+    if suffix.contains('%') || !width.chars().all(|c| c.is_ascii_digit()) {
+        return Err(bad());
+    }
+    let width: usize = width.parse().unwrap_or(0);
+    Ok(format!(
+        "{}{:0width$}{}",
+        prefix,
+        number,
+        suffix,
+        width = width
+    ))
+}
+
+/// "90", "90.5", "01:30" or "00:01:30.250" to ms
+pub fn parse_time(value: &str) -> IoResult<u32> {
+    let bad = || my_error(format!("bad time {}, expect [hh:]mm:ss[.fff]", value));
+    let mut parts: Vec<&str> = value.split(':').collect();
+    let seconds = parts
+        .pop()
+        .and_then(|seconds| seconds.parse::<f64>().ok())
+        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+        .ok_or_else(bad)?;
+    if parts.len() > 2 {
+        return Err(bad());
+    }
+    let mut minutes = 0u64;
+    for part in parts {
+        minutes = minutes * 60 + part.parse::<u64>().map_err(|_| bad())?;
+    }
+    let ms = minutes as f64 * 60_000.0 + (seconds * 1000.0).round();
+    if ms > u32::MAX as f64 {
+        return Err(bad());
+    }
+    Ok(ms as u32)
+}
+
+/// "from-to" with either end left out
+fn parse_range(value: &str) -> IoResult<(u32, u32)> {
+    let (from, to) = value
+        .split_once('-')
+        .ok_or_else(|| my_error(format!("bad range {}, expect from-to", value)))?;
+    let from = match from {
+        "" => 0,
+        from => parse_time(from)?,
+    };
+    let to = match to {
+        "" => u32::MAX,
+        to => parse_time(to)?,
+    };
+    Ok((from, to))
+}
+
+fn type_name(tag_type: u8) -> &'static str {
+    match tag_type {
+        TAG_TYPE_AUDIO => "audio",
+        TAG_TYPE_VIDEO => "video",
+        TAG_TYPE_SCRIPT => "script",
+        _ => "unknown",
+    }
+}
+
+fn frame_type_name(frame_type: u8) -> &'static str {
+    match frame_type {
+        1 => "key",
+        2 => "inter",
+        3 => "disposable",
+        4 => "generated",
+        5 => "command",
+        _ => "unknown",
+    }
+}
+
+fn codec_name(tag_type: u8, codec: u8) -> &'static str {
+    match (tag_type, codec) {
+        (TAG_TYPE_VIDEO, VIDEO_CODEC_AVC) => "avc",
+        (TAG_TYPE_AUDIO, SOUND_FORMAT_MP3) => "mp3",
+        (TAG_TYPE_AUDIO, SOUND_FORMAT_AAC) => "aac",
+        _ => "unknown",
+    }
+}
+
+fn packet_type_name(tag_type: u8, packet_type: u8) -> &'static str {
+    match (tag_type, packet_type) {
+        (_, 0) => "sequence_header",
+        (TAG_TYPE_VIDEO, 1) => "nalu",
+        (TAG_TYPE_VIDEO, 2) => "end_of_sequence",
+        (TAG_TYPE_AUDIO, 1) => "raw",
+        _ => "unknown",
+    }
+}
+
+/// the name as a JSON string, or null
+fn json_name(name: Option<&str>) -> String {
+    match name {
+        Some(name) => format!("\"{}\"", name),
+        None => "null".to_owned(),
+    }
+}
+
+/// text as a JSON string, quotes and control characters escaped
+fn json_string(text: &str) -> String {
+    let mut json = String::with_capacity(text.len() + 2);
+    json.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn tag_json(tag: &TagInfo) -> String {
+    format!(
+        "{{\"offset\":{},\"type\":\"{}\",\"timestamp\":{},\"size\":{},\"frame_type\":{},\"codec\":{},\"packet_type\":{},\"cts\":{}}}",
+        tag.offset,
+        type_name(tag.tag_type),
+        tag.timestamp,
+        tag.data_size,
+        json_name(tag.frame_type.map(frame_type_name)),
+        json_name(tag.codec.map(|codec| codec_name(tag.tag_type, codec))),
+        json_name(tag.packet_type.map(|packet| packet_type_name(tag.tag_type, packet))),
+        tag.composition_time
+            .map_or("null".to_owned(), |cts| cts.to_string())
+    )
+}
+
+fn tag_line(tag: &TagInfo) -> String {
+    format!(
+        "{:>10} {:<6} {:>10} {:>8} {:<10} {:<5} {:<15} {}",
+        tag.offset,
+        type_name(tag.tag_type),
+        tag.timestamp,
+        tag.data_size,
+        tag.frame_type.map_or("", frame_type_name),
+        tag.codec
+            .map_or("", |codec| codec_name(tag.tag_type, codec)),
+        tag.packet_type
+            .map_or("", |packet| packet_type_name(tag.tag_type, packet)),
+        tag.composition_time
+            .map_or(String::new(), |cts| cts.to_string())
+    )
+}
+
+/// list the tags of a file, one per line or as JSON
+fn inspect(args: &[String]) -> IoResult<()> {
+    let args = Args::parse(
+        args,
+        &["--video", "--audio", "--keyframes", "--json"],
+        &["--range"],
+    )?;
+    let path = &args.files(1)?[0];
+    let range = match args.value("--range") {
+        Some(range) => parse_range(range)?,
+        None => (0, u32::MAX),
+    };
+    let file = fs::read(path)?;
+    let (header, tags) = read_tags(&file)?;
+    let selected = tags.iter().filter(|tag| {
+        (!args.flag("--video") || tag.tag_type == TAG_TYPE_VIDEO)
+            && (!args.flag("--audio") || tag.tag_type == TAG_TYPE_AUDIO)
+            && (!args.flag("--keyframes") || tag.is_keyframe())
+            && (range.0..=range.1).contains(&tag.timestamp)
+    });
+
+    let mut out = BufWriter::new(io::stdout().lock());
+    if args.flag("--json") {
+        writeln!(
+            out,
+            "{{\"version\":{},\"has_audio\":{},\"has_video\":{},\"tags\":[",
+            header.version, header.has_audio, header.has_video
+        )?;
+        for (index, tag) in selected.enumerate() {
+            let separator = if index == 0 { "" } else { "," };
+            writeln!(out, "{}{}", separator, tag_json(tag))?;
+        }
+        writeln!(out, "]}}")?;
+    } else {
+        writeln!(
+            out,
+            "flv version:{} HasVideo:{} HasAudio:{} tags:{}",
+            header.version,
+            header.has_video,
+            header.has_audio,
+            tags.len()
+        )?;
+        writeln!(
+            out,
+            "{:>10} {:<6} {:>10} {:>8} {:<10} {:<5} {:<15} cts",
+            "offset", "type", "timestamp", "size", "frame", "codec", "packet"
+        )?;
+        for tag in selected {
+            writeln!(out, "{}", tag_line(tag).trim_end())?;
+        }
+    }
+    out.flush()
+}
+
+/// A/V sync report, and optionally a copy of the file in strict dts order
+fn sync(args: &[String]) -> IoResult<()> {
+    let args = Args::parse(args, &[], &["--gap", "--reinterleave", "--window"])?;
+    let path = &args.files(1)?[0];
+    let gap_ms = args.ms("--gap", DEFAULT_GAP_MS)?;
+    let window_ms = args.ms("--window", DEFAULT_WINDOW_MS)?;
+
+    let file = fs::read(path)?;
+    let (header, tags) = read_tags(&file)?;
+    let mut out = io::stdout().lock();
+    write!(out, "{}", analyze(&tags, gap_ms))?;
+
+    if let Some(output) = args.value("--reinterleave") {
+        let file_out = BufWriter::new(fs::File::create(output)?);
+        let mut writer = Reinterleaver::new(file_out, &header, window_ms)?;
+        for tag in &tags {
+            writer.push(tag.raw_tag(&file))?;
+        }
+        let (reordered, clamped) = (writer.reordered, writer.clamped);
+        writer.finish()?;
+        writeln!(
+            out,
+            "wrote {}: {} tags, {} reordered, {} later than the {}ms window",
+            output,
+            tags.len(),
+            reordered,
+            clamped,
+            window_ms
+        )?;
+    }
+    Ok(())
+}
+
+/// conformance report of a file, fails when it has errors,
+/// or with --strict any issue, so scripts can check the exit code
+fn validate_file(args: &[String]) -> IoResult<()> {
+    let args = Args::parse(args, &["--json", "--strict"], &[])?;
+    let path = &args.files(1)?[0];
+    let file = fs::read(path)?;
+    let report = validate(&file);
+    let errors = report.count(Severity::Error);
+    let warnings = report.count(Severity::Warning);
+
+    let mut out = BufWriter::new(io::stdout().lock());
+    if args.flag("--json") {
+        writeln!(
+            out,
+            "{{\"file\":{},\"size\":{},\"tags\":{},\"errors\":{},\"warnings\":{},\"issues\":[",
+            json_string(path),
+            file.len(),
+            report.tags,
+            errors,
+            warnings
+        )?;
+        for (index, issue) in report.issues.iter().enumerate() {
+            writeln!(
+                out,
+                "{}{{\"offset\":{},\"severity\":\"{}\",\"code\":\"{}\",\"message\":{}}}",
+                if index == 0 { "" } else { "," },
+                issue.offset,
+                issue.severity.name(),
+                issue.code,
+                json_string(&issue.message)
+            )?;
+        }
+        writeln!(out, "]}}")?;
+    } else {
+        writeln!(
+            out,
+            "{}: {} bytes, {} tags, {} errors, {} warnings",
+            path,
+            file.len(),
+            report.tags,
+            errors,
+            warnings
+        )?;
+        for issue in &report.issues {
+            writeln!(out, "{}", issue)?;
+        }
+    }
+    out.flush()?;
+
+    if errors > 0 || (args.flag("--strict") && warnings > 0) {
+        return Err(my_error(format!(
+            "{} is not valid: {} errors, {} warnings",
+            path, errors, warnings
+        )));
+    }
+    Ok(())
+}
+
+/// copy of a damaged file with only the tags that parse
+fn repair(args: &[String]) -> IoResult<()> {
+    let args = Args::parse(args, &[], &[])?;
+    let files = args.files(2)?;
+    let file = fs::read(&files[0])?;
+    let (_, tags, dropped) = read_tags_recovering(&file)?;
+    let tags: Vec<RawTag> = tags.iter().map(|tag| tag.raw_tag(&file)).collect();
+    write_file(BufWriter::new(fs::File::create(&files[1])?), &tags)?;
+
+    let mut out = io::stdout().lock();
+    writeln!(
+        out,
+        "wrote {}: {} tags kept, {} bytes in {} places dropped",
+        files[1],
+        tags.len(),
+        dropped.iter().map(|region| region.len).sum::<usize>(),
+        dropped.len()
+    )?;
+    for region in &dropped {
+        writeln!(
+            out,
+            "{:>10} {:>8} bytes: {}",
+            region.offset, region.len, region.reason
+        )?;
+    }
+    Ok(())
+}
+
+/// copy the time range of a file starting at a keyframe, without re-encoding
+fn cut_file(args: &[String]) -> IoResult<()> {
+    let args = Args::parse(args, &[], &["--from", "--to"])?;
+    let files = args.files(2)?;
+    let from = args.value("--from").map_or(Ok(0), parse_time)?;
+    let to = args.value("--to").map_or(Ok(u32::MAX), parse_time)?;
+    let file = fs::read(&files[0])?;
+    let (_, tags) = read_tags(&file)?;
+    let tags = cut(&file, &tags, from, to)?;
+    write_file(BufWriter::new(fs::File::create(&files[1])?), &tags)?;
+    let last = tags.iter().map(|tag| tag.timestamp).max().unwrap_or(0);
+    writeln!(
+        io::stdout().lock(),
+        "wrote {}: {} tags, {}ms",
+        files[1],
+        tags.len(),
+        last
+    )
+}
+
+/// join files into one, the last argument is the output
+fn concat_files(args: &[String]) -> IoResult<()> {
+    let args = Args::parse(args, &[], &[])?;
+    let files = args.files_min(2)?;
+    let (output, files) = files.split_last().unwrap();
+    let inputs = files
+        .iter()
+        .map(|path| Input::read(path))
+        .collect::<IoResult<Vec<_>>>()?;
+    let joined = concat(&inputs)?;
+    write_file(BufWriter::new(fs::File::create(output)?), &joined.tags)?;
+
+    let mut out = io::stdout().lock();
+    for warning in &joined.warnings {
+        writeln!(out, "warning: {}", warning)?;
+    }
+    let last = joined
+        .tags
+        .iter()
+        .map(|tag| tag.timestamp)
+        .max()
+        .unwrap_or(0);
+    writeln!(
+        out,
+        "wrote {}: {} files, {} tags, {}ms",
+        output,
+        inputs.len(),
+        joined.tags.len(),
+        last
+    )
+}
+
+/// parts of n minutes or n MB cut at keyframes, named after a pattern
+fn split_file(args: &[String]) -> IoResult<()> {
+    let args = Args::parse(args, &[], &["--minutes", "--mb"])?;
+    let files = args.files(2)?;
+    let limit = match (args.value("--minutes"), args.value("--mb")) {
+        (Some(minutes), None) => {
+            SplitLimit::Duration((parse_amount("--minutes", minutes)? * 60_000.0) as u32)
+        }
+        (None, Some(mb)) => SplitLimit::Size((parse_amount("--mb", mb)? * 1024.0 * 1024.0) as u64),
+        _ => return Err(my_error(format!("either --minutes or --mb\n{}", USAGE))),
+    };
+    part_name(&files[1], 0)?;
+
+    let file = fs::read(&files[0])?;
+    let (_, tags) = read_tags(&file)?;
+    let ranges = split(&file, &tags, limit)?;
+    let mut out = io::stdout().lock();
+    // one part in memory at a time
+    for (number, range) in ranges.into_iter().enumerate() {
+        let part = split_part(&file, &tags, range)?;
+        let name = part_name(&files[1], number)?;
+        write_file(BufWriter::new(fs::File::create(&name)?), &part)?;
+        let last = part.iter().map(|tag| tag.timestamp).max().unwrap_or(0);
+        writeln!(
+            out,
+            "wrote {}: {} tags, {}ms, {} bytes",
+            name,
+            part.len(),
+            last,
+            file_size(&part)
+        )?;
+    }
+    Ok(())
+}
+
+/// the tracks of a file as H.264 Annex B and ADTS AAC or MP3
+fn extract(args: &[String]) -> IoResult<()> {
+    let args = Args::parse(args, &[], &["--video", "--audio"])?;
+    let path = &args.files(1)?[0];
+    if args.value("--video").is_none() && args.value("--audio").is_none() {
+        return Err(my_error(format!("--video, --audio or both\n{}", USAGE)));
+    }
+    let file = fs::read(path)?;
+    let (_, tags) = read_tags(&file)?;
+    let mut out = io::stdout().lock();
+    if let Some(output) = args.value("--video") {
+        let mut file_out = BufWriter::new(fs::File::create(output)?);
+        let frames = extract_video(&file, &tags, &mut file_out)?;
+        file_out.flush()?;
+        writeln!(out, "wrote {}: {} video frames", output, frames)?;
+    }
+    if let Some(output) = args.value("--audio") {
+        let mut file_out = BufWriter::new(fs::File::create(output)?);
+        let frames = extract_audio(&file, &tags, &mut file_out)?;
+        file_out.flush()?;
+        writeln!(out, "wrote {}: {} audio frames", output, frames)?;
+    }
+    Ok(())
+}
+
+/// an FLV file of H.264 Annex B and ADTS AAC streams
+fn import_files(args: &[String]) -> IoResult<()> {
+    let args = Args::parse(args, &[], &["--video", "--fps", "--audio"])?;
+    let output = &args.files(1)?[0];
+    let video = match (args.value("--video"), args.value("--fps")) {
+        (Some(path), Some(fps)) => Some((fs::read(path)?, parse_rate("--fps", fps)?)),
+        (None, None) => None,
+        _ => return Err(my_error(format!("--video needs --fps\n{}", USAGE))),
+    };
+    let audio = args.value("--audio").map(fs::read).transpose()?;
+    if video.is_none() && audio.is_none() {
+        return Err(my_error(format!("--video, --audio or both\n{}", USAGE)));
+    }
+    let video = video.as_ref().map(|(data, fps)| (data.as_slice(), *fps));
+    let tags = import(video, audio.as_deref())?;
+    write_file(BufWriter::new(fs::File::create(output)?), &tags)?;
+    let last = tags.iter().map(|tag| tag.timestamp).max().unwrap_or(0);
+    writeln!(
+        io::stdout().lock(),
+        "wrote {}: {} tags, {}ms, {} bytes",
+        output,
+        tags.len(),
+        last,
+        file_size(&tags)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("90").unwrap(), 90_000);
+        assert_eq!(parse_time("1.5").unwrap(), 1500);
+        assert_eq!(parse_time("01:30").unwrap(), 90_000);
+        assert_eq!(parse_time("00:01:30.250").unwrap(), 90_250);
+        assert_eq!(parse_time("1:00:00").unwrap(), 3_600_000);
+        assert!(parse_time("1:2:3:4").is_err());
+        assert_eq!(parse_rate("--fps", "25").unwrap(), 25.0);
+        assert!((parse_rate("--fps", "30000/1001").unwrap() - 29.97).abs() < 0.001);
+        assert!(parse_rate("--fps", "30/0").is_err());
+        assert!(parse_time("-1").is_err());
+        assert!(parse_time("a:30").is_err());
+        assert_eq!(parse_range("10-").unwrap(), (10_000, u32::MAX));
+        assert_eq!(parse_range("-00:01").unwrap(), (0, 1000));
+        assert_eq!(part_name("part_%03d.flv", 7).unwrap(), "part_007.flv");
+        assert_eq!(part_name("%d.flv", 12).unwrap(), "12.flv");
+        assert!(part_name("part.flv", 1).is_err());
+        assert!(part_name("%d_%d.flv", 1).is_err());
+        assert!(parse_amount("--mb", "0").is_err());
+    }
+
+    #[test]
+    fn test_args_and_tag_output() {
+        let args: Vec<String> = ["a.flv", "--json", "--range", "1-2"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        let parsed = Args::parse(&args, &["--json"], &["--range"]).unwrap();
+        assert_eq!(parsed.files(1).unwrap(), &["a.flv".to_owned()]);
+        assert!(parsed.flag("--json"));
+        assert_eq!(parsed.value("--range"), Some("1-2"));
+        assert!(parsed.files(2).is_err());
+        assert!(Args::parse(&args, &[], &["--range"]).is_err());
+
+        let tag = TagInfo {
+            offset: 13,
+            tag_type: TAG_TYPE_VIDEO,
+            timestamp: 40,
+            data_size: 9,
+            frame_type: Some(1),
+            codec: Some(VIDEO_CODEC_AVC),
+            packet_type: Some(1),
+            composition_time: Some(-40),
+        };
+        assert_eq!(
+            tag_json(&tag),
+            "{\"offset\":13,\"type\":\"video\",\"timestamp\":40,\"size\":9,\"frame_type\":\"key\",\"codec\":\"avc\",\"packet_type\":\"nalu\",\"cts\":-40}"
+        );
+        let script = TagInfo {
+            tag_type: TAG_TYPE_SCRIPT,
+            frame_type: None,
+            codec: None,
+            packet_type: None,
+            composition_time: None,
+            ..tag
+        };
+        assert_eq!(
+            json_string("a \"b\"\\\n\u{1}"),
+            "\"a \\\"b\\\"\\\\\\n\\u0001\""
+        );
+        assert!(tag_json(&script)
+            .ends_with("\"frame_type\":null,\"codec\":null,\"packet_type\":null,\"cts\":null}"));
+    }
+
+    #[test]
+    fn test_inspect_malformed() {
+        let path = std::env::temp_dir().join(format!("cli-test-{}.flv", std::process::id()));
+        // an AVC body of one byte
+        let tags = [RawTag::new(TAG_TYPE_VIDEO, 0, &[0x17])];
+        fs::write(&path, write_file(Vec::new(), &tags).unwrap()).unwrap();
+        let args = vec![path.to_string_lossy().into_owned()];
+        assert!(inspect(&args).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
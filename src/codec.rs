@@ -0,0 +1,547 @@
+use crate::my_error::my_error;
+use std::io::Result as IoResult;
+
+/// coded slice of a non-IDR picture
+pub const NAL_SLICE: u8 = 1;
+pub const NAL_IDR: u8 = 5;
+pub const NAL_SPS: u8 = 7;
+pub const NAL_PPS: u8 = 8;
+/// access unit delimiter
+pub const NAL_AUD: u8 = 9;
+/// what every NALU starts with in an Annex B stream
+pub const START_CODE: [u8; 4] = [0, 0, 0, 1];
+/// header bytes of an ADTS frame without CRC
+pub const ADTS_HEADER_LEN: usize = 7;
+/// slice_type of B slices, modulo 5
+pub const SLICE_B: u32 = 1;
+/// profile_idc of the profiles whose SPS carries the chroma format and bit depths
+const CHROMA_PROFILES: [u8; 13] = [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135];
+/// profile_idc of the profiles whose AVCDecoderConfigurationRecord carries them too
+const RECORD_CHROMA_PROFILES: [u8; 4] = [100, 110, 122, 144];
+
+/// sampling frequencies by their index in AudioSpecificConfig and ADTS headers
+pub const AAC_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/// The part of an AAC AudioSpecificConfig players need to set up a decoder
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioSpecificConfig {
+    /// 2 for AAC LC
+    pub object_type: u8,
+    pub frequency_index: u8,
+    pub channels: u8,
+}
+
+impl AudioSpecificConfig {
+    pub fn parse(data: &[u8]) -> IoResult<Self> {
+        if data.len() < 2 {
+            return Err(my_error(
+                "AudioSpecificConfig parse failed: not enough data",
+            ));
+        }
+        let config = Self {
+            object_type: data[0] >> 3,
+            frequency_index: (data[0] & 0x07) << 1 | data[1] >> 7,
+            channels: (data[1] >> 3) & 0x0f,
+        };
+        // 31 escapes to a longer object type, 15 to an explicit frequency
+        if config.object_type == 31 || config.frequency_index as usize >= AAC_SAMPLE_RATES.len() {
+            return Err(my_error(format!(
+                "AudioSpecificConfig object type {} frequency index {} not support",
+                config.object_type, config.frequency_index
+            )));
+        }
+        Ok(config)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        AAC_SAMPLE_RATES[self.frequency_index as usize]
+    }
+
+    /// the header of an ADTS frame around a raw frame of len bytes
+    pub fn adts_header(&self, len: usize) -> IoResult<[u8; ADTS_HEADER_LEN]> {
+        let frame_len = len + ADTS_HEADER_LEN;
+        // ADTS has two bits for the profile, object type - 1
+        if !(1..=4).contains(&self.object_type) || frame_len > 0x1fff {
+            return Err(my_error(format!(
+                "ADTS can not carry object type {} frames of {} bytes",
+                self.object_type, len
+            )));
+        }
+        Ok([
+            0xff,
+            // MPEG-4, no CRC
+            0xf1,
+            (self.object_type - 1) << 6 | self.frequency_index << 2 | self.channels >> 2,
+            (self.channels & 0x03) << 6 | (frame_len >> 11) as u8,
+            (frame_len >> 3) as u8,
+            (frame_len as u8 & 0x07) << 5 | 0x1f,
+            0xfc,
+        ])
+    }
+
+    /// the two bytes of the configuration
+    pub fn to_bytes(self) -> [u8; 2] {
+        [
+            self.object_type << 3 | self.frequency_index >> 1,
+            (self.frequency_index & 0x01) << 7 | self.channels << 3,
+        ]
+    }
+}
+
+/// The ADTS frames of an AAC stream, the configuration of the first
+/// and the raw frames without the headers
+pub fn adts_frames(mut data: &[u8]) -> IoResult<(AudioSpecificConfig, Vec<&[u8]>)> {
+    let mut config = None;
+    let mut frames = Vec::new();
+    while !data.is_empty() {
+        if data.len() < ADTS_HEADER_LEN || data[0] != 0xff || data[1] & 0xf0 != 0xf0 {
+            return Err(my_error(format!(
+                "ADTS sync word not found at frame {}",
+                frames.len()
+            )));
+        }
+        let frame_len =
+            ((data[3] & 0x03) as usize) << 11 | (data[4] as usize) << 3 | (data[5] >> 5) as usize;
+        // a CRC follows the header when protection_absent is not set
+        let header_len = if data[1] & 0x01 == 0 { 9 } else { 7 };
+        if frame_len < header_len || data.len() < frame_len {
+            return Err(my_error(format!(
+                "ADTS frame {} of {} bytes does not fit",
+                frames.len(),
+                frame_len
+            )));
+        }
+        let frame_config = AudioSpecificConfig {
+            object_type: (data[2] >> 6) + 1,
+            frequency_index: (data[2] >> 2) & 0x0f,
+            channels: (data[2] & 0x01) << 2 | data[3] >> 6,
+        };
+        if frame_config.frequency_index as usize >= AAC_SAMPLE_RATES.len() {
+            return Err(my_error(format!(
+                "ADTS frequency index {} not support",
+                frame_config.frequency_index
+            )));
+        }
+        if *config.get_or_insert(frame_config) != frame_config {
+            return Err(my_error(format!(
+                "ADTS configuration changes at frame {}",
+                frames.len()
+            )));
+        }
+        frames.push(&data[header_len..frame_len]);
+        data = &data[frame_len..];
+    }
+    let config = config.ok_or_else(|| my_error("no ADTS frames"))?;
+    Ok((config, frames))
+}
+
+/// The AVCDecoderConfigurationRecord of an AVC sequence header
+#[derive(Debug, Clone, PartialEq)]
+pub struct AvcDecoderConfig {
+    pub profile: u8,
+    pub compatibility: u8,
+    pub level: u8,
+    /// bytes of the length in front of each NALU in packets
+    pub length_size: usize,
+    pub sps: Vec<Vec<u8>>,
+    pub pps: Vec<Vec<u8>>,
+    /// chroma_format_idc, 1 for 4:2:0
+    pub chroma_format: u8,
+    pub bit_depth_luma_minus8: u8,
+    pub bit_depth_chroma_minus8: u8,
+}
+
+impl AvcDecoderConfig {
+    pub fn parse(data: &[u8]) -> IoResult<Self> {
+        if data.len() < 6 {
+            return Err(my_error(
+                "AVCDecoderConfigurationRecord parse failed: not enough data",
+            ));
+        }
+        if data[0] != 1 {
+            return Err(my_error(format!(
+                "AVCDecoderConfigurationRecord version {} not support",
+                data[0]
+            )));
+        }
+        let mut config = Self {
+            profile: data[1],
+            compatibility: data[2],
+            level: data[3],
+            length_size: (data[4] & 0x03) as usize + 1,
+            sps: Vec::new(),
+            pps: Vec::new(),
+            chroma_format: 1,
+            bit_depth_luma_minus8: 0,
+            bit_depth_chroma_minus8: 0,
+        };
+        let mut rest = &data[5..];
+        config.sps = parameter_sets(&mut rest, 0x1f)?;
+        config.pps = parameter_sets(&mut rest, 0xff)?;
+        // older muxers leave the extension out, the SPS has the same
+        let fields = match rest {
+            [chroma, luma, chroma_depth, ..]
+                if RECORD_CHROMA_PROFILES.contains(&config.profile) =>
+            {
+                Some((chroma & 0x03, luma & 0x07, chroma_depth & 0x07))
+            }
+            _ => config.sps.first().and_then(|sps| sps_chroma(sps).ok()),
+        };
+        if let Some(fields) = fields {
+            config.chroma_format = fields.0;
+            config.bit_depth_luma_minus8 = fields.1;
+            config.bit_depth_chroma_minus8 = fields.2;
+        }
+        Ok(config)
+    }
+
+    /// a record of the SPS and PPS, with 4 byte NALU lengths
+    pub fn from_parameter_sets(sps: Vec<Vec<u8>>, pps: Vec<Vec<u8>>) -> IoResult<Self> {
+        let first = sps
+            .first()
+            .filter(|sps| sps.len() >= 4)
+            .ok_or_else(|| my_error("no SPS to build the AVCDecoderConfigurationRecord from"))?;
+        if pps.is_empty() {
+            return Err(my_error(
+                "no PPS to build the AVCDecoderConfigurationRecord from",
+            ));
+        }
+        let (chroma_format, bit_depth_luma_minus8, bit_depth_chroma_minus8) = sps_chroma(first)?;
+        Ok(Self {
+            profile: first[1],
+            compatibility: first[2],
+            level: first[3],
+            length_size: 4,
+            chroma_format,
+            bit_depth_luma_minus8,
+            bit_depth_chroma_minus8,
+            sps,
+            pps,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![
+            1,
+            self.profile,
+            self.compatibility,
+            self.level,
+            0xfc | (self.length_size - 1) as u8,
+            0xe0 | self.sps.len() as u8,
+        ];
+        for (index, parameter_set) in self.sps.iter().chain(&self.pps).enumerate() {
+            if index == self.sps.len() {
+                data.push(self.pps.len() as u8);
+            }
+            data.extend_from_slice(&(parameter_set.len() as u16).to_be_bytes());
+            data.extend_from_slice(parameter_set);
+        }
+        if RECORD_CHROMA_PROFILES.contains(&self.profile) {
+            data.extend_from_slice(&[
+                0xfc | self.chroma_format,
+                0xf8 | self.bit_depth_luma_minus8,
+                0xf8 | self.bit_depth_chroma_minus8,
+                // no SequenceParameterSetExt
+                0,
+            ]);
+        }
+        data
+    }
+}
+
+/// The bits of a NALU payload, emulation prevention bytes are skipped
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    // bits of data[pos] already read
+    bit: u8,
+    // zero bytes right before pos
+    zeros: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0,
+            bit: 0,
+            zeros: 0,
+        }
+    }
+
+    fn read_bit(&mut self) -> IoResult<u32> {
+        if self.bit == 0 && self.zeros >= 2 && self.data.get(self.pos) == Some(&3) {
+            self.pos += 1;
+            self.zeros = 0;
+        }
+        let byte = *self
+            .data
+            .get(self.pos)
+            .ok_or_else(|| my_error("NALU parse failed: not enough data"))?;
+        let bit = byte >> (7 - self.bit) & 0x01;
+        self.bit += 1;
+        if self.bit == 8 {
+            self.bit = 0;
+            self.pos += 1;
+            self.zeros = if byte == 0 { self.zeros + 1 } else { 0 };
+        }
+        Ok(bit as u32)
+    }
+
+    /// an unsigned Exp-Golomb code, ue(v)
+    fn read_ue(&mut self) -> IoResult<u32> {
+        let mut zeros = 0;
+        while self.read_bit()? == 0 {
+            zeros += 1;
+            if zeros > 31 {
+                return Err(my_error("NALU parse failed: Exp-Golomb code too long"));
+            }
+        }
+        let mut value = 0;
+        for _ in 0..zeros {
+            value = value << 1 | self.read_bit()?;
+        }
+        Ok((1 << zeros) - 1 + value)
+    }
+}
+
+/// chroma_format_idc, bit_depth_luma_minus8 and bit_depth_chroma_minus8 of an SPS,
+/// profiles without them are 4:2:0 at 8 bits
+fn sps_chroma(sps: &[u8]) -> IoResult<(u8, u8, u8)> {
+    if sps.len() < 4 {
+        return Err(my_error("SPS parse failed: not enough data"));
+    }
+    if !CHROMA_PROFILES.contains(&sps[1]) {
+        return Ok((1, 0, 0));
+    }
+    let mut reader = BitReader::new(&sps[4..]);
+    // seq_parameter_set_id
+    reader.read_ue()?;
+    let chroma_format = reader.read_ue()?;
+    if chroma_format == 3 {
+        // separate_colour_plane_flag
+        reader.read_bit()?;
+    }
+    let bit_depth_luma_minus8 = reader.read_ue()?;
+    let bit_depth_chroma_minus8 = reader.read_ue()?;
+    if chroma_format > 3 || bit_depth_luma_minus8 > 6 || bit_depth_chroma_minus8 > 6 {
+        return Err(my_error(format!(
+            "SPS chroma format {} bit depths {}/{} not support",
+            chroma_format,
+            bit_depth_luma_minus8 + 8,
+            bit_depth_chroma_minus8 + 8
+        )));
+    }
+    Ok((
+        chroma_format as u8,
+        bit_depth_luma_minus8 as u8,
+        bit_depth_chroma_minus8 as u8,
+    ))
+}
+
+/// slice_type of a slice NALU modulo 5: 0 P, 1 B, 2 I, 3 SP, 4 SI
+pub fn slice_type(nalu: &[u8]) -> IoResult<u32> {
+    let mut reader = BitReader::new(nalu.get(1..).unwrap_or(&[]));
+    // first_mb_in_slice
+    reader.read_ue()?;
+    Ok(reader.read_ue()? % 5)
+}
+
+/// The NALUs of an Annex B stream, split at 3 and 4 byte start codes
+pub fn annexb_nalus(data: &[u8]) -> Vec<&[u8]> {
+    let mut nalus = Vec::new();
+    // where the NALU after the last start code begins
+    let mut start = None;
+    let mut index = 0;
+    while index + 3 <= data.len() {
+        if data[index..index + 3] != [0, 0, 1] {
+            index += 1;
+            continue;
+        }
+        if let Some(start) = start {
+            // the zero of a 4 byte start code belongs to it, not to the NALU
+            let end = if index > start && data[index - 1] == 0 {
+                index - 1
+            } else {
+                index
+            };
+            nalus.push(&data[start..end]);
+        }
+        index += 3;
+        start = Some(index);
+    }
+    if let Some(start) = start {
+        nalus.push(&data[start..]);
+    }
+    nalus.retain(|nalu| !nalu.is_empty());
+    nalus
+}
+
+/// a count masked by mask, then that many parameter sets each behind a 16 bit length
+fn parameter_sets(data: &mut &[u8], mask: u8) -> IoResult<Vec<Vec<u8>>> {
+    let short = || my_error("AVCDecoderConfigurationRecord parse failed: not enough data");
+    let count = data.first().ok_or_else(short)? & mask;
+    *data = &data[1..];
+    let mut sets = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        if data.len() < 2 {
+            return Err(short());
+        }
+        let len = u16::from_be_bytes([data[0], data[1]]) as usize;
+        if data.len() < 2 + len {
+            return Err(short());
+        }
+        sets.push(data[2..2 + len].to_vec());
+        *data = &data[2 + len..];
+    }
+    Ok(sets)
+}
+
+/// nal_unit_type of a NALU
+pub fn nal_type(nalu: &[u8]) -> u8 {
+    nalu.first().map_or(0, |byte| byte & 0x1f)
+}
+
+/// The NALUs of an AVC packet, each behind its length in length_size bytes
+pub fn avcc_nalus(mut data: &[u8], length_size: usize) -> IoResult<Vec<&[u8]>> {
+    let mut nalus = Vec::new();
+    while !data.is_empty() {
+        if data.len() < length_size {
+            return Err(my_error("AVC NALU length parse failed: not enough data"));
+        }
+        let len = data[..length_size]
+            .iter()
+            .fold(0, |len, &byte| len << 8 | byte as usize);
+        data = &data[length_size..];
+        if data.len() < len {
+            return Err(my_error(format!(
+                "AVC NALU parse failed: not enough data {}/{}",
+                data.len(),
+                len
+            )));
+        }
+        nalus.push(&data[..len]);
+        data = &data[len..];
+    }
+    Ok(nalus)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audio_specific_config() {
+        // AAC LC 44.1kHz stereo
+        let config = AudioSpecificConfig::parse(&[0x12, 0x10]).unwrap();
+        assert_eq!((config.object_type, config.channels), (2, 2));
+        assert_eq!(config.sample_rate(), 44100);
+        assert_eq!(
+            AudioSpecificConfig::parse(&[0x11, 0x88])
+                .unwrap()
+                .sample_rate(),
+            48000
+        );
+        assert!(AudioSpecificConfig::parse(&[0x17, 0x80]).is_err());
+        assert!(AudioSpecificConfig::parse(&[0x12]).is_err());
+
+        // AAC LC 44.1kHz stereo, 378 bytes with the header
+        assert_eq!(
+            config.adts_header(371).unwrap(),
+            [0xff, 0xf1, 0x50, 0x80, 0x2f, 0x5f, 0xfc]
+        );
+    }
+
+    #[test]
+    fn test_avc_decoder_config() {
+        let record = [
+            1, 0x64, 0, 0x1f, 0xff, 0xe1, 0, 4, 0x67, 0x64, 0, 0x1f, 1, 0, 3, 0x68, 0xee, 0x3c,
+        ];
+        let config = AvcDecoderConfig::parse(&record).unwrap();
+        assert_eq!(
+            (config.profile, config.level, config.length_size),
+            (0x64, 0x1f, 4)
+        );
+        assert_eq!(config.sps, vec![vec![0x67, 0x64, 0, 0x1f]]);
+        assert_eq!(config.pps, vec![vec![0x68, 0xee, 0x3c]]);
+        assert_eq!(nal_type(&config.sps[0]), NAL_SPS);
+        assert!(AvcDecoderConfig::parse(&record[..12]).is_err());
+
+        let packet = [0, 0, 0, 2, 0x65, 1, 0, 0, 0, 1, 0x41];
+        assert_eq!(
+            avcc_nalus(&packet, 4).unwrap(),
+            vec![&[0x65, 1][..], &[0x41]]
+        );
+        assert!(avcc_nalus(&packet[..5], 4).is_err());
+
+        // High profile, 4:2:0 8 bit
+        let sps = vec![
+            0x67, 0x64, 0, 0x28, 0xac, 0xd9, 0x40, 0x78, 0x02, 0x27, 0xe5, 0x84, 0, 0, 3, 0, 4, 0,
+            0, 3, 0, 0xf0, 0x3c, 0x60, 0xc6, 0x58,
+        ];
+        let rebuilt = AvcDecoderConfig::from_parameter_sets(vec![sps.clone()], config.pps.clone())
+            .unwrap()
+            .to_bytes();
+        assert_eq!(rebuilt[..5], [1, 0x64, 0, 0x28, 0xff]);
+        assert_eq!(rebuilt[rebuilt.len() - 4..], [0xfd, 0xf8, 0xf8, 0]);
+        let parsed = AvcDecoderConfig::parse(&rebuilt).unwrap();
+        assert_eq!(parsed.to_bytes(), rebuilt);
+
+        // High 4:2:2 10 bit
+        let sps = vec![0x67, 0x6e, 0, 0x1e, 0xb6, 0xe0];
+        let config = AvcDecoderConfig::from_parameter_sets(vec![sps], config.pps).unwrap();
+        assert_eq!(
+            (
+                config.chroma_format,
+                config.bit_depth_luma_minus8,
+                config.bit_depth_chroma_minus8
+            ),
+            (2, 2, 2)
+        );
+        assert!(config.to_bytes().ends_with(&[0xfe, 0xfa, 0xfa, 0]));
+        // baseline records have no extension
+        let sps = vec![0x67, 0x42, 0, 0x1e];
+        let config = AvcDecoderConfig::from_parameter_sets(vec![sps], config.pps).unwrap();
+        assert!(config.to_bytes().ends_with(&[0x68, 0xee, 0x3c]));
+        assert!(
+            AvcDecoderConfig::from_parameter_sets(vec![vec![0x67, 0x64, 0, 0x1f]], config.pps)
+                .is_err()
+        );
+
+        // first_mb_in_slice 0, P, I and B
+        assert_eq!(slice_type(&[0x41, 0x9a]).unwrap(), 0);
+        assert_eq!(slice_type(&[0x65, 0x88]).unwrap(), 2);
+        assert_eq!(slice_type(&[0x41, 0x9c]).unwrap(), SLICE_B);
+        // first_mb_in_slice 65535 behind an emulation prevention byte
+        assert_eq!(
+            slice_type(&[0x41, 0, 0, 3, 0x80, 0, 0x1e]).unwrap(),
+            SLICE_B
+        );
+        assert!(slice_type(&[0x41]).is_err());
+    }
+
+    #[test]
+    fn test_annexb_and_adts() {
+        let stream = [
+            0, 0, 0, 1, 0x67, 1, 0, 0, 1, 0x68, 2, 0, 0, 0, 1, 0x65, 0, 3, 0,
+        ];
+        assert_eq!(
+            annexb_nalus(&stream),
+            vec![&[0x67, 1][..], &[0x68, 2], &[0x65, 0, 3, 0]]
+        );
+
+        let config = AudioSpecificConfig::parse(&[0x12, 0x10]).unwrap();
+        assert_eq!(config.to_bytes(), [0x12, 0x10]);
+        let mut stream = Vec::new();
+        for frame in &[&[1, 2, 3][..], &[4]] {
+            stream.extend_from_slice(&config.adts_header(frame.len()).unwrap());
+            stream.extend_from_slice(frame);
+        }
+        let (parsed, frames) = adts_frames(&stream).unwrap();
+        assert_eq!(parsed, config);
+        assert_eq!(frames, vec![&[1, 2, 3][..], &[4]]);
+        assert!(adts_frames(&stream[..stream.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,200 @@
+use crate::backend::BackendKind;
+use crate::live::{SlowViewerPolicy, ViewerLimits};
+use crate::my_error::my_error;
+use std::fs;
+use std::io::Result as IoResult;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard};
+use std::time::Duration;
+
+/// Server settings.
+/// The file has one `key = value` per line, # starts a comment, durations are seconds.
+/// On SIGHUP the timeouts and read settings are reloaded,
+/// listen, workers, reuseport, backend and event_buffer_size need a restart.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub listen: String,
+    pub workers: usize,
+    /// a started request must be complete within this
+    pub request_timeout: Duration,
+    /// idle keep-alive connections are closed after this
+    pub keep_alive_timeout: Duration,
+    /// a publisher sending nothing for this long is dropped
+    pub ingest_timeout: Duration,
+    /// a viewer not taking any pending data for this long is dropped
+    pub viewer_stall_timeout: Duration,
+    /// how much a viewer may fall behind before slow_viewer_policy applies
+    pub viewer_queue_bytes: usize,
+    pub viewer_queue_duration: Duration,
+    /// drop_frames, skip_gop or disconnect
+    pub slow_viewer_policy: SlowViewerPolicy,
+    /// how often the listeners print server stats
+    pub stats_interval: Duration,
+    /// how long open responses may take to finish on shutdown
+    pub shutdown_drain: Duration,
+    /// every worker binds its own listener, otherwise they share one
+    pub reuseport: bool,
+    /// register connections edge triggered, each wakeup reads until WouldBlock
+    pub edge_triggered: bool,
+    /// bytes a connection may read per wakeup before others get their turn
+    pub read_budget: usize,
+    /// events a worker takes from epoll at once, the io_uring ring size
+    pub event_buffer_size: usize,
+    /// epoll or uring
+    pub backend: BackendKind,
+    /// directory served under /vod/
+    pub vod_root: String,
+}
+
+pub type SharedConfig = Arc<RwLock<Config>>;
+
+/// the settings in effect now, a poisoned lock still holds valid settings
+pub fn current(config: &SharedConfig) -> RwLockReadGuard<'_, Config> {
+    config.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: "192.168.74.3:8848".to_owned(),
+            workers: 1,
+            request_timeout: Duration::from_secs(10),
+            keep_alive_timeout: Duration::from_secs(30),
+            ingest_timeout: Duration::from_secs(30),
+            viewer_stall_timeout: Duration::from_secs(30),
+            viewer_queue_bytes: 8 * 1024 * 1024,
+            viewer_queue_duration: Duration::from_secs(15),
+            slow_viewer_policy: SlowViewerPolicy::DropFrames,
+            stats_interval: Duration::from_secs(60),
+            shutdown_drain: Duration::from_secs(10),
+            reuseport: true,
+            edge_triggered: false,
+            read_budget: 256 * 1024,
+            event_buffer_size: 1024,
+            backend: BackendKind::Epoll,
+            vod_root: "vod".to_owned(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &str) -> IoResult<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| my_error(format!("read config {} failed:{}", path, err)))?;
+        Self::parse(&text).map_err(|err| my_error(format!("config {}: {}", path, err)))
+    }
+
+    /// settings missing from text keep their defaults
+    pub fn parse(text: &str) -> IoResult<Self> {
+        let mut config = Self::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => return Err(my_error(format!("line {}: expect key = value", index + 1))),
+            };
+            let number = || {
+                value.parse::<u64>().map_err(|_| {
+                    my_error(format!(
+                        "line {}: {} is not a number: {}",
+                        index + 1,
+                        key,
+                        value
+                    ))
+                })
+            };
+            let flag = || match value {
+                "true" | "yes" | "on" => Ok(true),
+                "false" | "no" | "off" => Ok(false),
+                _ => Err(my_error(format!(
+                    "line {}: {} is not true or false: {}",
+                    index + 1,
+                    key,
+                    value
+                ))),
+            };
+            match key {
+                "listen" => config.listen = value.to_owned(),
+                "vod_root" => config.vod_root = value.to_owned(),
+                "workers" => config.workers = number()?.max(1) as usize,
+                "request_timeout" => config.request_timeout = Duration::from_secs(number()?),
+                "keep_alive_timeout" => config.keep_alive_timeout = Duration::from_secs(number()?),
+                "ingest_timeout" => config.ingest_timeout = Duration::from_secs(number()?),
+                "viewer_stall_timeout" => {
+                    config.viewer_stall_timeout = Duration::from_secs(number()?)
+                }
+                "viewer_queue_bytes" => config.viewer_queue_bytes = number()? as usize,
+                "viewer_queue_duration" => {
+                    config.viewer_queue_duration = Duration::from_secs(number()?)
+                }
+                "slow_viewer_policy" => {
+                    config.slow_viewer_policy = SlowViewerPolicy::parse(value).ok_or_else(|| {
+                        my_error(format!(
+                            "line {}: unknown slow viewer policy {}",
+                            index + 1,
+                            value
+                        ))
+                    })?
+                }
+                "stats_interval" => config.stats_interval = Duration::from_secs(number()?.max(1)),
+                "shutdown_drain" => config.shutdown_drain = Duration::from_secs(number()?),
+                "reuseport" => config.reuseport = flag()?,
+                "edge_triggered" => config.edge_triggered = flag()?,
+                "read_budget" => config.read_budget = number()?.max(1) as usize,
+                "backend" => {
+                    config.backend = BackendKind::parse(value).ok_or_else(|| {
+                        my_error(format!("line {}: unknown backend {}", index + 1, value))
+                    })?
+                }
+                "event_buffer_size" => config.event_buffer_size = number()?.max(1) as usize,
+                _ => {
+                    return Err(my_error(format!(
+                        "line {}: unknown setting {}",
+                        index + 1,
+                        key
+                    )))
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    /// limits for viewers starting now
+    pub fn viewer_limits(&self) -> ViewerLimits {
+        ViewerLimits {
+            max_bytes: self.viewer_queue_bytes,
+            max_duration_ms: self.viewer_queue_duration.as_millis().min(u32::MAX as u128) as u32,
+            policy: self.slow_viewer_policy,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(
+            "# test server\nlisten = 127.0.0.1:8848\n\nworkers=4 # one per core\nkeep_alive_timeout = 5\nedge_triggered = yes\nbackend = uring\nslow_viewer_policy = skip_gop\n",
+        )
+        .unwrap();
+        assert_eq!(config.listen, "127.0.0.1:8848");
+        assert_eq!(config.workers, 4);
+        assert_eq!(config.keep_alive_timeout, Duration::from_secs(5));
+        assert_eq!(config.request_timeout, Config::default().request_timeout);
+        assert!(config.edge_triggered);
+        assert!(config.reuseport);
+        assert_eq!(config.backend, BackendKind::Uring);
+        assert_eq!(config.slow_viewer_policy, SlowViewerPolicy::SkipGop);
+        assert_eq!(config.viewer_limits().max_duration_ms, 15_000);
+
+        assert!(Config::parse("workers = many\n").is_err());
+        assert!(Config::parse("listen\n").is_err());
+        assert!(Config::parse("reuseport = maybe\n").is_err());
+        assert!(Config::parse("slow_viewer_policy = wait\n").is_err());
+        assert!(Config::parse("colour = blue\n").is_err());
+    }
+}
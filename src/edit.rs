@@ -0,0 +1,677 @@
+use crate::codec::AudioSpecificConfig;
+use crate::flv::{
+    merge_script_data, parse_script_data, read_tags, rewrite_script_data, TagInfo, FLV_HEADER_LEN,
+    PRE_TAG_SIZE_LEN, TAG_HEADER_LEN,
+};
+use crate::flv_demuxer::{RawTag, TAG_TYPE_AUDIO, TAG_TYPE_SCRIPT, TAG_TYPE_VIDEO};
+use crate::flv_writer::file_size;
+use crate::my_error::my_error;
+use std::fs;
+use std::io::Result as IoResult;
+use std::ops::Range;
+
+/// onMetaData properties pointing into the original file, dropped from edited files
+const STALE_METADATA: &[&str] = &["keyframes"];
+
+fn is_metadata(body: &[u8]) -> bool {
+    parse_script_data(body).is_ok_and(|data| data.name == "onMetaData")
+}
+
+/// where a file can start playing: video keyframes, or any audio frame without video
+fn is_sync_point(tag: &TagInfo, has_video: bool) -> bool {
+    if has_video {
+        tag.tag_type == TAG_TYPE_VIDEO && tag.is_keyframe() && !tag.is_sequence_header()
+    } else {
+        tag.tag_type == TAG_TYPE_AUDIO && !tag.is_sequence_header()
+    }
+}
+
+/// sample rates of the SoundRate field of audio tags
+const SOUND_RATES: [u32; 4] = [5512, 11025, 22050, 44100];
+
+/// A file read for editing
+pub struct Input {
+    pub name: String,
+    pub file: Vec<u8>,
+    pub tags: Vec<TagInfo>,
+}
+
+impl Input {
+    pub fn read(path: &str) -> IoResult<Self> {
+        let file = fs::read(path)?;
+        let (_, tags) = read_tags(&file).map_err(|err| my_error(format!("{}: {}", path, err)))?;
+        Ok(Self {
+            name: path.to_owned(),
+            file,
+            tags,
+        })
+    }
+
+    fn body(&self, tag: &TagInfo) -> &[u8] {
+        &tag.bytes(&self.file)[TAG_HEADER_LEN..]
+    }
+
+    fn metadata(&self) -> Option<&TagInfo> {
+        self.tags
+            .iter()
+            .find(|tag| tag.tag_type == TAG_TYPE_SCRIPT && is_metadata(self.body(tag)))
+    }
+
+    fn video_codec(&self) -> Option<u8> {
+        self.tags
+            .iter()
+            .find(|tag| tag.tag_type == TAG_TYPE_VIDEO)
+            .and_then(|tag| tag.codec)
+    }
+
+    /// SoundFormat, sample rate and channels, for AAC from the AudioSpecificConfig
+    fn audio_format(&self) -> Option<(u8, u32, u8)> {
+        let flags = self
+            .tags
+            .iter()
+            .find(|tag| tag.tag_type == TAG_TYPE_AUDIO)
+            .map(|tag| self.body(tag)[0])?;
+        let config = self
+            .tags
+            .iter()
+            .find(|tag| tag.tag_type == TAG_TYPE_AUDIO && tag.is_sequence_header())
+            .and_then(|tag| AudioSpecificConfig::parse(&self.body(tag)[2..]).ok());
+        Some(match config {
+            Some(config) => (flags >> 4, config.sample_rate(), config.channels),
+            None => (
+                flags >> 4,
+                SOUND_RATES[(flags >> 2 & 0x03) as usize],
+                (flags & 0x01) + 1,
+            ),
+        })
+    }
+}
+
+/// The tags from-to of a file as a file of their own: starting at the
+/// keyframe at or before from, metadata and sequence headers up front and
+/// timestamps rebased to 0. Tags at to and later are left out.
+pub fn cut(file: &[u8], tags: &[TagInfo], from: u32, to: u32) -> IoResult<Vec<RawTag>> {
+    if from >= to {
+        return Err(my_error(format!(
+            "cut from {}ms is not before to {}ms",
+            from, to
+        )));
+    }
+    if !tags
+        .iter()
+        .any(|tag| tag.tag_type != TAG_TYPE_SCRIPT && (from..to).contains(&tag.timestamp))
+    {
+        return Err(my_error(format!(
+            "no audio or video from {}ms to {}ms",
+            from, to
+        )));
+    }
+    let has_video = tags.iter().any(|tag| tag.tag_type == TAG_TYPE_VIDEO);
+    let mut sync_points = tags
+        .iter()
+        .enumerate()
+        .filter(|(_, tag)| is_sync_point(tag, has_video));
+    let first = sync_points
+        .next()
+        .ok_or_else(|| my_error("no keyframe to start the cut at"))?;
+    let (start, start_tag) = sync_points
+        .take_while(|(_, tag)| tag.timestamp <= from)
+        .last()
+        .unwrap_or(first);
+    let start_ts = start_tag.timestamp;
+    if start_ts >= to {
+        return Err(my_error(format!(
+            "first keyframe at {}ms is not before to {}ms",
+            start_ts, to
+        )));
+    }
+
+    let body = |tag: &TagInfo| &tag.bytes(file)[TAG_HEADER_LEN..];
+    let metadata = tags
+        .iter()
+        .find(|tag| tag.tag_type == TAG_TYPE_SCRIPT && is_metadata(body(tag)));
+    // the sequence headers in effect at the start
+    let sequence_headers = [TAG_TYPE_VIDEO, TAG_TYPE_AUDIO]
+        .iter()
+        .filter_map(|&track| {
+            tags[..start]
+                .iter()
+                .rev()
+                .find(|tag| tag.tag_type == track && tag.is_sequence_header())
+        });
+    let mut out: Vec<RawTag> = metadata
+        .into_iter()
+        .chain(sequence_headers)
+        .map(|tag| {
+            let mut tag = tag.raw_tag(file);
+            tag.set_timestamp(0);
+            tag
+        })
+        .collect();
+
+    for (index, tag) in tags.iter().enumerate() {
+        if Some(tag) == metadata || tag.timestamp < start_ts || tag.timestamp >= to {
+            continue;
+        }
+        // audio muxed just before the keyframe is kept, video and headers from it on
+        if index < start && (tag.tag_type != TAG_TYPE_AUDIO || tag.is_sequence_header()) {
+            continue;
+        }
+        let mut tag = tag.raw_tag(file);
+        tag.set_timestamp(tag.timestamp - start_ts);
+        out.push(tag);
+    }
+    update_metadata(&mut out)?;
+    Ok(out)
+}
+
+/// Joined files and what did not quite fit
+pub struct Concat {
+    pub tags: Vec<RawTag>,
+    pub warnings: Vec<String>,
+}
+
+/// where the next file goes on after tags: the last timestamp
+/// of each track plus the duration of its last frame
+fn end_timestamp(tags: &[RawTag]) -> Option<u32> {
+    [TAG_TYPE_VIDEO, TAG_TYPE_AUDIO]
+        .iter()
+        .filter_map(|&track| {
+            let mut timestamps = tags
+                .iter()
+                .filter(|tag| tag.tag_type == track)
+                .map(|tag| tag.timestamp);
+            let mut last = timestamps.next()?;
+            let mut step = 0;
+            for timestamp in timestamps {
+                if timestamp > last {
+                    step = timestamp - last;
+                }
+                last = last.max(timestamp);
+            }
+            Some(last + step)
+        })
+        .max()
+}
+
+/// Join files one after the other with continuous timestamps.
+/// Files with other codecs, sample rates or channels than the first file
+/// carrying the track are rejected, sequence headers are only written again
+/// when the configuration changes and the onMetaData is that of the first
+/// file with what the others add.
+pub fn concat(inputs: &[Input]) -> IoResult<Concat> {
+    let first = inputs.first().ok_or_else(|| my_error("nothing to join"))?;
+    let mut warnings = Vec::new();
+    // the format of each track and the first file carrying it
+    let mut video = first.video_codec().map(|codec| (codec, first));
+    let mut audio = first.audio_format().map(|format| (format, first));
+    for input in &inputs[1..] {
+        match (video, input.video_codec()) {
+            (Some((codec, from)), Some(other)) if codec != other => {
+                return Err(my_error(format!(
+                    "{}: video codec {} is not {} of {}",
+                    input.name, other, codec, from.name
+                )))
+            }
+            (Some((_, from)), None) => {
+                warnings.push(format!("{}: has no video, {} does", input.name, from.name))
+            }
+            (None, Some(codec)) => {
+                warnings.push(format!(
+                    "{}: has video, {} does not",
+                    input.name, first.name
+                ));
+                video = Some((codec, input));
+            }
+            _ => (),
+        }
+        match (audio, input.audio_format()) {
+            (Some((format, from)), Some(other)) if format != other => {
+                return Err(my_error(format!(
+                    "{}: audio format {} {}Hz {} channels is not {} {}Hz {} channels of {}",
+                    input.name, other.0, other.1, other.2, format.0, format.1, format.2, from.name
+                )))
+            }
+            (Some((_, from)), None) => {
+                warnings.push(format!("{}: has no audio, {} does", input.name, from.name))
+            }
+            (None, Some(format)) => {
+                warnings.push(format!(
+                    "{}: has audio, {} does not",
+                    input.name, first.name
+                ));
+                audio = Some((format, input));
+            }
+            _ => (),
+        }
+    }
+
+    let mut tags = Vec::new();
+    let mut metadata: Option<Vec<u8>> = None;
+    for input in inputs {
+        if let Some(tag) = input.metadata() {
+            metadata = Some(match metadata {
+                Some(body) => merge_script_data(&body, input.body(tag))?,
+                None => input.body(tag).to_vec(),
+            });
+        }
+    }
+    if let Some(body) = metadata {
+        tags.push(RawTag::new(TAG_TYPE_SCRIPT, 0, &body));
+    }
+
+    // the sequence headers in effect, video and audio
+    let mut sequence_headers: [Option<&[u8]>; 2] = [None, None];
+    let mut base = 0;
+    for input in inputs {
+        let start = tags.len();
+        // sequence headers are often stamped 0 whatever the frames start at,
+        // earlier ones go to the start of the file
+        let first_ts = input
+            .tags
+            .iter()
+            .filter(|tag| tag.tag_type != TAG_TYPE_SCRIPT && !tag.is_sequence_header())
+            .map(|tag| tag.timestamp)
+            .min()
+            .unwrap_or(0);
+        let metadata = input.metadata();
+        for tag in &input.tags {
+            if Some(tag) == metadata {
+                continue;
+            }
+            let timestamp = tag.timestamp.saturating_sub(first_ts) + base;
+            if tag.is_sequence_header() {
+                let (track, current) = match tag.tag_type {
+                    TAG_TYPE_VIDEO => ("video", &mut sequence_headers[0]),
+                    _ => ("audio", &mut sequence_headers[1]),
+                };
+                let body = input.body(tag);
+                match current {
+                    Some(current) if *current == body => continue,
+                    Some(_) => warnings.push(format!(
+                        "{}: {} configuration changes at {}ms",
+                        input.name, track, timestamp
+                    )),
+                    None => (),
+                }
+                *current = Some(body);
+            }
+            let mut tag = tag.raw_tag(&input.file);
+            tag.set_timestamp(timestamp);
+            tags.push(tag);
+        }
+        base = end_timestamp(&tags[start..]).unwrap_or(base);
+    }
+    update_metadata(&mut tags)?;
+    Ok(Concat { tags, warnings })
+}
+
+/// When split starts a new part
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SplitLimit {
+    /// ms, parts are at least this long
+    Duration(u32),
+    /// bytes, parts are at most this big unless a single GOP is bigger
+    Size(u64),
+}
+
+fn metadata_index(file: &[u8], tags: &[TagInfo]) -> Option<usize> {
+    tags.iter().position(|tag| {
+        tag.tag_type == TAG_TYPE_SCRIPT && is_metadata(&tag.bytes(file)[TAG_HEADER_LEN..])
+    })
+}
+
+/// Where to cut a file into parts that play on their own: at keyframes only,
+/// the ranges of tags for split_part
+pub fn split(file: &[u8], tags: &[TagInfo], limit: SplitLimit) -> IoResult<Vec<Range<usize>>> {
+    if tags.iter().all(|tag| tag.tag_type == TAG_TYPE_SCRIPT) {
+        return Err(my_error("no audio or video to split"));
+    }
+    let has_video = tags.iter().any(|tag| tag.tag_type == TAG_TYPE_VIDEO);
+    let metadata = metadata_index(file, tags);
+    let tag_size = |tag: &TagInfo| (TAG_HEADER_LEN + tag.data_size + PRE_TAG_SIZE_LEN) as u64;
+    // header, metadata and sequence headers, about the same for every part
+    let overhead = (FLV_HEADER_LEN + PRE_TAG_SIZE_LEN) as u64
+        + tags
+            .iter()
+            .enumerate()
+            .filter(|(index, tag)| Some(*index) == metadata || tag.is_sequence_header())
+            .take(3)
+            .map(|(_, tag)| tag_size(tag))
+            .sum::<u64>();
+
+    // the index each part starts at, going a GOP at a time
+    let mut starts = vec![0];
+    let mut part_ts = None;
+    let mut part_size = overhead;
+    let mut gop_start = 0;
+    for index in 1..=tags.len() {
+        if index < tags.len() && !is_sync_point(&tags[index], has_video) {
+            continue;
+        }
+        let gop = &tags[gop_start..index];
+        let gop_ts = gop[0].timestamp;
+        let gop_size: u64 = gop.iter().map(tag_size).sum();
+        let full = match limit {
+            SplitLimit::Duration(ms) => gop_ts.saturating_sub(*part_ts.get_or_insert(gop_ts)) >= ms,
+            SplitLimit::Size(bytes) => part_size + gop_size > bytes,
+        };
+        if gop_start > 0 && full {
+            starts.push(gop_start);
+            part_ts = Some(gop_ts);
+            part_size = overhead;
+        }
+        part_size += gop_size;
+        gop_start = index;
+    }
+    starts.push(tags.len());
+    Ok(starts
+        .windows(2)
+        .map(|bounds| bounds[0]..bounds[1])
+        .collect())
+}
+
+/// The tags of range, a part from split, as a file of their own: the metadata
+/// and the sequence headers in effect up front and timestamps from 0
+pub fn split_part(file: &[u8], tags: &[TagInfo], range: Range<usize>) -> IoResult<Vec<RawTag>> {
+    let metadata = metadata_index(file, tags);
+    let base = tags[range.clone()]
+        .iter()
+        .filter(|tag| tag.tag_type != TAG_TYPE_SCRIPT && !tag.is_sequence_header())
+        .map(|tag| tag.timestamp)
+        .min()
+        .unwrap_or(0);
+    let sequence_headers = [TAG_TYPE_VIDEO, TAG_TYPE_AUDIO]
+        .iter()
+        .filter_map(|&track| {
+            tags[..range.start]
+                .iter()
+                .rev()
+                .find(|tag| tag.tag_type == track && tag.is_sequence_header())
+        });
+    let mut part: Vec<RawTag> = metadata
+        .map(|index| &tags[index])
+        .into_iter()
+        .chain(sequence_headers)
+        .map(|tag| {
+            let mut tag = tag.raw_tag(file);
+            tag.set_timestamp(0);
+            tag
+        })
+        .collect();
+    for index in range {
+        if Some(index) == metadata {
+            continue;
+        }
+        let mut tag = tags[index].raw_tag(file);
+        tag.set_timestamp(tag.timestamp.saturating_sub(base));
+        part.push(tag);
+    }
+    update_metadata(&mut part)?;
+    Ok(part)
+}
+
+/// Set duration, and filesize where it is there, of the onMetaData
+/// among tags to what they are in a file of just these tags
+pub fn update_metadata(tags: &mut [RawTag]) -> IoResult<()> {
+    let index = match tags
+        .iter()
+        .position(|tag| tag.tag_type == TAG_TYPE_SCRIPT && is_metadata(tag.body()))
+    {
+        Some(index) => index,
+        None => return Ok(()),
+    };
+    let media = tags.iter().filter(|tag| tag.tag_type != TAG_TYPE_SCRIPT);
+    let first = media.clone().map(|tag| tag.timestamp).min().unwrap_or(0);
+    let last = media.map(|tag| tag.timestamp).max().unwrap_or(0);
+    let duration = (last - first) as f64 / 1000.0;
+
+    let metadata = parse_script_data(tags[index].body())?;
+    rewrite_metadata(&mut tags[index], &[("duration", duration)])?;
+    if metadata.numbers.contains_key("filesize") {
+        // numbers are 8 bytes whatever their value, the size does not change with it
+        let size = file_size(tags) as f64;
+        rewrite_metadata(&mut tags[index], &[("filesize", size)])?;
+    }
+    Ok(())
+}
+
+fn rewrite_metadata(tag: &mut RawTag, set: &[(&str, f64)]) -> IoResult<()> {
+    let body = rewrite_script_data(tag.body(), set, STALE_METADATA)?;
+    *tag = RawTag::new(TAG_TYPE_SCRIPT, tag.timestamp, &body);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flv::read_tags;
+    use crate::flv_writer::write_file;
+
+    /// onMetaData body with these numbers and a keyframe index
+    fn metadata_body(numbers: &[(&str, f64)]) -> Vec<u8> {
+        let mut body = vec![2, 0, 10];
+        body.extend_from_slice(b"onMetaData");
+        body.extend_from_slice(&[8, 0, 0, 0, numbers.len() as u8 + 1]);
+        for (name, number) in numbers {
+            body.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            body.extend_from_slice(name.as_bytes());
+            body.push(0);
+            body.extend_from_slice(&number.to_be_bytes());
+        }
+        body.extend_from_slice(&[0, 9]);
+        body.extend_from_slice(b"keyframes");
+        body.extend_from_slice(&[3, 0, 5]);
+        body.extend_from_slice(b"times");
+        body.extend_from_slice(&[10, 0, 0, 0, 1, 0]);
+        body.extend_from_slice(&0f64.to_be_bytes());
+        body.extend_from_slice(&[0, 0, 9, 0, 0, 9]);
+        body
+    }
+
+    /// metadata, sequence headers, then 40ms video with a keyframe every second
+    /// and 20ms audio, from start on
+    fn make_file(start: u32, frames: u32) -> Vec<u8> {
+        let mut tags = vec![
+            RawTag::new(
+                TAG_TYPE_SCRIPT,
+                0,
+                &metadata_body(&[("duration", 0.0), ("filesize", 0.0)]),
+            ),
+            RawTag::new(TAG_TYPE_VIDEO, start, &[0x17, 0, 0, 0, 0, 1, 0x64]),
+            RawTag::new(TAG_TYPE_AUDIO, start, &[0xaf, 0, 0x12, 0x10]),
+        ];
+        for frame in 0..frames {
+            let ts = start + frame * 40;
+            let frame_type = if frame % 25 == 0 { 0x17 } else { 0x27 };
+            tags.push(RawTag::new(
+                TAG_TYPE_VIDEO,
+                ts,
+                &[frame_type, 1, 0, 0, 0, 9],
+            ));
+            tags.push(RawTag::new(TAG_TYPE_AUDIO, ts, &[0xaf, 1, 1]));
+            tags.push(RawTag::new(TAG_TYPE_AUDIO, ts + 20, &[0xaf, 1, 2]));
+        }
+        write_file(Vec::new(), &tags).unwrap()
+    }
+
+    /// the file without the tags of one type
+    fn without(file: Vec<u8>, tag_type: u8) -> Vec<u8> {
+        let (_, tags) = read_tags(&file).unwrap();
+        let tags: Vec<RawTag> = tags
+            .iter()
+            .filter(|tag| tag.tag_type != tag_type)
+            .map(|tag| tag.raw_tag(&file))
+            .collect();
+        write_file(Vec::new(), &tags).unwrap()
+    }
+
+    fn input(name: &str, file: Vec<u8>) -> Input {
+        let (_, tags) = read_tags(&file).unwrap();
+        Input {
+            name: name.to_owned(),
+            file,
+            tags,
+        }
+    }
+
+    #[test]
+    fn test_concat() {
+        // the second file starts at 1 hour, its sequence headers are the same
+        let inputs = vec![
+            input("a", make_file(0, 50)),
+            input("b", make_file(3_600_000, 50)),
+        ];
+        let joined = concat(&inputs).unwrap();
+        assert_eq!(joined.warnings, Vec::<String>::new());
+        assert_eq!(joined.tags.len(), 1 + 2 + 2 * 50 * 3);
+        let timestamps: Vec<u32> = joined
+            .tags
+            .iter()
+            .filter(|tag| tag.tag_type == TAG_TYPE_VIDEO)
+            .map(|tag| tag.timestamp)
+            .collect();
+        assert_eq!(timestamps[..3], [0, 0, 40]);
+        assert_eq!(timestamps[50..52], [1960, 2000]);
+        let metadata = parse_script_data(joined.tags[0].body()).unwrap();
+        assert_eq!(metadata.numbers["duration"], 3.98);
+
+        // sequence headers stamped 0 in a file starting at 1 hour
+        let (_, b_tags) = read_tags(&inputs[1].file).unwrap();
+        let b_tags: Vec<RawTag> = b_tags
+            .iter()
+            .map(|tag| {
+                let mut raw = tag.raw_tag(&inputs[1].file);
+                if tag.is_sequence_header() {
+                    raw.set_timestamp(0);
+                }
+                raw
+            })
+            .collect();
+        let inputs = vec![
+            input("a", make_file(0, 50)),
+            input("b", write_file(Vec::new(), &b_tags).unwrap()),
+        ];
+        let joined = concat(&inputs).unwrap();
+        let video: Vec<&RawTag> = joined
+            .tags
+            .iter()
+            .filter(|tag| tag.tag_type == TAG_TYPE_VIDEO)
+            .collect();
+        assert_eq!(video.len(), 1 + 2 * 50);
+        assert_eq!(video[51].timestamp, 2000);
+
+        // a new AudioSpecificConfig at 48kHz
+        let mut other = make_file(0, 50);
+        let at = other
+            .windows(4)
+            .position(|bytes| bytes == [0xaf, 0, 0x12, 0x10])
+            .unwrap();
+        other[at + 2..at + 4].copy_from_slice(&[0x11, 0x88]);
+        let inputs = vec![input("a", make_file(0, 50)), input("c", other.clone())];
+        assert!(concat(&inputs).is_err());
+        // checked against the first file with audio
+        let inputs = vec![
+            input("a", without(make_file(0, 50), TAG_TYPE_AUDIO)),
+            input("b", make_file(0, 50)),
+            input("c", other),
+        ];
+        let err = concat(&inputs).err().unwrap().to_string();
+        assert!(err.starts_with("c: audio format") && err.ends_with("of b"));
+
+        // tracks missing on either side
+        let inputs = vec![
+            input("a", make_file(0, 50)),
+            input("b", without(make_file(0, 50), TAG_TYPE_AUDIO)),
+            input("c", without(make_file(0, 50), TAG_TYPE_VIDEO)),
+        ];
+        assert_eq!(
+            concat(&inputs).unwrap().warnings,
+            vec!["b: has no audio, a does", "c: has no video, a does"]
+        );
+        let inputs = vec![
+            input("b", without(make_file(0, 50), TAG_TYPE_AUDIO)),
+            input("a", make_file(0, 50)),
+        ];
+        assert_eq!(
+            concat(&inputs).unwrap().warnings,
+            vec!["a: has audio, b does not"]
+        );
+        let inputs = vec![
+            input("b", without(make_file(0, 50), TAG_TYPE_AUDIO)),
+            input("a", make_file(0, 50)),
+            input("c", without(make_file(0, 50), TAG_TYPE_AUDIO)),
+        ];
+        assert_eq!(
+            concat(&inputs).unwrap().warnings,
+            vec!["a: has audio, b does not", "c: has no audio, a does"]
+        );
+    }
+
+    #[test]
+    fn test_split() {
+        let file = make_file(0, 250);
+        let (_, tags) = read_tags(&file).unwrap();
+        let split_all = |limit| -> Vec<Vec<RawTag>> {
+            split(&file, &tags, limit)
+                .unwrap()
+                .into_iter()
+                .map(|range| split_part(&file, &tags, range).unwrap())
+                .collect()
+        };
+        let parts = split_all(SplitLimit::Duration(3000));
+        // keyframes every second, 10s
+        assert_eq!(parts.len(), 4);
+        for part in &parts {
+            assert!(part[1].is_sequence_header() && part[2].is_sequence_header());
+            assert!(part[3].is_video_keyframe() && part[3].timestamp == 0);
+            let metadata = parse_script_data(part[0].body()).unwrap();
+            assert_eq!(metadata.numbers["filesize"], file_size(part) as f64);
+        }
+        let last = parse_script_data(parts[3][0].body()).unwrap();
+        assert_eq!(last.numbers["duration"], 0.98);
+        let media =
+            |parts: &[Vec<RawTag>]| -> usize { parts.iter().map(|part| part.len() - 3).sum() };
+        assert_eq!(media(&parts), 250 * 3);
+
+        let parts = split_all(SplitLimit::Size(6000));
+        assert_eq!(media(&parts), 250 * 3);
+        assert!(parts.iter().all(|part| file_size(part) <= 6000));
+        // 1400 bytes a second
+        assert_eq!(parts.len(), 3);
+
+        // nothing but metadata
+        let file = write_file(
+            Vec::new(),
+            &[RawTag::new(TAG_TYPE_SCRIPT, 0, &metadata_body(&[]))],
+        )
+        .unwrap();
+        let (_, tags) = read_tags(&file).unwrap();
+        assert!(split(&file, &tags, SplitLimit::Duration(3000)).is_err());
+        assert!(split(&file, &[], SplitLimit::Duration(3000)).is_err());
+    }
+
+    #[test]
+    fn test_cut() {
+        let file = make_file(0, 250);
+        let (_, tags) = read_tags(&file).unwrap();
+        let out = cut(&file, &tags, 2500, 4000).unwrap();
+        let output = write_file(Vec::new(), &out).unwrap();
+        let (_, cut_tags) = read_tags(&output).unwrap();
+
+        // metadata and sequence headers, then the keyframe at 2s
+        assert!(cut_tags[1].is_sequence_header() && cut_tags[2].is_sequence_header());
+        assert!(cut_tags[3].is_keyframe() && !cut_tags[3].is_sequence_header());
+        assert!(cut_tags.iter().all(|tag| tag.timestamp < 2000));
+        assert_eq!(cut_tags.iter().map(|tag| tag.timestamp).max(), Some(1980));
+        assert_eq!(cut_tags.len(), 3 + 50 * 3);
+
+        let metadata = parse_script_data(&cut_tags[0].bytes(&output)[TAG_HEADER_LEN..]).unwrap();
+        assert_eq!(metadata.numbers["duration"], 1.98);
+        assert_eq!(metadata.numbers["filesize"], output.len() as f64);
+        assert!(!output.windows(9).any(|bytes| bytes == b"keyframes"));
+
+        assert!(cut(&file, &tags, 2000, 1000).is_err());
+        assert!(cut(&file, &tags, 20_000, 30_000).is_err());
+    }
+}
@@ -0,0 +1,379 @@
+use crate::codec::{
+    adts_frames, annexb_nalus, avcc_nalus, nal_type, slice_type, AudioSpecificConfig,
+    AvcDecoderConfig, NAL_AUD, NAL_IDR, NAL_PPS, NAL_SLICE, NAL_SPS, SLICE_B, START_CODE,
+};
+use crate::edit::update_metadata;
+use crate::flv::{script_data, TagInfo, TAG_HEADER_LEN};
+use crate::flv_demuxer::{
+    RawTag, SOUND_FORMAT_AAC, SOUND_FORMAT_MP3, TAG_TYPE_AUDIO, TAG_TYPE_SCRIPT, TAG_TYPE_VIDEO,
+    VIDEO_CODEC_AVC,
+};
+use crate::my_error::my_error;
+use std::io::Result as IoResult;
+use std::io::Write;
+use std::mem;
+
+/// AVCPacketType of coded frames
+const AVC_NALU: u8 = 1;
+const NAL_SEI: u8 = 6;
+/// AAC frames are this many samples
+const AAC_FRAME_SAMPLES: f64 = 1024.0;
+
+fn body<'a>(file: &'a [u8], tag: &TagInfo) -> &'a [u8] {
+    &tag.bytes(file)[TAG_HEADER_LEN..]
+}
+
+/// The video of a file as an H.264 Annex B stream, SPS and PPS are written
+/// before every IDR that does not carry them. Returns the frames written.
+pub fn extract_video<W: Write>(file: &[u8], tags: &[TagInfo], out: &mut W) -> IoResult<usize> {
+    let mut config: Option<AvcDecoderConfig> = None;
+    let mut frames = 0;
+    for tag in tags.iter().filter(|tag| tag.tag_type == TAG_TYPE_VIDEO) {
+        if tag.codec != Some(VIDEO_CODEC_AVC) {
+            return Err(my_error("video is not AVC, only AVC can be extracted"));
+        }
+        // behind the packet type and the composition time
+        let data = body(file, tag).get(5..).unwrap_or(&[]);
+        if tag.is_sequence_header() {
+            config = Some(AvcDecoderConfig::parse(data)?);
+            continue;
+        }
+        if tag.packet_type != Some(AVC_NALU) {
+            continue;
+        }
+        let config = config.as_ref().ok_or_else(|| {
+            my_error(format!(
+                "video frame at offset {} before any sequence header",
+                tag.offset
+            ))
+        })?;
+        let nalus = avcc_nalus(data, config.length_size)?;
+        let idr = nalus.iter().any(|nalu| nal_type(nalu) == NAL_IDR);
+        if idr && !nalus.iter().any(|nalu| nal_type(nalu) == NAL_SPS) {
+            for parameter_set in config.sps.iter().chain(&config.pps) {
+                out.write_all(&START_CODE)?;
+                out.write_all(parameter_set)?;
+            }
+        }
+        for nalu in nalus {
+            out.write_all(&START_CODE)?;
+            out.write_all(nalu)?;
+        }
+        frames += 1;
+    }
+    Ok(frames)
+}
+
+/// The audio of a file as ADTS for AAC or as it is for MP3.
+/// Returns the frames written.
+pub fn extract_audio<W: Write>(file: &[u8], tags: &[TagInfo], out: &mut W) -> IoResult<usize> {
+    let mut config = None;
+    let mut frames = 0;
+    for tag in tags.iter().filter(|tag| tag.tag_type == TAG_TYPE_AUDIO) {
+        let data = body(file, tag).get(1..).unwrap_or(&[]);
+        match tag.codec {
+            Some(SOUND_FORMAT_MP3) => out.write_all(data)?,
+            Some(SOUND_FORMAT_AAC) => {
+                // behind the AACPacketType
+                let raw = match (tag.packet_type, data.get(1..)) {
+                    (Some(_), Some(raw)) => raw,
+                    _ => {
+                        return Err(my_error(format!(
+                            "AAC audio tag at offset {} has no AACPacketType",
+                            tag.offset
+                        )))
+                    }
+                };
+                if tag.is_sequence_header() {
+                    config = Some(AudioSpecificConfig::parse(raw)?);
+                    continue;
+                }
+                let config = config.as_ref().ok_or_else(|| {
+                    my_error(format!(
+                        "audio frame at offset {} before any AudioSpecificConfig",
+                        tag.offset
+                    ))
+                })?;
+                out.write_all(&config.adts_header(raw.len())?)?;
+                out.write_all(raw)?;
+            }
+            _ => return Err(my_error("audio is neither AAC nor MP3")),
+        }
+        frames += 1;
+    }
+    Ok(frames)
+}
+
+/// NALUs grouped into access units: a new one starts with an AUD, with
+/// SEI or parameter sets after a slice, or with a slice of a new picture
+fn access_units(nalus: Vec<&[u8]>) -> Vec<Vec<&[u8]>> {
+    let mut units = Vec::new();
+    let mut unit = Vec::new();
+    // type of the last slice of the unit
+    let mut last_slice = None;
+    for nalu in nalus {
+        let nal = nal_type(nalu);
+        let slice = (1..=NAL_IDR).contains(&nal);
+        // first_mb_in_slice 0 is a single 1 bit in ue(v)
+        let new_picture = slice
+            && (nalu.get(1).is_some_and(|byte| byte & 0x80 != 0)
+                || last_slice.is_some_and(|last| (last == NAL_IDR) != (nal == NAL_IDR)));
+        if last_slice.is_some()
+            && (new_picture || [NAL_AUD, NAL_SEI, NAL_SPS, NAL_PPS].contains(&nal))
+        {
+            units.push(mem::take(&mut unit));
+            last_slice = None;
+        }
+        if slice {
+            last_slice = Some(nal);
+        }
+        unit.push(nalu);
+    }
+    if !unit.is_empty() {
+        units.push(unit);
+    }
+    units
+}
+
+/// Video tags of an H.264 Annex B stream at fps frames a second.
+/// Frames are in decode order with cts 0, so streams with B frames are rejected.
+fn video_tags(data: &[u8], fps: f64) -> IoResult<Vec<RawTag>> {
+    let nalus = annexb_nalus(data);
+    if nalus.is_empty() {
+        return Err(my_error("no start code found, video is not H.264 Annex B"));
+    }
+    let mut tags = Vec::new();
+    let mut config: Option<AvcDecoderConfig> = None;
+    // parameter sets since the last frame
+    let (mut sps, mut pps) = (Vec::new(), Vec::new());
+    let mut frames = 0;
+    for unit in access_units(nalus) {
+        let mut body = vec![0x27, AVC_NALU, 0, 0, 0];
+        for nalu in unit {
+            match nal_type(nalu) {
+                NAL_SPS => sps.push(nalu.to_vec()),
+                NAL_PPS => pps.push(nalu.to_vec()),
+                NAL_AUD => (),
+                nal => {
+                    if nal == NAL_IDR {
+                        body[0] = 0x17;
+                    }
+                    if nal == NAL_SLICE && slice_type(nalu)? == SLICE_B {
+                        return Err(my_error(format!(
+                            "video frame {} has B slices, only streams without B frames can be imported",
+                            frames
+                        )));
+                    }
+                    body.extend_from_slice(&(nalu.len() as u32).to_be_bytes());
+                    body.extend_from_slice(nalu);
+                }
+            }
+        }
+        if body.len() == 5 {
+            continue;
+        }
+        let timestamp = (frames as f64 * 1000.0 / fps).round() as u32;
+        if !sps.is_empty() || !pps.is_empty() {
+            let current = config.as_ref();
+            let sps = match sps.is_empty() {
+                true => current.map(|config| config.sps.clone()).unwrap_or_default(),
+                false => mem::take(&mut sps),
+            };
+            let pps = match pps.is_empty() {
+                true => current.map(|config| config.pps.clone()).unwrap_or_default(),
+                false => mem::take(&mut pps),
+            };
+            let new = AvcDecoderConfig::from_parameter_sets(sps, pps)?;
+            if current != Some(&new) {
+                let mut header = vec![0x17, 0, 0, 0, 0];
+                header.extend_from_slice(&new.to_bytes());
+                tags.push(RawTag::new(TAG_TYPE_VIDEO, timestamp, &header));
+                config = Some(new);
+            }
+        }
+        if config.is_none() {
+            return Err(my_error(format!(
+                "video frame {} before any SPS and PPS",
+                frames
+            )));
+        }
+        tags.push(RawTag::new(TAG_TYPE_VIDEO, timestamp, &body));
+        frames += 1;
+    }
+    if frames == 0 {
+        return Err(my_error("no video frames"));
+    }
+    Ok(tags)
+}
+
+/// Audio tags of an ADTS AAC stream and its configuration
+fn audio_tags(data: &[u8]) -> IoResult<(Vec<RawTag>, AudioSpecificConfig)> {
+    let (config, frames) = adts_frames(data)?;
+    let mut header = vec![0xaf, 0];
+    header.extend_from_slice(&config.to_bytes());
+    let mut tags = vec![RawTag::new(TAG_TYPE_AUDIO, 0, &header)];
+    let ms_per_frame = AAC_FRAME_SAMPLES * 1000.0 / config.sample_rate() as f64;
+    for (index, frame) in frames.iter().enumerate() {
+        let mut body = vec![0xaf, 1];
+        body.extend_from_slice(frame);
+        let timestamp = (index as f64 * ms_per_frame).round() as u32;
+        tags.push(RawTag::new(TAG_TYPE_AUDIO, timestamp, &body));
+    }
+    Ok((tags, config))
+}
+
+/// The tags of an FLV file of an H.264 Annex B stream at fps frames a second
+/// and an ADTS AAC stream, interleaved by timestamp behind an onMetaData
+pub fn import(video: Option<(&[u8], f64)>, audio: Option<&[u8]>) -> IoResult<Vec<RawTag>> {
+    let mut numbers = vec![("duration", 0.0), ("filesize", 0.0)];
+    let mut booleans = vec![("hasVideo", video.is_some()), ("hasAudio", audio.is_some())];
+    let mut tags = Vec::new();
+    if let Some((data, fps)) = video {
+        tags.extend(video_tags(data, fps)?);
+        numbers.extend_from_slice(&[("videocodecid", VIDEO_CODEC_AVC as f64), ("framerate", fps)]);
+    }
+    if let Some(data) = audio {
+        let (audio_tags, config) = audio_tags(data)?;
+        tags.extend(audio_tags);
+        numbers.extend_from_slice(&[
+            ("audiocodecid", SOUND_FORMAT_AAC as f64),
+            ("audiosamplerate", config.sample_rate() as f64),
+        ]);
+        booleans.push(("stereo", config.channels == 2));
+    }
+    // sequence headers before the frames at the same time
+    tags.sort_by_key(|tag| (tag.timestamp, !tag.is_sequence_header()));
+    let metadata = script_data("onMetaData", &numbers, &booleans);
+    tags.insert(0, RawTag::new(TAG_TYPE_SCRIPT, 0, &metadata));
+    update_metadata(&mut tags)?;
+    Ok(tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flv::read_tags;
+    use crate::flv_demuxer::RawTag;
+    use crate::flv_writer::write_file;
+
+    #[test]
+    fn test_extract() {
+        let record = [
+            1, 0x42, 0, 0x1e, 0xff, 0xe1, 0, 3, 0x67, 0x42, 0, 1, 0, 2, 0x68, 0xce,
+        ];
+        let mut video_header = vec![0x17, 0, 0, 0, 0];
+        video_header.extend_from_slice(&record);
+        let tags = vec![
+            RawTag::new(TAG_TYPE_VIDEO, 0, &video_header),
+            RawTag::new(TAG_TYPE_AUDIO, 0, &[0xaf, 0, 0x12, 0x10]),
+            RawTag::new(
+                TAG_TYPE_VIDEO,
+                0,
+                &[0x17, 1, 0, 0, 0, 0, 0, 0, 2, 0x65, 0xaa],
+            ),
+            RawTag::new(TAG_TYPE_AUDIO, 0, &[0xaf, 1, 0x21, 0x22]),
+            RawTag::new(TAG_TYPE_VIDEO, 40, &[0x27, 1, 0, 0, 0, 0, 0, 0, 1, 0x41]),
+        ];
+        let file = write_file(Vec::new(), &tags).unwrap();
+        let (_, tags) = read_tags(&file).unwrap();
+
+        let mut video = Vec::new();
+        assert_eq!(extract_video(&file, &tags, &mut video).unwrap(), 2);
+        assert_eq!(
+            video,
+            [
+                0, 0, 0, 1, 0x67, 0x42, 0, 0, 0, 0, 1, 0x68, 0xce, 0, 0, 0, 1, 0x65, 0xaa, 0, 0, 0,
+                1, 0x41
+            ]
+        );
+
+        let mut audio = Vec::new();
+        assert_eq!(extract_audio(&file, &tags, &mut audio).unwrap(), 1);
+        assert_eq!(
+            audio,
+            [0xff, 0xf1, 0x50, 0x80, 0x01, 0x3f, 0xfc, 0x21, 0x22]
+        );
+
+        // an AAC tag of only the SoundFormat byte
+        let short = write_file(Vec::new(), &[RawTag::new(TAG_TYPE_AUDIO, 0, &[0xaf])]).unwrap();
+        let (_, tags) = read_tags(&short).unwrap();
+        let err = extract_audio(&short, &tags, &mut Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("offset 13"));
+    }
+
+    #[test]
+    fn test_import() {
+        let mut video = Vec::new();
+        for (index, nalu) in [
+            &[0x09, 0xf0][..],
+            &[0x67, 0x42, 0, 0x1e],
+            &[0x68, 0xce],
+            &[0x65, 0x88, 1],
+            // a second slice of the same picture
+            &[0x65, 0x42, 2],
+            &[0x09, 0xf0],
+            &[0x41, 0x9a, 3],
+            &[0x41, 0x9a, 4],
+        ]
+        .iter()
+        .enumerate()
+        {
+            let start_code = if index % 2 == 0 {
+                &[0, 0, 1][..]
+            } else {
+                &[0, 0, 0, 1]
+            };
+            video.extend_from_slice(start_code);
+            video.extend_from_slice(nalu);
+        }
+        let config = AudioSpecificConfig::parse(&[0x11, 0x90]).unwrap();
+        let mut audio = Vec::new();
+        for frame in 0..3u8 {
+            audio.extend_from_slice(&config.adts_header(2).unwrap());
+            audio.extend_from_slice(&[frame, frame]);
+        }
+
+        let tags = import(Some((&video, 25.0)), Some(&audio)).unwrap();
+        let file = write_file(Vec::new(), &tags).unwrap();
+        let (header, tags) = read_tags(&file).unwrap();
+        assert!(header.has_audio && header.has_video);
+        let layout: Vec<(u8, u32, Option<u8>)> = tags
+            .iter()
+            .map(|tag| (tag.tag_type, tag.timestamp, tag.packet_type))
+            .collect();
+        assert_eq!(
+            layout,
+            vec![
+                (TAG_TYPE_SCRIPT, 0, None),
+                (TAG_TYPE_VIDEO, 0, Some(0)),
+                (TAG_TYPE_AUDIO, 0, Some(0)),
+                (TAG_TYPE_VIDEO, 0, Some(1)),
+                (TAG_TYPE_AUDIO, 0, Some(1)),
+                (TAG_TYPE_AUDIO, 21, Some(1)),
+                (TAG_TYPE_VIDEO, 40, Some(1)),
+                (TAG_TYPE_AUDIO, 43, Some(1)),
+                (TAG_TYPE_VIDEO, 80, Some(1)),
+            ]
+        );
+        assert!(tags[3].is_keyframe() && !tags[6].is_keyframe());
+
+        // and back
+        let mut extracted = Vec::new();
+        extract_video(&file, &tags, &mut extracted).unwrap();
+        let nalus: Vec<&[u8]> = annexb_nalus(&video)
+            .into_iter()
+            .filter(|nalu| nal_type(nalu) != NAL_AUD)
+            .collect();
+        assert_eq!(annexb_nalus(&extracted), nalus);
+        let mut extracted = Vec::new();
+        extract_audio(&file, &tags, &mut extracted).unwrap();
+        assert_eq!(extracted, audio);
+
+        // a B slice
+        video.extend_from_slice(&[0, 0, 1, 0x41, 0x9c, 5]);
+        let err = import(Some((&video, 25.0)), None).unwrap_err();
+        assert!(err.to_string().contains("B slices"));
+        assert!(import(Some((&[1, 2, 3], 25.0)), None).is_err());
+        assert!(import(Some((&[0, 0, 1, 0x67, 0x42, 0, 0x1e], 25.0)), None).is_err());
+    }
+}
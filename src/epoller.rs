@@ -1,116 +1,822 @@
-use std::collections::btree_map;
+use crate::backend::{Backend, EpollBackend, Event};
+use crate::my_error::my_error;
+use crate::remote::Remote;
+use crate::slab::{Slab, Token};
+use crate::timer::{TimerId, TimerWheel};
+use std::any::Any;
 use std::collections::BTreeMap;
+use std::collections::VecDeque;
 use std::io::Error;
+use std::io::ErrorKind;
+use std::io::IoSlice;
 use std::io::Result as IoResult;
+use std::mem;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::ptr;
+use std::time::{Duration, Instant};
 
 pub trait RWHandle: AsRawFd {
     fn on_read(&mut self, epoller: &mut Epoller) -> IoResult<()>;
     fn on_write(&mut self, epoller: &mut Epoller) -> IoResult<()>;
+    /// a timer added for this handle's fd is due,
+    /// returning an error drops the handle like on_read/on_write
+    fn on_timer(&mut self, _epoller: &mut Epoller, _timer: TimerId) -> IoResult<()> {
+        Ok(())
+    }
+    /// a message another handle sent to this one with Epoller::send,
+    /// the handle downcasts the messages it knows and ignores the rest
+    fn on_message(&mut self, _epoller: &mut Epoller, _msg: Box<dyn Any>) -> IoResult<()> {
+        Ok(())
+    }
+    /// a callback failed or the fd reported an error, on_close follows
+    fn on_error(&mut self, _epoller: &mut Epoller, err: &Error) {
+        println!("fd:{} err:{}", self.as_raw_fd(), err);
+    }
+    /// output to send when the handle asked for Epoller::send_batched
+    fn batched_output(&self) -> Vec<IoSlice<'_>> {
+        Vec::new()
+    }
+    /// how much of batched_output the socket took, or why it took nothing
+    fn on_batch_sent(&mut self, _epoller: &mut Epoller, _result: IoResult<usize>) -> IoResult<()> {
+        Ok(())
+    }
+    /// the epoller is about to drop the handle, its fd is no longer watched.
+    /// Not called for handles taken out with Epoller::remove.
+    fn on_close(&mut self, _epoller: &mut Epoller) {}
+}
+
+/// Which readiness events a registered fd is waiting for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interest {
+    Read,
+    ReadWrite,
 }
 
+impl Interest {
+    pub(crate) fn events(self) -> u32 {
+        match self {
+            Interest::Read => (libc::EPOLLIN | libc::EPOLLRDHUP) as u32,
+            Interest::ReadWrite => (libc::EPOLLIN | libc::EPOLLRDHUP | libc::EPOLLOUT) as u32,
+        }
+    }
+}
+
+/// How readiness of a registered fd is reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    /// every wait while the fd stays ready
+    Level,
+    /// once per change, the handle reads until WouldBlock
+    /// or asks for Epoller::read_again
+    Edge,
+    /// edge triggered and only one of the epollers sharing the fd is woken,
+    /// for a listener registered by several workers. Its interest can't be changed.
+    Exclusive,
+}
+
+impl Trigger {
+    pub(crate) fn events(self, interest: Interest) -> u32 {
+        match self {
+            Trigger::Level => interest.events(),
+            Trigger::Edge => interest.events() | libc::EPOLLET as u32,
+            // epoll rejects EPOLLRDHUP together with EPOLLEXCLUSIVE
+            Trigger::Exclusive => {
+                interest.events() & !(libc::EPOLLRDHUP as u32)
+                    | (libc::EPOLLET | libc::EPOLLEXCLUSIVE) as u32
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Registration {
+    token: Token,
+    interest: Interest,
+    trigger: Trigger,
+}
+
+/// events taken from the kernel by one run() unless Epoller::with_event_buffer says otherwise
+const DEFAULT_EVENT_BUFFER: usize = 100;
+
 /// Epoller is a wrapper for unix epoll
-/// It handles RWHandle which is a wrapper for system raw fd
+/// It handles RWHandle which is a wrapper for system raw fd.
+/// Each registered handle is addressed by a Token, which stays valid only
+/// as long as that handle is registered, a reused fd gets a new token.
 pub struct Epoller<'a> {
-    fd: RawFd,
-    fd_to_handle: BTreeMap<RawFd, Box<dyn RWHandle + 'a>>,
+    backend: Box<dyn Backend>,
+    handles: Slab<Box<dyn RWHandle + 'a>>,
+    registrations: BTreeMap<RawFd, Registration>,
+    events: Vec<Event>,
+    // edge triggered handles that stopped reading before WouldBlock
+    read_again: Vec<Token>,
+    // handles with output for the next send batch
+    batch: Vec<Token>,
+    timers: TimerWheel,
+    // timers due in this round but not delivered yet
+    firing: VecDeque<(TimerId, RawFd)>,
+    // messages between handles, delivered after the current callbacks
+    messages: VecDeque<(Token, Box<dyn Any>)>,
+    // handle inside a callback, its value is taken out of handles meanwhile
+    dispatching: Option<Token>,
+    // the dispatching handle was closed by its callback, drop it on return
+    close_dispatching: bool,
+    // handles whose output the kernel may still read, kept alive for good
+    parked: Vec<Box<dyn RWHandle + 'a>>,
+    remote: Option<Remote>,
 }
 
 impl<'a> Epoller<'a> {
     pub fn create() -> IoResult<Self> {
-        let res = unsafe { libc::epoll_create1(0) };
-        match res {
-            -1 => Err(Error::last_os_error()),
-            _ => Ok(Self {
-                fd: res,
-                fd_to_handle: BTreeMap::new(),
-            }),
+        Self::with_event_buffer(DEFAULT_EVENT_BUFFER)
+    }
+
+    /// epoll based epoller taking up to size events from the kernel per run()
+    pub fn with_event_buffer(size: usize) -> IoResult<Self> {
+        Ok(Self::with_backend(Box::new(EpollBackend::create(size)?)))
+    }
+
+    /// epoller on another kernel interface, e.g. io_uring
+    pub fn with_backend(backend: Box<dyn Backend>) -> Self {
+        Self {
+            backend,
+            handles: Slab::new(),
+            registrations: BTreeMap::new(),
+            events: Vec::new(),
+            read_again: Vec::new(),
+            batch: Vec::new(),
+            timers: TimerWheel::new(Instant::now()),
+            firing: VecDeque::new(),
+            messages: VecDeque::new(),
+            dispatching: None,
+            close_dispatching: false,
+            parked: Vec::new(),
+            remote: None,
         }
     }
 
-    pub fn wait_read<T: RWHandle + 'a>(&mut self, handle: T) -> Result<(), (T, Error)> {
+    pub fn backend_name(&self) -> &'static str {
+        self.backend.name()
+    }
+
+    /// Register handle for level triggered read events and return its token.
+    /// When its fd is already registered the old handle is kept, only its interest
+    /// goes back to read.
+    pub fn wait_read<T: RWHandle + 'a>(&mut self, handle: T) -> Result<Token, (T, Error)> {
+        self.wait_read_with(handle, Trigger::Level)
+    }
+
+    /// wait_read with the given trigger mode
+    pub fn wait_read_with<T: RWHandle + 'a>(
+        &mut self,
+        handle: T,
+        trigger: Trigger,
+    ) -> Result<Token, (T, Error)> {
         let raw_fd = handle.as_raw_fd();
-        let mut read_event = libc::epoll_event {
-            events: libc::EPOLLIN as u32,
-            u64: raw_fd as u64,
+        if let Some(token) = self.token_of(raw_fd) {
+            if let Err(err) = self.backend.modify(raw_fd, token, Interest::Read, trigger) {
+                return Err((handle, err));
+            }
+            let registration = Registration {
+                token,
+                interest: Interest::Read,
+                trigger,
+            };
+            self.registrations.insert(raw_fd, registration);
+            return Ok(token);
+        }
+
+        let token = self.handles.next_token();
+        if let Err(err) = self
+            .backend
+            .register(raw_fd, token, Interest::Read, trigger)
+        {
+            return Err((handle, err));
+        }
+        self.handles.insert(Box::new(handle));
+        let registration = Registration {
+            token,
+            interest: Interest::Read,
+            trigger,
         };
-        let event_ptr = &mut read_event as *mut libc::epoll_event;
-
-        match self.fd_to_handle.entry(raw_fd) {
-            btree_map::Entry::Occupied(_) => {
-                let res =
-                    unsafe { libc::epoll_ctl(self.fd, libc::EPOLL_CTL_MOD, raw_fd, event_ptr) };
-                match res {
-                    -1 => Err((handle, Error::last_os_error())),
-                    _ => Ok(()),
-                }
+        self.registrations.insert(raw_fd, registration);
+        Ok(token)
+    }
+
+    /// number of registered handles
+    pub fn handle_count(&self) -> usize {
+        self.registrations.len()
+    }
+
+    /// tokens of all registered handles, e.g. to message every one of them
+    pub fn tokens(&self) -> Vec<Token> {
+        self.registrations
+            .values()
+            .map(|registration| registration.token)
+            .collect()
+    }
+
+    /// token of the handle registered for raw_fd
+    pub fn token_of(&self, raw_fd: RawFd) -> Option<Token> {
+        self.registrations
+            .get(&raw_fd)
+            .map(|registration| registration.token)
+    }
+
+    /// Change the events an already registered fd waits for.
+    /// Handlers use this on their own fd while inside on_read/on_write,
+    /// e.g. to wait for writable once output is pending.
+    /// The trigger mode stays as registered.
+    pub fn set_interest(&mut self, raw_fd: RawFd, interest: Interest) -> IoResult<()> {
+        let registration = self
+            .registrations
+            .get_mut(&raw_fd)
+            .ok_or_else(|| my_error(format!("fd:{} is not registered", raw_fd)))?;
+        self.backend
+            .modify(raw_fd, registration.token, interest, registration.trigger)?;
+        registration.interest = interest;
+        Ok(())
+    }
+
+    /// Send the batched_output of the handle of raw_fd together with the other
+    /// handles asking in this round, the backend may submit them all at once.
+    /// on_batch_sent tells the handle how much went out.
+    pub fn send_batched(&mut self, raw_fd: RawFd) {
+        if let Some(token) = self.token_of(raw_fd) {
+            if !self.batch.contains(&token) {
+                self.batch.push(token);
             }
-            btree_map::Entry::Vacant(entry) => {
-                let res =
-                    unsafe { libc::epoll_ctl(self.fd, libc::EPOLL_CTL_ADD, raw_fd, event_ptr) };
-                match res {
-                    -1 => Err((handle, Error::last_os_error())),
-                    _ => {
-                        entry.insert(Box::new(handle));
-                        Ok(())
-                    }
+        }
+    }
+
+    /// Call on_read of the edge triggered handle of raw_fd again in the next run,
+    /// for a handle that stopped reading before WouldBlock to let others go first.
+    /// Level triggered fds are reported again anyway, nothing is done for them.
+    pub fn read_again(&mut self, raw_fd: RawFd) {
+        if let Some(registration) = self.registrations.get(&raw_fd) {
+            if registration.trigger != Trigger::Level
+                && !self.read_again.contains(&registration.token)
+            {
+                self.read_again.push(registration.token);
+            }
+        }
+    }
+
+    /// Queue msg for the on_message of the handle with token.
+    /// Delivery happens after the running callback returns, so a handle may
+    /// message any other handle, or itself. false when token is no longer registered.
+    pub fn send(&mut self, token: Token, msg: Box<dyn Any>) -> bool {
+        if !self.handles.contains(token) {
+            return false;
+        }
+        self.messages.push_back((token, msg));
+        true
+    }
+
+    /// A handle for other threads to wake this epoller and run tasks on its thread.
+    /// The eventfd behind it is registered on first use.
+    pub fn remote(&mut self) -> IoResult<Remote> {
+        if let Some(remote) = &self.remote {
+            return Ok(remote.clone());
+        }
+        let remote = Remote::register(self)?;
+        self.remote = Some(remote.clone());
+        Ok(remote)
+    }
+
+    /// call on_timer of the handle registered for raw_fd once after delay
+    pub fn add_timer(&mut self, raw_fd: RawFd, delay: Duration) -> TimerId {
+        self.timers.add(Instant::now(), raw_fd, delay, None)
+    }
+
+    /// call on_timer of the handle registered for raw_fd every period
+    pub fn add_periodic_timer(&mut self, raw_fd: RawFd, period: Duration) -> TimerId {
+        self.timers
+            .add(Instant::now(), raw_fd, period, Some(period))
+    }
+
+    /// false when the timer was already delivered or cancelled
+    pub fn cancel_timer(&mut self, timer: TimerId) -> bool {
+        if let Some(pos) = self.firing.iter().position(|(id, _)| *id == timer) {
+            self.firing.remove(pos);
+            return true;
+        }
+        self.timers.cancel(timer)
+    }
+
+    /// the wait timeout shortened to the next timer
+    fn wait_timeout(&self, timeout: i32) -> i32 {
+        // messages sent from outside a callback are delivered without waiting,
+        // handles with input left should not wait either
+        if !self.messages.is_empty() || !self.read_again.is_empty() || !self.batch.is_empty() {
+            return 0;
+        }
+        match self.timers.next_timeout(Instant::now()) {
+            Some(next) => {
+                // round up, waking before the tick is due would just spin
+                let next_ms = next.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32;
+                if timeout < 0 {
+                    next_ms
+                } else {
+                    timeout.min(next_ms)
                 }
             }
+            None => timeout,
         }
     }
 
-    pub fn run(&mut self, timeout: i32) -> IoResult<()> {
-        let mut event_buffer: [libc::epoll_event; 100] =
-            [libc::epoll_event { events: 0, u64: 0 }; 100];
-        let ready_cnt = unsafe {
-            libc::epoll_wait(
-                self.fd,
-                &mut event_buffer as *mut libc::epoll_event,
-                event_buffer.len() as i32,
-                timeout as libc::c_int,
-            ) as i32
+    /// Stop watching raw_fd and hand its handle back, its timers are cancelled.
+    /// No callback is made, dropping the handle closes the fd.
+    /// When raw_fd is the handle whose callback is running, None is returned
+    /// and the handle gets on_close and is dropped as soon as the callback returns.
+    pub fn remove(&mut self, raw_fd: RawFd) -> Option<Box<dyn RWHandle + 'a>> {
+        let token = self.token_of(raw_fd)?;
+        if self.dispatching == Some(token) {
+            self.close_dispatching = true;
+            self.deregister(raw_fd, token);
+            return None;
+        }
+        self.deregister(raw_fd, token)
+    }
+
+    /// Remove the handle of raw_fd, call its on_close and drop it.
+    /// Safe to call from any callback, including the handle's own.
+    pub fn close(&mut self, raw_fd: RawFd) {
+        if let Some(mut boxed_handle) = self.remove(raw_fd) {
+            boxed_handle.on_close(self);
+        }
+    }
+
+    /// Forget the handle of raw_fd and stop watching the fd, its timers and messages go too.
+    /// The handle is returned unless it is taken out for its callback.
+    /// The fd may already be gone from the backend, e.g. closed by the handle, so failure is only logged.
+    fn deregister(&mut self, raw_fd: RawFd, token: Token) -> Option<Box<dyn RWHandle + 'a>> {
+        self.timers.cancel_fd(raw_fd);
+        self.firing.retain(|(_, fd)| *fd != raw_fd);
+        self.messages.retain(|(to, _)| *to != token);
+        self.registrations.remove(&raw_fd);
+        let boxed_handle = self.handles.remove(token);
+        if let Err(err) = self.backend.deregister(raw_fd) {
+            println!("remove fd:{} failed with {}", raw_fd, err);
+        }
+        boxed_handle
+    }
+
+    /// Run one callback of the handle with token.
+    /// An error from the callback closes the handle with on_error and on_close.
+    /// Returns whether the handle is still registered afterwards.
+    fn dispatch<F>(&mut self, token: Token, callback: F) -> bool
+    where
+        F: FnOnce(&mut (dyn RWHandle + 'a), &mut Self) -> IoResult<()>,
+    {
+        let mut boxed_handle = match self.handles.take(token) {
+            Some(boxed_handle) => boxed_handle,
+            None => return false,
         };
+        let raw_fd = boxed_handle.as_raw_fd();
+        self.dispatching = Some(token);
+        let result = callback(&mut *boxed_handle, self);
+        self.dispatching = None;
+        let closed = mem::replace(&mut self.close_dispatching, false);
 
-        for i in 0..ready_cnt as usize {
-            let raw_fd = event_buffer[i].u64 as RawFd;
-            if let Some(mut boxed_handle) = self.fd_to_handle.remove(&raw_fd) {
-                if (event_buffer[i].events & libc::EPOLLIN as u32) != 0 {
-                    if let Err(err) = (*boxed_handle).on_read(self) {
-                        println!("fd:{} read err:{}", raw_fd, err);
-                        let res = unsafe {
-                            libc::epoll_ctl(self.fd, libc::EPOLL_CTL_DEL, raw_fd, ptr::null_mut())
-                        };
-                        assert_ne!(
-                            res,
-                            -1,
-                            "remove fd:{} failed with {}",
-                            raw_fd,
-                            Error::last_os_error()
-                        );
-                        continue;
-                    }
+        match result {
+            Err(err) => {
+                if !closed {
+                    self.deregister(raw_fd, token);
                 }
-                if (event_buffer[i].events & libc::EPOLLOUT as u32) != 0 {
-                    if let Err(err) = (*boxed_handle).on_write(self) {
-                        println!("fd:{} write err:{}", raw_fd, err);
-                        let res = unsafe {
-                            libc::epoll_ctl(self.fd, libc::EPOLL_CTL_DEL, raw_fd, ptr::null_mut())
-                        };
-                        assert_ne!(
-                            res,
-                            -1,
-                            "remove fd:{} failed with {}",
-                            raw_fd,
-                            Error::last_os_error()
-                        );
-                        continue;
-                    }
+                boxed_handle.on_error(self, &err);
+                boxed_handle.on_close(self);
+                false
+            }
+            Ok(()) if closed => {
+                boxed_handle.on_close(self);
+                false
+            }
+            Ok(()) => {
+                self.handles.restore(token, boxed_handle);
+                true
+            }
+        }
+    }
+
+    /// EPOLLERR on raw_fd, report the pending socket error and close the handle
+    fn fail(&mut self, raw_fd: RawFd) {
+        if let Some(mut boxed_handle) = self.remove(raw_fd) {
+            boxed_handle.on_error(self, &socket_error(raw_fd));
+            boxed_handle.on_close(self);
+        }
+    }
+
+    fn run_timers(&mut self) {
+        self.firing.extend(self.timers.expire(Instant::now()));
+        while let Some((timer, raw_fd)) = self.firing.pop_front() {
+            if let Some(token) = self.token_of(raw_fd) {
+                self.dispatch(token, |handle, epoller| handle.on_timer(epoller, timer));
+            }
+        }
+    }
+
+    fn run_messages(&mut self) {
+        while let Some((token, msg)) = self.messages.pop_front() {
+            self.dispatch(token, |handle, epoller| handle.on_message(epoller, msg));
+        }
+    }
+
+    fn run_batch(&mut self) -> IoResult<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let mut tokens = Vec::new();
+        let mut outputs = Vec::new();
+        for token in mem::take(&mut self.batch) {
+            if let Some(boxed_handle) = self.handles.get(token) {
+                let output = boxed_handle.batched_output();
+                if output.iter().any(|slice| !slice.is_empty()) {
+                    tokens.push(token);
+                    outputs.push((boxed_handle.as_raw_fd(), output));
                 }
-                self.fd_to_handle.insert(raw_fd, boxed_handle);
             }
         }
+        let sends: Vec<(RawFd, &[IoSlice])> = outputs
+            .iter()
+            .map(|(raw_fd, output)| (*raw_fd, &output[..]))
+            .collect();
+        let results = self.backend.send_batch(&sends);
+        drop(sends);
+        if results.iter().any(in_flight) {
+            // the kernel may still read the iovec lists
+            mem::forget(outputs);
+        } else {
+            drop(outputs);
+        }
+        // park the handles of sends the backend gave up on before any
+        // callback can drop them, their buffers must never be freed
+        let mut sent = Vec::new();
+        for (token, result) in tokens.into_iter().zip(results) {
+            if in_flight(&result) {
+                self.park(token);
+            } else {
+                sent.push((token, result));
+            }
+        }
+        self.fall_back()?;
+        for (token, result) in sent {
+            self.dispatch(token, |handle, epoller| {
+                handle.on_batch_sent(epoller, result)
+            });
+        }
+        Ok(())
+    }
+
+    /// take the handle with token out for good, without on_close
+    fn park(&mut self, token: Token) {
+        let raw_fd = match self.handles.get(token) {
+            Some(boxed_handle) => boxed_handle.as_raw_fd(),
+            None => return,
+        };
+        println!("fd:{} send still in flight, park its handle", raw_fd);
+        if let Some(boxed_handle) = self.deregister(raw_fd, token) {
+            self.parked.push(boxed_handle);
+        }
+    }
 
+    /// move every registration to the backend's replacement once it failed
+    fn fall_back(&mut self) -> IoResult<()> {
+        let mut backend = match self.backend.fallback() {
+            Some(backend) => backend?,
+            None => return Ok(()),
+        };
+        for (raw_fd, registration) in &self.registrations {
+            backend.register(
+                *raw_fd,
+                registration.token,
+                registration.interest,
+                registration.trigger,
+            )?;
+        }
+        self.backend = backend;
         Ok(())
     }
+
+    /// wait up to timeout ms (-1 forever) for events, or until the next timer is due,
+    /// then dispatch events and due timers
+    pub fn run(&mut self, timeout: i32) -> IoResult<()> {
+        let mut events = mem::take(&mut self.events);
+        events.clear();
+        if let Err(err) = self.backend.poll(&mut events, self.wait_timeout(timeout)) {
+            self.events = events;
+            if err.kind() == ErrorKind::Interrupted {
+                self.run_timers();
+                self.run_messages();
+                return Ok(());
+            }
+            return Err(err);
+        }
+
+        // handles asking now are served in the next round
+        let read_again = mem::take(&mut self.read_again);
+
+        for event in &events {
+            let token = event.token;
+            let events = event.events;
+            // the handle may have been removed by an earlier callback of this round
+            let raw_fd = match self.handles.get(token) {
+                Some(boxed_handle) => boxed_handle.as_raw_fd(),
+                None => continue,
+            };
+            if events & libc::EPOLLERR as u32 != 0 {
+                self.fail(raw_fd);
+                continue;
+            }
+            // a half closed peer is seen as EOF by on_read,
+            // after a full hangup it is also given the chance to drain what is left
+            let readable = (libc::EPOLLIN | libc::EPOLLRDHUP | libc::EPOLLHUP) as u32;
+            if events & readable != 0
+                && !self.dispatch(token, |handle, epoller| handle.on_read(epoller))
+            {
+                continue;
+            }
+            if events & libc::EPOLLHUP as u32 != 0 {
+                // nothing can be written any more
+                self.close(raw_fd);
+                continue;
+            }
+            if events & libc::EPOLLOUT as u32 != 0 {
+                self.dispatch(token, |handle, epoller| handle.on_write(epoller));
+            }
+        }
+        self.events = events;
+        for token in read_again {
+            self.dispatch(token, |handle, epoller| handle.on_read(epoller));
+        }
+
+        self.run_messages();
+        self.run_timers();
+        self.run_messages();
+        self.run_batch()
+    }
+}
+
+/// a send the backend gave up waiting for, see Backend::send_batch
+fn in_flight(result: &IoResult<usize>) -> bool {
+    matches!(result, Err(err) if err.raw_os_error() == Some(libc::EINPROGRESS))
+}
+
+/// the pending error of a socket that reported EPOLLERR
+fn socket_error(raw_fd: RawFd) -> Error {
+    let mut err: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            raw_fd,
+            libc::SOL_SOCKET,
+            libc::SO_ERROR,
+            &mut err as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res == -1 || err == 0 {
+        my_error(format!("fd:{} error event", raw_fd))
+    } else {
+        Error::from_raw_os_error(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::rc::Rc;
+
+    struct PairEnd {
+        stream: UnixStream,
+        closed: Rc<Cell<u32>>,
+    }
+
+    impl AsRawFd for PairEnd {
+        fn as_raw_fd(&self) -> RawFd {
+            self.stream.as_raw_fd()
+        }
+    }
+
+    impl RWHandle for PairEnd {
+        fn on_read(&mut self, _epoller: &mut Epoller) -> IoResult<()> {
+            let mut buf = [0; 16];
+            match self.stream.read(&mut buf)? {
+                0 => Err(my_error("EOF")),
+                _ => Ok(()),
+            }
+        }
+        fn on_write(&mut self, _epoller: &mut Epoller) -> IoResult<()> {
+            Ok(())
+        }
+        fn on_close(&mut self, _epoller: &mut Epoller) {
+            self.closed.set(self.closed.get() + 1);
+        }
+    }
+
+    #[test]
+    fn test_remove_and_hangup() {
+        let closed = Rc::new(Cell::new(0));
+        let mut epoller = Epoller::create().unwrap();
+
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let fd = ours.as_raw_fd();
+        let handle = PairEnd {
+            stream: ours,
+            closed: closed.clone(),
+        };
+        assert!(epoller.wait_read(handle).is_ok());
+        let removed = epoller.remove(fd);
+        assert!(removed.is_some());
+        assert!(epoller.remove(fd).is_none());
+        drop(removed);
+        drop(theirs);
+        assert_eq!(closed.get(), 0);
+
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let handle = PairEnd {
+            stream: ours,
+            closed: closed.clone(),
+        };
+        assert!(epoller.wait_read(handle).is_ok());
+        // the peer going away closes the handle through on_read's EOF
+        drop(theirs);
+        epoller.run(1000).unwrap();
+        assert_eq!(closed.get(), 1);
+        assert!(epoller.registrations.is_empty());
+    }
+
+    /// takes one byte per wakeup
+    struct Trickle {
+        stream: UnixStream,
+        read: Rc<Cell<u32>>,
+    }
+
+    impl AsRawFd for Trickle {
+        fn as_raw_fd(&self) -> RawFd {
+            self.stream.as_raw_fd()
+        }
+    }
+
+    impl RWHandle for Trickle {
+        fn on_read(&mut self, epoller: &mut Epoller) -> IoResult<()> {
+            let mut buf = [0; 1];
+            match self.stream.read(&mut buf) {
+                Ok(0) => Err(my_error("EOF")),
+                Ok(_) => {
+                    self.read.set(self.read.get() + 1);
+                    epoller.read_again(self.as_raw_fd());
+                    Ok(())
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(()),
+                Err(err) => Err(err),
+            }
+        }
+        fn on_write(&mut self, _epoller: &mut Epoller) -> IoResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_edge_read_again() {
+        let read = Rc::new(Cell::new(0));
+        let mut epoller = Epoller::with_event_buffer(4).unwrap();
+
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        ours.set_nonblocking(true).unwrap();
+        let handle = Trickle {
+            stream: ours,
+            read: read.clone(),
+        };
+        assert!(epoller.wait_read_with(handle, Trigger::Edge).is_ok());
+        theirs.write_all(b"abc").unwrap();
+
+        // one edge for all three bytes, the rest is read on request
+        epoller.run(1000).unwrap();
+        assert_eq!(read.get(), 1);
+        epoller.run(0).unwrap();
+        epoller.run(0).unwrap();
+        assert_eq!(read.get(), 3);
+        epoller.run(0).unwrap();
+        epoller.run(0).unwrap();
+        assert_eq!(read.get(), 3);
+        assert!(epoller.read_again.is_empty());
+    }
+
+    /// epoll whose sends never complete, like a ring that can't be entered
+    struct StuckBackend {
+        epoll: EpollBackend,
+        failed: bool,
+    }
+
+    impl Backend for StuckBackend {
+        fn name(&self) -> &'static str {
+            "stuck"
+        }
+        fn register(
+            &mut self,
+            raw_fd: RawFd,
+            token: Token,
+            interest: Interest,
+            trigger: Trigger,
+        ) -> IoResult<()> {
+            self.epoll.register(raw_fd, token, interest, trigger)
+        }
+        fn modify(
+            &mut self,
+            raw_fd: RawFd,
+            token: Token,
+            interest: Interest,
+            trigger: Trigger,
+        ) -> IoResult<()> {
+            self.epoll.modify(raw_fd, token, interest, trigger)
+        }
+        fn deregister(&mut self, raw_fd: RawFd) -> IoResult<()> {
+            self.epoll.deregister(raw_fd)
+        }
+        fn poll(&mut self, events: &mut Vec<Event>, timeout: i32) -> IoResult<()> {
+            self.epoll.poll(events, timeout)
+        }
+        fn send_batch(&mut self, sends: &[(RawFd, &[IoSlice])]) -> Vec<IoResult<usize>> {
+            self.failed = true;
+            sends
+                .iter()
+                .map(|_| Err(Error::from_raw_os_error(libc::EINPROGRESS)))
+                .collect()
+        }
+        fn fallback(&mut self) -> Option<IoResult<Box<dyn Backend>>> {
+            if !self.failed {
+                return None;
+            }
+            let backend = EpollBackend::create(4);
+            Some(backend.map(|backend| Box::new(backend) as Box<dyn Backend>))
+        }
+    }
+
+    struct Batched {
+        end: PairEnd,
+        output: Vec<u8>,
+    }
+
+    impl AsRawFd for Batched {
+        fn as_raw_fd(&self) -> RawFd {
+            self.end.as_raw_fd()
+        }
+    }
+
+    impl RWHandle for Batched {
+        fn on_read(&mut self, epoller: &mut Epoller) -> IoResult<()> {
+            self.end.on_read(epoller)
+        }
+        fn on_write(&mut self, _epoller: &mut Epoller) -> IoResult<()> {
+            Ok(())
+        }
+        fn batched_output(&self) -> Vec<IoSlice<'_>> {
+            vec![IoSlice::new(&self.output)]
+        }
+        fn on_close(&mut self, epoller: &mut Epoller) {
+            self.end.on_close(epoller)
+        }
+    }
+
+    #[test]
+    fn test_fall_back_after_stuck_send() {
+        let closed = Rc::new(Cell::new(0));
+        let stuck = StuckBackend {
+            epoll: EpollBackend::create(4).unwrap(),
+            failed: false,
+        };
+        let mut epoller = Epoller::with_backend(Box::new(stuck));
+
+        let (ours, _theirs) = UnixStream::pair().unwrap();
+        let sender_fd = ours.as_raw_fd();
+        let sender = Batched {
+            end: PairEnd {
+                stream: ours,
+                closed: closed.clone(),
+            },
+            output: b"in flight".to_vec(),
+        };
+        assert!(epoller.wait_read(sender).is_ok());
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let reader = PairEnd {
+            stream: ours,
+            closed: closed.clone(),
+        };
+        assert!(epoller.wait_read(reader).is_ok());
+
+        epoller.send_batched(sender_fd);
+        epoller.run(0).unwrap();
+        // the sender is kept alive without on_close, the rest moved to epoll
+        assert_eq!(closed.get(), 0);
+        assert_eq!(epoller.handle_count(), 1);
+        assert_eq!(epoller.parked.len(), 1);
+        assert_eq!(epoller.backend_name(), "epoll");
+
+        drop(theirs);
+        epoller.run(1000).unwrap();
+        assert_eq!(closed.get(), 1);
+        assert_eq!(epoller.handle_count(), 0);
+    }
 }
@@ -0,0 +1,1485 @@
+use crate::flv_demuxer::{
+    FlvHeader, RawTag, SOUND_FORMAT_AAC, SOUND_FORMAT_MP3, VIDEO_CODEC_AVC,
+};
+use crate::my_error::my_error;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+
+pub const FLV_HEADER_LEN: usize = 9;
+pub const TAG_HEADER_LEN: usize = 11;
+const TAG_HEADER_DATA_SIZE_LEN: usize = 3;
+const TAG_HEADER_TIMESTAMP_LEN: usize = 4;
+const TAG_HEADER_STREAM_ID_LEN: usize = 4;
+pub const PRE_TAG_SIZE_LEN: usize = 4;
+const AVC_PACKET_COMPOSITION_TIME_LEN: usize = 3;
+
+struct TagHeader {
+    data_size: usize,
+    timestamp: i32,
+}
+
+impl fmt::Display for TagHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[header]:data_size:{}, timestamp:{}",
+            self.data_size, self.timestamp
+        )
+    }
+}
+
+impl TagHeader {
+    fn parse(mut data: &[u8]) -> Result<(&[u8], Self)> {
+        if data.len() < TAG_HEADER_DATA_SIZE_LEN {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "tag header data size parse failed:not enouth data",
+            ));
+        }
+
+        let data_size =
+            ((data[0] as usize) << 16) | ((data[1] as usize) << 8) | ((data[2] as usize) << 0);
+
+        // 前进
+        data = &data[TAG_HEADER_DATA_SIZE_LEN..];
+
+        if data.len() < TAG_HEADER_TIMESTAMP_LEN {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "tag header timestamp parse failed:not enouth data",
+            ));
+        }
+
+        let timestamp = ((data[3] as i32) << 24)
+            | ((data[0] as i32) << 16)
+            | ((data[1] as i32) << 8)
+            | ((data[2] as i32) << 0);
+
+        data = &data[TAG_HEADER_TIMESTAMP_LEN..];
+
+        if data.len() < TAG_HEADER_STREAM_ID_LEN {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "tag header streamid parse failed:not enough data",
+            ));
+        }
+
+        if &data[0..3] != [0, 0, 0] {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "tag header streamid parse failed:not 0",
+            ));
+        }
+        data = &data[3..];
+
+        return Ok((
+            data,
+            Self {
+                data_size: data_size,
+                timestamp: timestamp,
+            },
+        ));
+    }
+}
+#[allow(dead_code)]
+#[derive(Debug)]
+enum VideoFrameType {
+    KeyFrame,
+    InterFrame,
+    DisposableInterFrame,
+    GeneratedKeyFrame,
+    InfoOrCommandFrame,
+}
+
+impl fmt::Display for VideoFrameType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VideoFrameType::KeyFrame => write!(f, "[FrameType]:I frame"),
+            VideoFrameType::InterFrame => write!(f, "[FrameType]:B/P frame"),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl VideoFrameType {
+    fn parse(data: &[u8]) -> Result<(&[u8], Self)> {
+        if data.len() == 0 {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "video tag frame type parse failed. reason: not enough data.",
+            ));
+        }
+
+        match (data[0] & 0xf0) >> 4 {
+            1 => Ok((data, Self::KeyFrame)),
+            2 => Ok((data, Self::InterFrame)),
+            _ => Err(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "video tag frame type parse failed. reason: invalid value {}.",
+                    data[0]
+                ),
+            )),
+        }
+    }
+}
+
+struct AVCNALUData {
+    composition_time: u32,
+    nalu_data: Vec<u8>,
+}
+
+impl AVCNALUData {
+    fn parse(data: &[u8]) -> Result<(&[u8], Self)> {
+        if data.len() < AVC_PACKET_COMPOSITION_TIME_LEN {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "avc packet parse cts failed: not enough data.",
+            ));
+        }
+
+        let composition_time =
+            ((data[0] as u32) << 16) | ((data[1] as u32) << 8) | ((data[2] as u32) << 0);
+
+        let nalu_data = (&data[AVC_PACKET_COMPOSITION_TIME_LEN..]).to_vec();
+
+        Ok((
+            &data[data.len()..],
+            Self {
+                composition_time: composition_time,
+                nalu_data: nalu_data,
+            },
+        ))
+    }
+}
+
+impl fmt::Display for AVCNALUData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            " cts:{} nalu size:{}",
+            self.composition_time,
+            self.nalu_data.len()
+        )
+    }
+}
+
+enum AVCPacketData {
+    AVCHeader(Vec<u8>),
+    AVCNALU(AVCNALUData),
+    AVCEndOfSequence,
+}
+
+impl AVCPacketData {
+    fn parse(mut data: &[u8]) -> Result<(&[u8], Self)> {
+        if data.is_empty() {
+            return Err(my_error(
+                "avc packet data parsed failed: packet type not enough data",
+            ));
+        }
+        let avc_packet_type = data[0];
+        data = &data[1..];
+        match avc_packet_type {
+            0 => {
+                if data.len() < 3 {
+                    return Err(my_error(
+                        "avc packet header parsed failed: composition time not enough data",
+                    ));
+                }
+                if data[0..3] != [0, 0, 0] {
+                    return Err(my_error(
+                        "avc packet header parsed failed: composition time not 0",
+                    ));
+                }
+                data = &data[3..];
+
+                Ok((&data[data.len()..], Self::AVCHeader(Vec::from(data))))
+            }
+
+            1 => AVCNALUData::parse(data)
+                .and_then(|(rest_data, nalu_data)| Ok((rest_data, Self::AVCNALU(nalu_data))))
+                .or_else(|err| {
+                    Err(my_error(format!(
+                        "avc packet naul data parsed failed:{}",
+                        err
+                    )))
+                }),
+            2 => Ok((data, Self::AVCEndOfSequence)),
+            _ => Err(Error::new(
+                ErrorKind::Other,
+                format!(
+                    " avc packet data parsed failed. reason: invaild avc packet type{}",
+                    avc_packet_type
+                ),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for AVCPacketData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AVCPacketData::AVCHeader(data) => {
+                write!(f, "[avc header]:")?;
+                Ok(for byte in data {
+                    write!(f, "{:02x} ", byte)?;
+                })
+            }
+            AVCPacketData::AVCNALU(nal_data) => write!(f, "[avc nalu data]:{}", nal_data),
+            AVCPacketData::AVCEndOfSequence => write!(f, "avc end of seq"),
+        }
+    }
+}
+
+#[allow(dead_code)]
+enum VideoPacket {
+    H263,
+    Screen,
+    VP6,
+    VP6Alpha,
+    ScreenV2,
+    AVC(AVCPacketData),
+}
+
+impl VideoPacket {
+    fn parse(data: &[u8]) -> Result<(&[u8], Self)> {
+        match data[0] & 0x0f {
+            7 => AVCPacketData::parse(&data[1..])
+                .and_then(|(rest_data, packet_data)| Ok((rest_data, Self::AVC(packet_data)))),
+            _ => Err(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "video packet parsed failed. reason: codecid {} not supported.",
+                    data[0] & 0x0f
+                ),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for VideoPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VideoPacket::AVC(data) => write!(f, "{}", data),
+            _ => write!(f, "unsupported codec type"),
+        }
+    }
+}
+
+struct VideoTag {
+    header: TagHeader,
+    frame_type: VideoFrameType,
+    packet_data: VideoPacket,
+}
+
+impl VideoTag {
+    fn len(&self) -> usize {
+        return TAG_HEADER_LEN + self.header.data_size;
+    }
+
+    fn parse(mut data: &[u8]) -> Result<(&[u8], Self)> {
+        let (rest_data, header) = TagHeader::parse(data)
+            .or_else(|err| Err(my_error(format!("video tag header parse failed:{}", err))))?;
+
+        if rest_data.len() < header.data_size {
+            return Err(my_error(format!(
+                "script tag body parse failed: not enough data {}/{}",
+                rest_data.len(),
+                header.data_size
+            )));
+        }
+        data = &rest_data[0..header.data_size];
+        let return_data = &rest_data[header.data_size..];
+
+        let (rest_data, frame_type) = VideoFrameType::parse(data).or_else(|err| {
+            Err(my_error(format!(
+                "video tag frame type parse failed:{}",
+                err
+            )))
+        })?;
+        data = rest_data;
+
+        let (_rest_data, packet_data) = VideoPacket::parse(data)
+            .or_else(|err| Err(my_error(format!("video tag packet parse failed:{}", err))))?;
+
+        Ok((
+            return_data,
+            Self {
+                header: header,
+                frame_type: frame_type,
+                packet_data: packet_data,
+            },
+        ))
+    }
+}
+
+impl fmt::Display for VideoTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[VideoTag]:{}|{}|{}",
+            self.header, self.frame_type, self.packet_data
+        )
+    }
+}
+
+enum SoundFormatType {
+    MP3,
+    AAC,
+}
+impl fmt::Display for SoundFormatType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MP3 => write!(f, "[format]:mp3"),
+            Self::AAC => write!(f, "[format]:aac"),
+        }
+    }
+}
+enum SoundSampleRate {
+    Rate5500,
+    Rate11k,
+    Rate22k,
+    Rate44k,
+}
+impl fmt::Display for SoundSampleRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rate5500 => write!(f, "[rate]:5.5k"),
+            Self::Rate11k => write!(f, "[rate]:11k"),
+            Self::Rate22k => write!(f, "[rate]:22k"),
+            Self::Rate44k => write!(f, "[rate]:44k"),
+        }
+    }
+}
+enum SoundSampleSize {
+    Size8Bit,
+    Size16Bit,
+}
+
+impl fmt::Display for SoundSampleSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Size8Bit => write!(f, "[sample size]:8bit"),
+            Self::Size16Bit => write!(f, "[sample size]:16bit"),
+        }
+    }
+}
+
+enum SoundType {
+    TypeMono,
+    TypeStero,
+}
+
+impl fmt::Display for SoundType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TypeMono => write!(f, "[type]:mono"),
+            Self::TypeStero => write!(f, "[type]:stero"),
+        }
+    }
+}
+
+struct AudioTag {
+    header: TagHeader,
+    sound_format: SoundFormatType,
+    sound_rate: SoundSampleRate,
+    sound_size: SoundSampleSize,
+    sound_type: SoundType,
+    sound_data: Vec<u8>,
+}
+
+impl AudioTag {
+    fn len(&self) -> usize {
+        TAG_HEADER_LEN + self.header.data_size
+    }
+
+    fn parse(mut data: &[u8]) -> Result<(&[u8], Self)> {
+        let (rest_data, header) = TagHeader::parse(data)
+            .or_else(|err| Err(my_error(format!("audio tag header parse failed:{}", err))))?;
+
+        if rest_data.len() < header.data_size {
+            return Err(my_error(format!(
+                "audio tag body parse failed: not enough data {}/{}",
+                rest_data.len(),
+                header.data_size
+            )));
+        }
+        data = &rest_data[0..header.data_size];
+        let return_data = &rest_data[header.data_size..];
+
+        if data.len() < 1 {
+            return Err(my_error("audio tag format parse failed: not enough data"));
+        }
+
+        let sound_format = match (data[0] & 0b11110000) >> 4 {
+            2 => SoundFormatType::MP3,
+            10 => SoundFormatType::AAC,
+            _ => {
+                return Err(my_error(format!(
+                    "audio tag sound format parse failed: unsupported type {}",
+                    data[0]
+                )))
+            }
+        };
+
+        let sound_rate = match (data[0] & 0b00001100) >> 2 {
+            0 => SoundSampleRate::Rate5500,
+            1 => SoundSampleRate::Rate11k,
+            2 => SoundSampleRate::Rate22k,
+            3 => SoundSampleRate::Rate44k,
+            _ => {
+                return Err(my_error(format!(
+                    "audio tag sound rate failed: unsupported type {}",
+                    data[0]
+                )))
+            }
+        };
+
+        let sound_size = match (data[0] & 0b00000010) >> 1 {
+            0 => SoundSampleSize::Size8Bit,
+            1 => SoundSampleSize::Size16Bit,
+            _ => return Err(my_error("not possible")),
+        };
+
+        let sound_type = match data[0] & 0b00000001 {
+            0 => SoundType::TypeMono,
+            1 => SoundType::TypeStero,
+            _ => return Err(my_error("not possible")),
+        };
+
+        if let SoundFormatType::AAC = sound_format {
+            match sound_rate {
+                SoundSampleRate::Rate44k => (),
+                _ => {
+                    return Err(my_error(format!(
+                        "audio tag parse failed: AAC rate is not 44k but {}",
+                        sound_rate
+                    )))
+                }
+            }
+
+            match sound_size {
+                SoundSampleSize::Size16Bit => (),
+                _ => {
+                    return Err(my_error(format!(
+                        "audio tag parse failed: AAC sample size is not 16bit but {}",
+                        sound_size
+                    )))
+                }
+            }
+
+            match sound_type {
+                SoundType::TypeStero => (),
+                _ => {
+                    return Err(my_error(format!(
+                        "audio tag parse failed: AAC type is not stero but {}",
+                        sound_type
+                    )))
+                }
+            }
+        }
+
+        data = &data[1..];
+        let sound_data = Vec::from(data);
+        Ok((
+            return_data,
+            AudioTag {
+                header: header,
+                sound_format: sound_format,
+                sound_rate: sound_rate,
+                sound_size: sound_size,
+                sound_type: sound_type,
+                sound_data: sound_data,
+            },
+        ))
+    }
+}
+
+impl fmt::Display for AudioTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[AudioTag]:{}|{}|{}|{}|{}|{}",
+            self.header,
+            self.sound_format,
+            self.sound_rate,
+            self.sound_size,
+            self.sound_type,
+            self.sound_data.len()
+        )
+    }
+}
+
+struct AMF0Date {
+    date_time: f64,
+    local_offset: i16,
+}
+
+/*
+   AMF0_P_Number = 0x00,
+   AMF0_P_Boolean = 0x01,
+   AMF0_P_String = 0x02,
+   AMF0_P_Object = 0x03,
+   AMF0_P_MovieClip = 0x04,
+   AMF0_P_Null = 0x05,
+   AMF0_P_Undefined = 0x06,
+   AMF0_P_Reference = 0x07,
+   AMF0_P_MixedArray = 0x08,
+   AMF0_P_EndOfObject = 0x09,
+   AMF0_P_Array = 0x0a,
+   AMF0_P_Date = 0x0b,
+   AMF0_P_LongString = 0x0c,
+*/
+enum AMF0 {
+    Number(f64),
+    Boolean(bool),
+    String(String),
+    ObjectMap(BTreeMap<String, Box<AMF0>>),
+    MovieClip(String),
+    Null,
+    Undefine,
+    Reference(u16),
+    ECMAArray((u32, BTreeMap<String, Box<AMF0>>)),
+    EndIndicator,
+    Array(Vec<AMF0>),
+    Date(AMF0Date),
+    LongString(String),
+}
+
+fn print_map(f: &mut fmt::Formatter<'_>, map: &BTreeMap<String, Box<AMF0>>) -> fmt::Result {
+    write!(f, "{{")?;
+    for (name, val) in map {
+        write!(f, "{}:{},", name, val)?;
+    }
+    write!(f, "}}")
+}
+
+impl AMF0 {
+    fn parse(mut data: &[u8]) -> Result<(&[u8], Self)> {
+        if data.is_empty() {
+            return Err(my_error("amf0 parse failed: no data"));
+        }
+        let amf0_type = data[0];
+        data = &data[1..];
+        match amf0_type {
+            0 => {
+                if data.len() < 8 {
+                    Err(my_error(
+                        "amf0 num parse failed: not enough data.".to_string(),
+                    ))
+                } else {
+                    let number = f64::from_be_bytes((&data[0..8]).try_into().unwrap());
+                    data = &data[8..];
+                    Ok((data, Self::Number(number)))
+                }
+            }
+            1 => {
+                if data.len() < 1 {
+                    Err(my_error(
+                        "amf0 bool parse failed: not enough data.".to_string(),
+                    ))
+                } else {
+                    let bool_val = data[0] != 0;
+                    data = &data[1..];
+                    Ok((data, Self::Boolean(bool_val)))
+                }
+            }
+            2 => Self::parse_string(data)
+                .and_then(|(rest_data, string_val)| Ok((rest_data, Self::String(string_val))))
+                .or_else(|err| Err(my_error(format!("amf0 string parse failed:{}", err)))),
+            3 => {
+                let mut map = BTreeMap::new();
+
+                loop {
+                    if data.len() < 3 {
+                        break Err(my_error("amf0 obj map parse failed: end of data."));
+                    }
+
+                    if data[0..3] == [0, 0, 9] {
+                        break Ok((&data[3..], Self::ObjectMap(map)));
+                    }
+
+                    let (rest_data, name) = Self::parse_string(data).or_else(|err| {
+                        Err(my_error(format!("amf0 obj map parse name failed:{}", err)))
+                    })?;
+                    data = rest_data;
+
+                    let (rest_data, val) = Self::parse(data)?;
+                    data = rest_data;
+                    map.insert(name, Box::new(val));
+                }
+            }
+            4 => Self::parse_string(data)
+                .and_then(|(data, val)| Ok((data, Self::MovieClip(val))))
+                .or_else(|err| Err(my_error(format!("amf0 string parse failed:{}", err)))),
+            5 => Ok((data, Self::Null)),
+            6 => Ok((data, Self::Undefine)),
+            7 => {
+                if data.len() < 2 {
+                    Err(my_error("amf0 reference parse failed: not enough data"))
+                } else {
+                    let val = u16::from_be_bytes((&data[0..2]).try_into().unwrap());
+                    data = &data[2..];
+                    Ok((data, Self::Reference(val)))
+                }
+            }
+            8 => {
+                let mut map = BTreeMap::new();
+
+                if data.len() < 4 {
+                    Err(my_error("amf0 ECMA array parse failed: not enough data"))
+                } else {
+                    // ECMAArrayLen 只是hint 实际的array结束点还是AMF::EndIndicator
+                    let hint_len = u32::from_be_bytes(data[0..4].try_into().unwrap());
+                    data = &data[4..];
+                    loop {
+                        if data.len() < 3 {
+                            break Err(my_error("amf0 obj map parse failed: end of data."));
+                        }
+
+                        if data[0..3] == [0, 0, 9] {
+                            break Ok((&data[3..], Self::ECMAArray((hint_len, map))));
+                        }
+
+                        let (rest_data, name) = Self::parse_string(data).or_else(|err| {
+                            Err(my_error(format!("amf0 obj map parse name failed:{}", err)))
+                        })?;
+                        data = rest_data;
+
+                        let (rest_data, val) = Self::parse(data)?;
+                        data = rest_data;
+                        map.insert(name, Box::new(val));
+                    }
+                }
+            }
+            9 => Ok((data, AMF0::EndIndicator)),
+            10 => {
+                if data.len() < 4 {
+                    return Err(my_error("amf0 array parse failed: not enough data"));
+                }
+
+                // strict array, values without names
+                let array_len = u32::from_be_bytes(data[0..4].try_into().unwrap());
+                data = &data[4..];
+                let mut values = Vec::new();
+                for _ in 0..array_len {
+                    let (rest_data, val) = Self::parse(data)?;
+                    data = rest_data;
+                    values.push(val);
+                }
+                Ok((data, Self::Array(values)))
+            }
+            11 => {
+                if data.len() < 8 + 2 {
+                    return Err(my_error("amf0 date.datetime parse failed: not enough data"));
+                }
+
+                let date_time = f64::from_be_bytes(data[0..8].try_into().unwrap());
+                data = &data[8..];
+
+                let local_offset = i16::from_be_bytes(data[0..2].try_into().unwrap());
+                data = &data[2..];
+
+                Ok((
+                    data,
+                    Self::Date(AMF0Date {
+                        date_time: date_time,
+                        local_offset: local_offset,
+                    }),
+                ))
+            }
+            12 => {
+                if data.len() < 4 {
+                    Err(my_error(
+                        "amf0 long string size parse failed: not enough data.",
+                    ))
+                } else {
+                    let string_len = u32::from_be_bytes(data[0..4].try_into().unwrap()) as usize;
+                    data = &data[4..];
+                    if data.len() < string_len {
+                        Err(my_error("amf0 long string parse failed: not enough data."))
+                    } else {
+                        let string_val =
+                            String::from_utf8_lossy((&data[0..string_len]).try_into().unwrap())
+                                .to_string();
+                        Ok((&data[string_len..], AMF0::LongString(string_val)))
+                    }
+                }
+            }
+            _ => Err(my_error(format!(
+                "amf0 parse failed: unknow type {}",
+                amf0_type
+            ))),
+        }
+    }
+
+    fn parse_string(mut data: &[u8]) -> Result<(&[u8], String)> {
+        if data.len() < 2 {
+            Err(my_error("amf0 string size parse failed: not enough data."))
+        } else {
+            let string_len = u16::from_be_bytes(data[0..2].try_into().unwrap()) as usize;
+            data = &data[2..];
+            if data.len() < string_len {
+                Err(my_error("amf0 string parse failed: not enough data."))
+            } else {
+                let string_val =
+                    String::from_utf8_lossy((&data[0..string_len]).try_into().unwrap()).to_string();
+                Ok((&data[string_len..], string_val))
+            }
+        }
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, string: &str) {
+    out.extend_from_slice(&(string.len() as u16).to_be_bytes());
+    out.extend_from_slice(string.as_bytes());
+}
+
+fn write_map(out: &mut Vec<u8>, map: &BTreeMap<String, Box<AMF0>>) {
+    for (name, val) in map {
+        write_string(out, name);
+        val.write_to(out);
+    }
+    out.extend_from_slice(&[0, 0, 9]);
+}
+
+impl AMF0 {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        match self {
+            Self::Number(num) => {
+                out.push(0);
+                out.extend_from_slice(&num.to_be_bytes());
+            }
+            Self::Boolean(boolean) => out.extend_from_slice(&[1, *boolean as u8]),
+            Self::String(string) | Self::LongString(string) if string.len() > 0xffff => {
+                out.push(12);
+                out.extend_from_slice(&(string.len() as u32).to_be_bytes());
+                out.extend_from_slice(string.as_bytes());
+            }
+            Self::String(string) | Self::LongString(string) => {
+                out.push(2);
+                write_string(out, string);
+            }
+            Self::ObjectMap(map) => {
+                out.push(3);
+                write_map(out, map);
+            }
+            Self::MovieClip(path) => {
+                out.push(4);
+                write_string(out, path);
+            }
+            Self::Null => out.push(5),
+            Self::Undefine => out.push(6),
+            Self::Reference(val) => {
+                out.push(7);
+                out.extend_from_slice(&val.to_be_bytes());
+            }
+            Self::ECMAArray((_, map)) => {
+                out.push(8);
+                out.extend_from_slice(&(map.len() as u32).to_be_bytes());
+                write_map(out, map);
+            }
+            Self::EndIndicator => out.push(9),
+            Self::Array(values) => {
+                out.push(10);
+                out.extend_from_slice(&(values.len() as u32).to_be_bytes());
+                for val in values {
+                    val.write_to(out);
+                }
+            }
+            Self::Date(date_val) => {
+                out.push(11);
+                out.extend_from_slice(&date_val.date_time.to_be_bytes());
+                out.extend_from_slice(&date_val.local_offset.to_be_bytes());
+            }
+        }
+    }
+}
+
+impl fmt::Display for AMF0 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{{")?;
+        match self {
+            Self::Number(num) => write!(f, "{}", num),
+            Self::Boolean(boolean) => write!(f, "{}", boolean),
+            Self::String(string) => write!(f, "{}", string),
+            Self::ObjectMap(map) => {
+                write!(f, "obj map({}):", map.len())?;
+                print_map(f, map)
+            }
+            Self::MovieClip(path) => write!(f, "movie clip path:{}", path),
+            Self::Null => write!(f, "null"),
+            Self::Undefine => write!(f, "undefine"),
+            Self::Reference(val) => write!(f, "reference:{}", val),
+            Self::ECMAArray((hint_len, map)) => {
+                write!(f, "ecma map({}/{}):", hint_len, map.len())?;
+                print_map(f, map)
+            }
+            Self::EndIndicator => write!(f, "end indicator."),
+            Self::Array(values) => {
+                write!(f, "array({}):[", values.len())?;
+                for val in values {
+                    write!(f, "{},", val)?;
+                }
+                write!(f, "]")
+            }
+            Self::Date(date_val) => write!(
+                f,
+                "date:{{ base:{}, locale:{} }}",
+                date_val.date_time, date_val.local_offset
+            ),
+            Self::LongString(string) => write!(f, "long string:{}", string),
+        }?;
+
+        write!(f, "}}")
+    }
+}
+
+struct ScriptTag {
+    header: TagHeader,
+    obj_name: String,
+    obj_val: AMF0,
+}
+
+impl ScriptTag {
+    fn len(&self) -> usize {
+        TAG_HEADER_LEN + self.header.data_size
+    }
+
+    fn parse(mut data: &[u8]) -> Result<(&[u8], Self)> {
+        let (rest_data, header) = TagHeader::parse(data)
+            .or_else(|err| Err(my_error(format!("script tag header parse failed:{}", err))))?;
+
+        if rest_data.len() < header.data_size {
+            return Err(my_error(format!(
+                "script tag body parse failed: not enough data {}/{}",
+                rest_data.len(),
+                header.data_size
+            )));
+        }
+        data = &rest_data[0..header.data_size];
+        let return_data = &rest_data[header.data_size..];
+
+        let (rest_data, amf0_val) = AMF0::parse(data).or_else(|err| {
+            Err(my_error(format!(
+                "script tag name parse failed:{{ {} }}",
+                err
+            )))
+        })?;
+
+        data = rest_data;
+
+        if let AMF0::String(obj_name) = amf0_val {
+            let (_rest_data, amf0_val) = AMF0::parse(data).or_else(|err| {
+                Err(my_error(format!(
+                    "script tag val parse failed:{{ {} }}",
+                    err
+                )))
+            })?;
+
+            Ok((
+                return_data,
+                Self {
+                    header: header,
+                    obj_name: obj_name,
+                    obj_val: amf0_val,
+                },
+            ))
+        } else {
+            Err(my_error(
+                "script tag parse failed: first type not string no function name",
+            ))
+        }
+    }
+}
+
+impl fmt::Display for ScriptTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "script tag:{{name:{{{}}}, val:{}}}",
+            self.obj_name, self.obj_val
+        )
+    }
+}
+
+enum FlvTag {
+    VideoTag(VideoTag),
+    AudioTag(AudioTag),
+    ScriptTag(ScriptTag),
+}
+
+impl FlvTag {
+    fn tag_len(&self) -> usize {
+        match self {
+            FlvTag::VideoTag(tag_data) => tag_data.len(),
+            FlvTag::AudioTag(tag_data) => tag_data.len(),
+            FlvTag::ScriptTag(tag_data) => tag_data.len(),
+        }
+    }
+
+    fn parse(mut data: &[u8]) -> Result<(&[u8], Self)> {
+        if data.len() < TAG_HEADER_LEN {
+            return Err(Error::new(ErrorKind::Other, "tag has not enouth data"));
+        }
+
+        let tag_type = data[0];
+        data = &data[1..];
+
+        if tag_type == 8 {
+            AudioTag::parse(data)
+                .and_then(|(rest_data, tag)| Ok((rest_data, FlvTag::AudioTag(tag))))
+        } else if tag_type == 9 {
+            VideoTag::parse(data)
+                .and_then(|(rest_data, tag)| Ok((rest_data, FlvTag::VideoTag(tag))))
+        } else if tag_type == 18 {
+            ScriptTag::parse(data)
+                .and_then(|(rest_data, tag)| Ok((rest_data, FlvTag::ScriptTag(tag))))
+        } else {
+            Err(Error::new(
+                ErrorKind::Other,
+                format!("tag type {} not support", tag_type),
+            ))
+        }
+    }
+}
+
+impl FlvTag {
+    fn header(&self) -> &TagHeader {
+        match self {
+            FlvTag::VideoTag(tag) => &tag.header,
+            FlvTag::AudioTag(tag) => &tag.header,
+            FlvTag::ScriptTag(tag) => &tag.header,
+        }
+    }
+
+    fn info(&self, offset: usize) -> TagInfo {
+        let mut info = TagInfo {
+            offset,
+            tag_type: 18,
+            timestamp: self.header().timestamp as u32,
+            data_size: self.header().data_size,
+            frame_type: None,
+            codec: None,
+            packet_type: None,
+            composition_time: None,
+        };
+        match self {
+            FlvTag::VideoTag(tag) => {
+                info.tag_type = 9;
+                info.frame_type = Some(match tag.frame_type {
+                    VideoFrameType::KeyFrame => 1,
+                    VideoFrameType::InterFrame => 2,
+                    VideoFrameType::DisposableInterFrame => 3,
+                    VideoFrameType::GeneratedKeyFrame => 4,
+                    VideoFrameType::InfoOrCommandFrame => 5,
+                });
+                if let VideoPacket::AVC(packet) = &tag.packet_data {
+                    info.codec = Some(VIDEO_CODEC_AVC);
+                    info.packet_type = Some(match packet {
+                        AVCPacketData::AVCHeader(_) => 0,
+                        AVCPacketData::AVCNALU(nalu) => {
+                            // SI24, B frames before their reference are negative
+                            info.composition_time =
+                                Some(((nalu.composition_time << 8) as i32) >> 8);
+                            1
+                        }
+                        AVCPacketData::AVCEndOfSequence => 2,
+                    });
+                }
+            }
+            FlvTag::AudioTag(tag) => {
+                info.tag_type = 8;
+                match tag.sound_format {
+                    SoundFormatType::MP3 => info.codec = Some(SOUND_FORMAT_MP3),
+                    SoundFormatType::AAC => {
+                        info.codec = Some(SOUND_FORMAT_AAC);
+                        info.packet_type = tag.sound_data.first().copied();
+                    }
+                }
+            }
+            FlvTag::ScriptTag(_) => (),
+        }
+        info
+    }
+}
+
+/// Name and top level numbers and booleans of a script tag,
+/// e.g. onMetaData with duration, hasVideo, videocodecid
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ScriptData {
+    pub name: String,
+    pub numbers: BTreeMap<String, f64>,
+    pub booleans: BTreeMap<String, bool>,
+}
+
+/// the script data in the body of a script tag
+pub fn parse_script_data(body: &[u8]) -> Result<ScriptData> {
+    let (rest, name) = AMF0::parse(body)?;
+    let name = match name {
+        AMF0::String(name) => name,
+        _ => return Err(my_error("script data does not start with a name")),
+    };
+    let (_, value) = AMF0::parse(rest)?;
+    let properties = match &value {
+        AMF0::ObjectMap(map) | AMF0::ECMAArray((_, map)) => map,
+        _ => return Err(my_error("script data value is not an object")),
+    };
+    let mut data = ScriptData {
+        name,
+        ..ScriptData::default()
+    };
+    for (name, value) in properties {
+        match **value {
+            AMF0::Number(number) => {
+                data.numbers.insert(name.clone(), number);
+            }
+            AMF0::Boolean(boolean) => {
+                data.booleans.insert(name.clone(), boolean);
+            }
+            _ => (),
+        }
+    }
+    Ok(data)
+}
+
+/// A new script data body, e.g. onMetaData of a file written from scratch
+pub fn script_data(name: &str, numbers: &[(&str, f64)], booleans: &[(&str, bool)]) -> Vec<u8> {
+    let mut map = BTreeMap::new();
+    for (key, number) in numbers {
+        map.insert(key.to_string(), Box::new(AMF0::Number(*number)));
+    }
+    for (key, boolean) in booleans {
+        map.insert(key.to_string(), Box::new(AMF0::Boolean(*boolean)));
+    }
+    let mut out = Vec::new();
+    AMF0::String(name.to_owned()).write_to(&mut out);
+    AMF0::ECMAArray((map.len() as u32, map)).write_to(&mut out);
+    out
+}
+
+/// A script data body with numbers set, added when missing, and properties removed,
+/// e.g. onMetaData with the duration of a cut and without the stale keyframe index
+pub fn rewrite_script_data(body: &[u8], set: &[(&str, f64)], remove: &[&str]) -> Result<Vec<u8>> {
+    let (rest, name) = AMF0::parse(body)?;
+    let (_, mut value) = AMF0::parse(rest)?;
+    let properties = match &mut value {
+        AMF0::ObjectMap(map) | AMF0::ECMAArray((_, map)) => map,
+        _ => return Err(my_error("script data value is not an object")),
+    };
+    for name in remove {
+        properties.remove(*name);
+    }
+    for (name, number) in set {
+        properties.insert(name.to_string(), Box::new(AMF0::Number(*number)));
+    }
+    let mut out = Vec::with_capacity(body.len());
+    name.write_to(&mut out);
+    value.write_to(&mut out);
+    Ok(out)
+}
+
+/// A script data body with the properties of other it does not have yet,
+/// e.g. the onMetaData of joined files
+pub fn merge_script_data(body: &[u8], other: &[u8]) -> Result<Vec<u8>> {
+    let (rest, name) = AMF0::parse(body)?;
+    let (_, mut value) = AMF0::parse(rest)?;
+    let (rest, _) = AMF0::parse(other)?;
+    let (_, other_value) = AMF0::parse(rest)?;
+    match (&mut value, other_value) {
+        (
+            AMF0::ObjectMap(map) | AMF0::ECMAArray((_, map)),
+            AMF0::ObjectMap(other_map) | AMF0::ECMAArray((_, other_map)),
+        ) => {
+            for (key, val) in other_map {
+                map.entry(key).or_insert(val);
+            }
+        }
+        _ => return Err(my_error("script data value is not an object")),
+    }
+    let mut out = Vec::with_capacity(body.len());
+    name.write_to(&mut out);
+    value.write_to(&mut out);
+    Ok(out)
+}
+
+/// What file tools need to know about one parsed tag
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagInfo {
+    /// of the tag header from the start of the file
+    pub offset: usize,
+    pub tag_type: u8,
+    /// the decode timestamp in ms
+    pub timestamp: u32,
+    pub data_size: usize,
+    /// video only, 1 for keyframes
+    pub frame_type: Option<u8>,
+    /// video CodecID or audio SoundFormat
+    pub codec: Option<u8>,
+    /// AVCPacketType or AACPacketType, 0 for sequence headers
+    pub packet_type: Option<u8>,
+    /// AVC NALU packets only, pts - dts in ms
+    pub composition_time: Option<i32>,
+}
+
+impl TagInfo {
+    pub fn is_keyframe(&self) -> bool {
+        self.frame_type == Some(1)
+    }
+
+    /// AVC decoder configuration or AAC AudioSpecificConfig
+    pub fn is_sequence_header(&self) -> bool {
+        self.packet_type == Some(0)
+    }
+
+    /// the whole tag, header and body, in the file it was read from
+    pub fn bytes<'a>(&self, file: &'a [u8]) -> &'a [u8] {
+        &file[self.offset..self.offset + TAG_HEADER_LEN + self.data_size]
+    }
+
+    /// a copy of the tag to write elsewhere
+    pub fn raw_tag(&self, file: &[u8]) -> RawTag {
+        RawTag {
+            tag_type: self.tag_type,
+            timestamp: self.timestamp,
+            data: self.bytes(file).to_vec(),
+        }
+    }
+}
+
+impl fmt::Display for FlvTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlvTag::AudioTag(tag) => write!(f, "{}", tag),
+            FlvTag::VideoTag(tag) => write!(f, "{}", tag),
+            FlvTag::ScriptTag(tag) => write!(f, "[ScriptTag]:{}", tag),
+        }
+    }
+}
+
+fn parse_pre_tag_size(data: &[u8]) -> Result<(&[u8], usize)> {
+    if data.len() < PRE_TAG_SIZE_LEN {
+        Err(Error::new(
+            ErrorKind::Other,
+            "pre tag size parse failed, reason: not enough data.",
+        ))
+    } else {
+        let size = u32::from_be_bytes((&data[0..PRE_TAG_SIZE_LEN]).try_into().unwrap()) as usize;
+        Ok((&data[PRE_TAG_SIZE_LEN..], size))
+    }
+}
+
+pub fn parse_flv(data: &[u8]) -> Result<()> {
+    let (header, tags) = read_tags(data)?;
+    println!(
+        "flv version:{} HasVideo:{} HasAudio:{} tags:{}",
+        header.version,
+        header.has_video,
+        header.has_audio,
+        tags.len()
+    );
+    Ok(())
+}
+
+/// Parse a whole file, failing at the first bad tag
+pub fn read_tags(file: &[u8]) -> Result<(FlvHeader, Vec<TagInfo>)> {
+    let mut data = file;
+    if data.len() < FLV_HEADER_LEN {
+        return Err(my_error("flv header parse failed: not enough data"));
+    }
+
+    if data[0] != 'F' as u8 || data[1] != 'L' as u8 || data[2] != 'V' as u8 {
+        return Err(Error::new(
+            ErrorKind::Other,
+            "First Three Bytes is not 'F' 'L' 'V'.",
+        ));
+    }
+
+    let version = data[3];
+
+    let reserved_bit_not_zero = (data[4] & 0b11111010) != 0;
+    if reserved_bit_not_zero {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!("Type flag reserved bit not 0, flag:{:#08b}.", data[4]),
+        ));
+    }
+
+    let has_video = (data[4] & 0b0000001) != 0;
+    let has_audio = (data[4] & 0b0000100) != 0;
+
+    let data_offset = u32::from_be_bytes((&data[5..9]).try_into().unwrap()) as usize;
+    if version == 1 && data_offset != FLV_HEADER_LEN {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!("flv version 1, but data offset:{} is not 9", data_offset),
+        ));
+    }
+    if data.len() < data_offset + PRE_TAG_SIZE_LEN {
+        return Err(my_error(
+            "flv first pre tag size parse failed: not enough data",
+        ));
+    }
+
+    data = &data[data_offset..];
+    let first_pre_tag_size = u32::from_be_bytes((&data[0..4]).try_into().unwrap()) as usize;
+    if first_pre_tag_size != 0 {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!("flv first pre tag size is {} not 0", first_pre_tag_size),
+        ));
+    }
+
+    data = &data[4..];
+
+    let mut tag_cnt = 0;
+    let mut tags = Vec::new();
+    let mut offset = file.len() - data.len();
+    while offset < file.len() {
+        tag_cnt += 1;
+        let (info, len) = parse_tag_at(file, offset)
+            .map_err(|err| my_error(format!("flv tag {} : {}", tag_cnt, err)))?;
+        tags.push(info);
+        offset += len;
+    }
+
+    let header = FlvHeader {
+        version,
+        has_audio,
+        has_video,
+    };
+    Ok((header, tags))
+}
+
+/// The tag at offset and the PreviousTagSize after it,
+/// returns the tag and how far the next one is
+fn parse_tag_at(file: &[u8], offset: usize) -> Result<(TagInfo, usize)> {
+    let (rest_data, flv_tag) = FlvTag::parse(&file[offset..])?;
+    let (_, pre_tag_size) = parse_pre_tag_size(rest_data)?;
+    let tag_size = flv_tag.tag_len();
+    if pre_tag_size != tag_size {
+        return Err(my_error(format!(
+            "pre tag size {} is not equal to size in tag {}",
+            pre_tag_size, tag_size
+        )));
+    }
+    Ok((flv_tag.info(offset), tag_size + PRE_TAG_SIZE_LEN))
+}
+
+/// a tag header the framing agrees with: known type, StreamID 0
+/// and the PreviousTagSize after the body matching the size in the header
+fn is_tag_boundary(data: &[u8]) -> bool {
+    if data.len() < TAG_HEADER_LEN + PRE_TAG_SIZE_LEN
+        || ![8, 9, 18].contains(&data[0])
+        || data[8..11] != [0, 0, 0]
+    {
+        return false;
+    }
+    let tag_size = TAG_HEADER_LEN + u32::from_be_bytes([0, data[1], data[2], data[3]]) as usize;
+    data.len() >= tag_size + PRE_TAG_SIZE_LEN
+        && data[tag_size..tag_size + PRE_TAG_SIZE_LEN] == (tag_size as u32).to_be_bytes()
+}
+
+/// the first offset after offset where a tag header the framing agrees with starts
+pub fn next_tag_boundary(file: &[u8], offset: usize) -> Option<usize> {
+    (offset + 1..file.len()).find(|&next| is_tag_boundary(&file[next..]))
+}
+
+/// Bytes skipped to get back to a tag boundary
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dropped {
+    pub offset: usize,
+    pub len: usize,
+    /// why the tag at offset did not parse
+    pub reason: String,
+}
+
+/// Parse a whole file like read_tags, but go on after bad data:
+/// from a tag that does not parse the file is scanned forward
+/// for the next plausible tag header and read on from there
+pub fn read_tags_recovering(file: &[u8]) -> Result<(FlvHeader, Vec<TagInfo>, Vec<Dropped>)> {
+    if file.len() < FLV_HEADER_LEN || &file[0..3] != b"FLV" {
+        return Err(my_error("First Three Bytes is not 'F' 'L' 'V'."));
+    }
+    let header = FlvHeader {
+        version: file[3],
+        has_audio: file[4] & 0b100 != 0,
+        has_video: file[4] & 0b001 != 0,
+    };
+    // a broken data offset is taken as the usual one, the first PreviousTagSize is skipped
+    let mut data_offset = u32::from_be_bytes(file[5..9].try_into().unwrap()) as usize;
+    if !(FLV_HEADER_LEN..=file.len()).contains(&data_offset) {
+        data_offset = FLV_HEADER_LEN;
+    }
+
+    let mut tags = Vec::new();
+    let mut dropped = Vec::new();
+    let mut offset = (data_offset + PRE_TAG_SIZE_LEN).min(file.len());
+    while offset < file.len() {
+        match parse_tag_at(file, offset) {
+            Ok((info, len)) => {
+                tags.push(info);
+                offset += len;
+            }
+            Err(err) => {
+                let next = (offset + 1..file.len())
+                    .find(|&next| {
+                        is_tag_boundary(&file[next..]) && parse_tag_at(file, next).is_ok()
+                    })
+                    .unwrap_or(file.len());
+                dropped.push(Dropped {
+                    offset,
+                    len: next - offset,
+                    reason: err.to_string(),
+                });
+                offset = next;
+            }
+        }
+    }
+    Ok((header, tags, dropped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flv_demuxer::make_tag;
+
+    #[test]
+    fn test_read_tags_recovering() {
+        let mut file = FlvHeader {
+            version: 1,
+            has_audio: true,
+            has_video: true,
+        }
+        .to_bytes();
+        file.extend(make_tag(9, 0, &[0x17, 1, 0, 0, 0, 1, 2]));
+        // garbage with a tag type byte in it, then a tag cut short
+        let garbage_at = file.len();
+        file.extend_from_slice(&[0x55, 9, 0, 0xff, 3, 0, 0, 0]);
+        file.extend(&make_tag(8, 10, &[0xaf, 1, 1, 2, 3])[..12]);
+        let audio_at = file.len();
+        file.extend(make_tag(8, 20, &[0xaf, 1, 1, 2, 3]));
+        // unknown tag type with a matching PreviousTagSize
+        let unknown_at = file.len();
+        file.extend(make_tag(7, 30, &[1, 2, 3]));
+        file.extend(make_tag(9, 40, &[0x27, 1, 0, 0, 0, 1, 2]));
+        let end = file.len();
+        file.extend_from_slice(&[9, 0]);
+
+        assert!(read_tags(&file).is_err());
+        let (_, tags, dropped) = read_tags_recovering(&file).unwrap();
+        let timestamps: Vec<u32> = tags.iter().map(|tag| tag.timestamp).collect();
+        assert_eq!(timestamps, vec![0, 20, 40]);
+        assert_eq!(tags[1].offset, audio_at);
+        let regions: Vec<(usize, usize)> = dropped
+            .iter()
+            .map(|region| (region.offset, region.len))
+            .collect();
+        assert_eq!(
+            regions,
+            vec![
+                (garbage_at, audio_at - garbage_at),
+                (unknown_at, 18),
+                (end, 2)
+            ]
+        );
+        assert!(dropped[1].reason.contains("tag type 7"));
+    }
+
+    #[test]
+    fn test_read_tags_recovering_short_avc() {
+        let mut file = FlvHeader {
+            version: 1,
+            has_audio: false,
+            has_video: true,
+        }
+        .to_bytes();
+        file.extend(make_tag(9, 0, &[0x17, 1, 0, 0, 0, 1, 2]));
+        // AVC bodies of 0, 1 and 2 bytes, the last with an unknown packet type
+        let short_at = file.len();
+        file.extend(make_tag(9, 10, &[]));
+        file.extend(make_tag(9, 20, &[0x17]));
+        file.extend(make_tag(9, 30, &[0x27, 5]));
+        let video_at = file.len();
+        file.extend(make_tag(9, 40, &[0x27, 1, 0, 0, 0, 1, 2]));
+
+        assert!(read_tags(&file).is_err());
+        let (_, tags, dropped) = read_tags_recovering(&file).unwrap();
+        let timestamps: Vec<u32> = tags.iter().map(|tag| tag.timestamp).collect();
+        assert_eq!(timestamps, vec![0, 40]);
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(
+            (dropped[0].offset, dropped[0].len),
+            (short_at, video_at - short_at)
+        );
+        assert!(parse_tag_at(&file, short_at + 15).is_err());
+        let err = parse_tag_at(&file, short_at + 15 + 16).unwrap_err();
+        assert!(err.to_string().contains("packet type5"));
+    }
+
+    fn get_timestamp(data: &[u8; 4]) -> i32 {
+        ((data[3] as i32) << 24)
+            | ((data[0] as i32) << 16)
+            | ((data[1] as i32) << 8)
+            | ((data[2] as i32) << 0)
+    }
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(get_timestamp(&[0x00, 0x00, 0x00, 0x80]), -0x80000000);
+        assert_eq!(get_timestamp(&[0x00, 0x00, 0x00, 0x00]), 0);
+        assert_eq!(get_timestamp(&[0xff, 0xff, 0xff, 0xff]), -1);
+        assert_eq!(get_timestamp(&[0xff, 0xff, 0xfe, 0xff]), -2);
+        assert_eq!(get_timestamp(&[0xff, 0xff, 0xff, 0x00]), 0xffffff);
+    }
+
+    fn property(body: &mut Vec<u8>, name: &str) {
+        body.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        body.extend_from_slice(name.as_bytes());
+    }
+
+    #[test]
+    fn test_parse_script_data() {
+        let mut body = vec![2, 0, 10];
+        body.extend_from_slice(b"onMetaData");
+        body.extend_from_slice(&[8, 0, 0, 0, 6]);
+        property(&mut body, "duration");
+        body.push(0);
+        body.extend_from_slice(&2.5f64.to_be_bytes());
+        property(&mut body, "hasAudio");
+        body.extend_from_slice(&[1, 1]);
+        property(&mut body, "hasVideo");
+        body.extend_from_slice(&[1, 0]);
+        // keyframe index as strict arrays
+        property(&mut body, "keyframes");
+        body.push(3);
+        property(&mut body, "times");
+        body.extend_from_slice(&[10, 0, 0, 0, 2, 0]);
+        body.extend_from_slice(&0f64.to_be_bytes());
+        body.push(0);
+        body.extend_from_slice(&1f64.to_be_bytes());
+        body.extend_from_slice(&[0, 0, 9]);
+        property(&mut body, "creationdate");
+        body.push(11);
+        body.extend_from_slice(&1.6e12f64.to_be_bytes());
+        body.extend_from_slice(&[0, 0]);
+        property(&mut body, "comment");
+        body.extend_from_slice(&[12, 0, 0, 0, 2]);
+        body.extend_from_slice(b"ok");
+        body.extend_from_slice(&[0, 0, 9]);
+
+        let data = parse_script_data(&body).unwrap();
+        assert_eq!(data.name, "onMetaData");
+        assert_eq!(data.numbers["duration"], 2.5);
+        assert!(data.booleans["hasAudio"]);
+        assert!(!data.booleans["hasVideo"]);
+        // cut anywhere, an error and no panic
+        for len in 0..body.len() - 3 {
+            assert!(parse_script_data(&body[..len]).is_err());
+        }
+    }
+}
@@ -0,0 +1,305 @@
+use crate::flv::{FLV_HEADER_LEN, PRE_TAG_SIZE_LEN, TAG_HEADER_LEN};
+use crate::my_error::my_error;
+use std::convert::TryInto;
+use std::io::Result as IoResult;
+
+pub const TAG_TYPE_AUDIO: u8 = 8;
+pub const TAG_TYPE_VIDEO: u8 = 9;
+pub const TAG_TYPE_SCRIPT: u8 = 18;
+
+/// CodecID of the video tag header
+pub const VIDEO_CODEC_AVC: u8 = 7;
+/// SoundFormat of the audio tag header
+pub const SOUND_FORMAT_MP3: u8 = 2;
+pub const SOUND_FORMAT_AAC: u8 = 10;
+
+/// FLV file header as sent by the publisher
+#[derive(Debug, Clone)]
+pub struct FlvHeader {
+    pub version: u8,
+    pub has_audio: bool,
+    pub has_video: bool,
+}
+
+impl FlvHeader {
+    /// header bytes followed by the first PreviousTagSize 0
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.has_audio {
+            flags |= 0b100;
+        }
+        if self.has_video {
+            flags |= 0b001;
+        }
+        let mut data = vec![b'F', b'L', b'V', self.version, flags];
+        data.extend_from_slice(&(FLV_HEADER_LEN as u32).to_be_bytes());
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        data
+    }
+}
+
+/// One tag as it is on the wire: 11 bytes tag header and the tag body,
+/// without the PreviousTagSize after it.
+/// Only the fields needed to route it are decoded, the body is kept as is.
+#[derive(Debug)]
+pub struct RawTag {
+    pub tag_type: u8,
+    pub timestamp: u32,
+    pub data: Vec<u8>,
+}
+
+/// the tag bytes as sent to viewers
+impl AsRef<[u8]> for RawTag {
+    fn as_ref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl RawTag {
+    /// a tag with a fresh header, StreamID 0
+    pub fn new(tag_type: u8, timestamp: u32, body: &[u8]) -> Self {
+        let size = (body.len() as u32).to_be_bytes();
+        let mut data = Vec::with_capacity(TAG_HEADER_LEN + body.len());
+        data.extend_from_slice(&[tag_type, size[1], size[2], size[3]]);
+        data.extend_from_slice(&timestamp_bytes(timestamp));
+        data.extend_from_slice(&[0, 0, 0]);
+        data.extend_from_slice(body);
+        Self {
+            tag_type,
+            timestamp,
+            data,
+        }
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.data[TAG_HEADER_LEN..]
+    }
+
+    /// timestamp in the field and in the tag header, the extended byte last
+    pub fn set_timestamp(&mut self, timestamp: u32) {
+        self.timestamp = timestamp;
+        self.data[4..8].copy_from_slice(&timestamp_bytes(timestamp));
+    }
+
+    /// the tag header with another timestamp
+    pub fn header_with_timestamp(&self, timestamp: u32) -> [u8; TAG_HEADER_LEN] {
+        let mut header = [0; TAG_HEADER_LEN];
+        header.copy_from_slice(&self.data[..TAG_HEADER_LEN]);
+        header[4..8].copy_from_slice(&timestamp_bytes(timestamp));
+        header
+    }
+
+    pub fn is_video_keyframe(&self) -> bool {
+        self.tag_type == TAG_TYPE_VIDEO && self.body().first().map(|byte| byte >> 4) == Some(1)
+    }
+
+    /// AVC decoder configuration or AAC AudioSpecificConfig
+    pub fn is_sequence_header(&self) -> bool {
+        match (self.tag_type, self.body()) {
+            (TAG_TYPE_VIDEO, [flags, 0, ..]) => flags & 0x0f == VIDEO_CODEC_AVC,
+            (TAG_TYPE_AUDIO, [flags, 0, ..]) => flags >> 4 == SOUND_FORMAT_AAC,
+            _ => false,
+        }
+    }
+}
+
+/// a tag as it is in a file, with the PreviousTagSize after it
+#[cfg(test)]
+pub fn make_tag(tag_type: u8, timestamp: u32, body: &[u8]) -> Vec<u8> {
+    let mut data = RawTag::new(tag_type, timestamp, body).data;
+    data.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    data
+}
+
+fn timestamp_bytes(timestamp: u32) -> [u8; 4] {
+    let bytes = timestamp.to_be_bytes();
+    [bytes[1], bytes[2], bytes[3], bytes[0]]
+}
+
+/// What the demuxer produced from the bytes fed so far
+#[derive(Debug)]
+pub enum DemuxEvent {
+    Header(FlvHeader),
+    Tag(RawTag),
+}
+
+/// Incremental FLV demuxer for data arriving in arbitrary pieces,
+/// e.g. the body of an ingest request.
+/// Checks framing only (header, PreviousTagSize, StreamID), codec data is not parsed.
+#[derive(Debug)]
+pub struct FlvDemuxer {
+    buffer: Vec<u8>,
+    header_done: bool,
+}
+
+impl FlvDemuxer {
+    pub fn new() -> Self {
+        Self {
+            buffer: Vec::new(),
+            header_done: false,
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Ok(None) means more data is needed
+    pub fn next_event(&mut self) -> IoResult<Option<DemuxEvent>> {
+        if !self.header_done {
+            return self.parse_header();
+        }
+
+        if self.buffer.len() < TAG_HEADER_LEN {
+            return Ok(None);
+        }
+        let tag_type = self.buffer[0];
+        if tag_type & 0x1f != TAG_TYPE_AUDIO
+            && tag_type & 0x1f != TAG_TYPE_VIDEO
+            && tag_type & 0x1f != TAG_TYPE_SCRIPT
+        {
+            return Err(my_error(format!(
+                "flv demux tag type {} not support",
+                tag_type
+            )));
+        }
+        if tag_type & 0x20 != 0 {
+            return Err(my_error("flv demux encrypted tag not support"));
+        }
+        let data_size = u32::from_be_bytes([0, self.buffer[1], self.buffer[2], self.buffer[3]]);
+        let timestamp = u32::from_be_bytes([
+            self.buffer[7],
+            self.buffer[4],
+            self.buffer[5],
+            self.buffer[6],
+        ]);
+        if self.buffer[8..11] != [0, 0, 0] {
+            return Err(my_error("flv demux tag header streamid not 0"));
+        }
+
+        let tag_len = TAG_HEADER_LEN + data_size as usize;
+        if self.buffer.len() < tag_len + PRE_TAG_SIZE_LEN {
+            return Ok(None);
+        }
+        let pre_tag_size = u32::from_be_bytes(
+            self.buffer[tag_len..tag_len + PRE_TAG_SIZE_LEN]
+                .try_into()
+                .unwrap(),
+        ) as usize;
+        if pre_tag_size != tag_len {
+            return Err(my_error(format!(
+                "flv demux pre tag size {} is not equal to size in tag {}",
+                pre_tag_size, tag_len
+            )));
+        }
+
+        let rest = self.buffer.split_off(tag_len);
+        let data = std::mem::replace(&mut self.buffer, rest);
+        self.buffer.drain(0..PRE_TAG_SIZE_LEN);
+        Ok(Some(DemuxEvent::Tag(RawTag {
+            tag_type,
+            timestamp,
+            data,
+        })))
+    }
+
+    fn parse_header(&mut self) -> IoResult<Option<DemuxEvent>> {
+        if self.buffer.len() < FLV_HEADER_LEN {
+            return Ok(None);
+        }
+        if &self.buffer[0..3] != b"FLV" {
+            return Err(my_error("flv demux first three bytes is not 'F' 'L' 'V'"));
+        }
+        let flags = self.buffer[4];
+        if flags & 0b11111010 != 0 {
+            return Err(my_error(format!(
+                "flv demux type flag reserved bit not 0, flag:{:#08b}",
+                flags
+            )));
+        }
+        let data_offset = u32::from_be_bytes(self.buffer[5..9].try_into().unwrap()) as usize;
+        if data_offset < FLV_HEADER_LEN {
+            return Err(my_error(format!(
+                "flv demux data offset {} too small",
+                data_offset
+            )));
+        }
+        if self.buffer.len() < data_offset + PRE_TAG_SIZE_LEN {
+            return Ok(None);
+        }
+
+        let header = FlvHeader {
+            version: self.buffer[3],
+            has_audio: flags & 0b100 != 0,
+            has_video: flags & 0b001 != 0,
+        };
+        // the first PreviousTagSize is not checked, some muxers do not write 0
+        self.buffer.drain(0..data_offset + PRE_TAG_SIZE_LEN);
+        self.header_done = true;
+        Ok(Some(DemuxEvent::Header(header)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_demux_in_pieces() {
+        let header = FlvHeader {
+            version: 1,
+            has_audio: true,
+            has_video: true,
+        };
+        let mut stream = header.to_bytes();
+        stream.extend(make_tag(TAG_TYPE_VIDEO, 0, &[0x17, 0, 0, 0, 0, 1, 2]));
+        stream.extend(make_tag(TAG_TYPE_AUDIO, 0x01020304, &[0xaf, 1, 9]));
+
+        let mut demuxer = FlvDemuxer::new();
+        let mut events = Vec::new();
+        for byte in stream {
+            demuxer.feed(&[byte]);
+            while let Some(event) = demuxer.next_event().unwrap() {
+                events.push(event);
+            }
+        }
+
+        assert_eq!(events.len(), 3);
+        assert!(matches!(&events[0], DemuxEvent::Header(h) if h.has_audio && h.has_video));
+        match &events[1] {
+            DemuxEvent::Tag(tag) => {
+                assert!(tag.is_video_keyframe());
+                assert!(tag.is_sequence_header());
+                assert_eq!(tag.body().len(), 7);
+            }
+            _ => panic!("expect video tag"),
+        }
+        match &events[2] {
+            DemuxEvent::Tag(tag) => {
+                assert_eq!(tag.timestamp, 0x01020304);
+                assert!(!tag.is_sequence_header());
+                assert_eq!(tag.header_with_timestamp(0x05060708)[4..8], [6, 7, 8, 5]);
+            }
+            _ => panic!("expect audio tag"),
+        }
+    }
+
+    #[test]
+    fn test_demux_bad_pre_tag_size() {
+        let mut demuxer = FlvDemuxer::new();
+        demuxer.feed(
+            &FlvHeader {
+                version: 1,
+                has_audio: true,
+                has_video: false,
+            }
+            .to_bytes(),
+        );
+        let mut tag = make_tag(TAG_TYPE_AUDIO, 0, &[0xaf, 1]);
+        let len = tag.len();
+        tag[len - 1] += 1;
+        demuxer.feed(&tag);
+        assert!(demuxer.next_event().unwrap().is_some());
+        assert!(demuxer.next_event().is_err());
+    }
+}
@@ -0,0 +1,65 @@
+use crate::flv::{FLV_HEADER_LEN, PRE_TAG_SIZE_LEN};
+use crate::flv_demuxer::{FlvHeader, RawTag, TAG_TYPE_AUDIO, TAG_TYPE_VIDEO};
+use std::io::Result as IoResult;
+use std::io::Write;
+
+/// Writes an FLV file tag by tag, each followed by its PreviousTagSize
+pub struct FlvWriter<W: Write> {
+    out: W,
+    /// bytes written so far, header included
+    pub bytes: u64,
+    pub tags: u64,
+}
+
+impl<W: Write> FlvWriter<W> {
+    pub fn new(mut out: W, header: &FlvHeader) -> IoResult<Self> {
+        let header = header.to_bytes();
+        out.write_all(&header)?;
+        Ok(Self {
+            out,
+            bytes: header.len() as u64,
+            tags: 0,
+        })
+    }
+
+    /// a whole tag, tag header and body
+    pub fn write_tag(&mut self, tag: &[u8]) -> IoResult<()> {
+        self.out.write_all(tag)?;
+        self.out.write_all(&(tag.len() as u32).to_be_bytes())?;
+        self.bytes += (tag.len() + PRE_TAG_SIZE_LEN) as u64;
+        self.tags += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> IoResult<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// the header of a file with these tags, flags set for the tracks there are
+pub fn header_for(tags: &[RawTag]) -> FlvHeader {
+    FlvHeader {
+        version: 1,
+        has_audio: tags.iter().any(|tag| tag.tag_type == TAG_TYPE_AUDIO),
+        has_video: tags.iter().any(|tag| tag.tag_type == TAG_TYPE_VIDEO),
+    }
+}
+
+/// size of a file with these tags as FlvWriter writes it
+pub fn file_size(tags: &[RawTag]) -> u64 {
+    let tags: usize = tags
+        .iter()
+        .map(|tag| tag.data.len() + PRE_TAG_SIZE_LEN)
+        .sum();
+    (FLV_HEADER_LEN + PRE_TAG_SIZE_LEN + tags) as u64
+}
+
+/// a complete file of these tags
+pub fn write_file<W: Write>(out: W, tags: &[RawTag]) -> IoResult<W> {
+    let mut writer = FlvWriter::new(out, &header_for(tags))?;
+    for tag in tags {
+        writer.write_tag(&tag.data)?;
+    }
+    writer.finish()
+}
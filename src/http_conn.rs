@@ -1,11 +1,40 @@
-use crate::epoller::{Epoller, RWHandle};
+use crate::config::{current, SharedConfig};
+use crate::epoller::{Epoller, Interest, RWHandle, Trigger};
+use crate::flv::TAG_HEADER_LEN;
+use crate::flv_demuxer::{DemuxEvent, FlvDemuxer, RawTag};
+use crate::http_request::{BodyFraming, HttpMethod, HttpReq, HttpReqParser, ParseEvent};
+use crate::http_response::{http_date, write_chunk, write_last_chunk, HttpResponse};
+use crate::live::{
+    is_header, live_stream_key, lock, Publisher, SharedRegistry, Subscription, ViewerWaker,
+    ViewerWakeup,
+};
 use crate::my_error::my_error;
-use std::collections::BTreeMap;
+use crate::output_queue::OutputQueue;
+use crate::timer::TimerId;
+use crate::timestamp::rebase;
+use crate::vod::{content_type, parse_range, vod_file_path, FileBody};
+use crate::websocket::{
+    upgrade_accept_key, write_close_frame, write_frame, write_frame_header, WsFrameParser,
+    CLOSE_GOING_AWAY, CLOSE_PROTOCOL_ERROR, OPCODE_BINARY, OPCODE_CLOSE, OPCODE_PING, OPCODE_PONG,
+};
+use std::any::Any;
+use std::fs::File;
 use std::io::Result as IoResult;
-use std::io::{Error, ErrorKind, Read};
-use std::net::{TcpListener, TcpStream};
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::io::{Error, ErrorKind, IoSlice, Read};
+use std::mem;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::str;
+use std::sync::Arc;
+
+/// message asking the listener to print its stats now
+#[derive(Debug)]
+pub struct PrintStats;
+
+/// message sent to every handle when the server shuts down:
+/// the listener stops accepting, connections finish what they are sending and close
+#[derive(Debug)]
+pub struct Shutdown;
 
 impl RWHandle for TcpListener {
     fn on_read(&mut self, epoller: &mut Epoller) -> IoResult<()> {
@@ -65,15 +94,113 @@ impl RWHandle for TcpStream {
 #[derive(Debug)]
 pub struct HttpListener {
     listener: TcpListener,
+    registry: SharedRegistry,
+    config: SharedConfig,
+    accepted: u64,
 }
 
 impl HttpListener {
-    pub fn bind(address: &str) -> IoResult<Self> {
-        let tcplistener = TcpListener::bind(address)?;
-        tcplistener.set_nonblocking(true)?;
-        return Ok(Self {
-            listener: tcplistener,
-        });
+    /// Every worker binds the same address, SO_REUSEPORT lets the kernel
+    /// spread incoming connections over their listeners.
+    pub fn bind(address: &str, registry: SharedRegistry, config: SharedConfig) -> IoResult<Self> {
+        Self::new(bind_reuseport(address)?, registry, config)
+    }
+
+    /// listen on an already bound socket, e.g. a clone of one shared by all workers
+    pub fn new(
+        listener: TcpListener,
+        registry: SharedRegistry,
+        config: SharedConfig,
+    ) -> IoResult<Self> {
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            registry,
+            config,
+            accepted: 0,
+        })
+    }
+}
+
+/// listening socket with SO_REUSEADDR and SO_REUSEPORT set before bind,
+/// which std::net::TcpListener::bind has no way to do
+pub fn bind_reuseport(address: &str) -> IoResult<TcpListener> {
+    let addr: SocketAddr = address
+        .parse()
+        .map_err(|err| my_error(format!("bad listen address {}: {}", address, err)))?;
+    let domain = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd == -1 {
+        return Err(Error::last_os_error());
+    }
+    // owns the fd from here, closed on the error returns
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+    let one: libc::c_int = 1;
+    for option in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        let res = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                &one as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if res == -1 {
+            return Err(Error::last_os_error());
+        }
+    }
+
+    let res = match addr {
+        SocketAddr::V4(v4) => {
+            let mut sin: libc::sockaddr_in = unsafe { mem::zeroed() };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = v4.port().to_be();
+            sin.sin_addr.s_addr = u32::from_ne_bytes(v4.ip().octets());
+            unsafe {
+                libc::bind(
+                    fd,
+                    &sin as *const libc::sockaddr_in as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                )
+            }
+        }
+        SocketAddr::V6(v6) => {
+            let mut sin6: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = v6.port().to_be();
+            sin6.sin6_flowinfo = v6.flowinfo();
+            sin6.sin6_addr.s6_addr = v6.ip().octets();
+            sin6.sin6_scope_id = v6.scope_id();
+            unsafe {
+                libc::bind(
+                    fd,
+                    &sin6 as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                )
+            }
+        }
+    };
+    if res == -1 {
+        return Err(Error::last_os_error());
+    }
+    if unsafe { libc::listen(fd, libc::SOMAXCONN) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(listener)
+}
+
+impl HttpListener {
+    fn print_stats(&self) {
+        let (streams, viewers) = lock(&self.registry).stats();
+        println!(
+            "http listener {:?} stats: accepted:{} streams:{} viewers:{}",
+            self.listener, self.accepted, streams, viewers
+        );
     }
 }
 
@@ -83,18 +210,659 @@ impl AsRawFd for HttpListener {
     }
 }
 
+/// request bodies we keep in memory are capped, streaming uploads are not buffered
+const MAX_BUFFERED_BODY_SIZE: usize = 1024 * 1024;
+/// bytes taken from the socket per read call
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+/// live tags moved into a viewer's output at most, the rest stays in its
+/// queue where the slow viewer policy can drop them
+const VIEWER_OUTPUT_WATERMARK: usize = 256 * 1024;
+
+/// a request body being published as a live stream
+#[derive(Debug)]
+struct Ingest {
+    publisher: Publisher,
+    demuxer: FlvDemuxer,
+}
+
+/// how FLV data is wrapped for a viewer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ViewerFraming {
+    Chunked,
+    /// HTTP/1.0, the body ends when the connection closes
+    Raw,
+    WebSocket,
+}
+
+/// a connection playing a live stream, over http or websocket
+#[derive(Debug)]
+struct Viewer {
+    subscription: Subscription,
+    framing: ViewerFraming,
+    ws_parser: WsFrameParser,
+    // stream timestamp of the first audio/video tag sent, the viewer's 0
+    base_timestamp: Option<u32>,
+}
+
 #[derive(Debug)]
 struct HttpStream {
     stream: TcpStream,
-    output_buf: Vec<u8>,
+    registry: SharedRegistry,
+    config: SharedConfig,
+    parser: HttpReqParser,
+    current_req: Option<HttpReq>,
+    ingest: Option<Ingest>,
+    viewer: Option<Viewer>,
+    output: OutputQueue,
+    // body of a file response, sent once output is
+    file: Option<FileBody>,
+    // EPOLLOUT is only requested while output is pending
+    want_write: bool,
+    // bytes written so far, to tell whether a write made progress
+    sent: u64,
+    // no more requests are served, close once output is sent
+    close_after_flush: bool,
+    // the server is shutting down, every response closes the connection
+    draining: bool,
+    idle_timer: Option<TimerId>,
 }
 
 impl HttpStream {
-    fn new(stream: TcpStream) -> Self {
-        return Self {
-            stream: stream,
-            output_buf: Vec::new(),
+    fn new(stream: TcpStream, registry: SharedRegistry, config: SharedConfig) -> Self {
+        Self {
+            stream,
+            registry,
+            config,
+            parser: HttpReqParser::new(),
+            current_req: None,
+            ingest: None,
+            viewer: None,
+            output: OutputQueue::new(),
+            file: None,
+            want_write: false,
+            sent: 0,
+            close_after_flush: false,
+            draining: false,
+            idle_timer: None,
+        }
+    }
+
+    /// restart the inactivity timer, how long depends on what the connection is doing
+    fn rearm_idle_timer(&mut self, epoller: &mut Epoller) {
+        if let Some(timer) = self.idle_timer.take() {
+            epoller.cancel_timer(timer);
+        }
+        let config = current(&self.config);
+        let timeout = if self.ingest.is_some() {
+            config.ingest_timeout
+        } else if self.viewer.is_some() {
+            config.viewer_stall_timeout
+        } else if self.close_after_flush
+            || self.file.is_some()
+            || self.current_req.is_some()
+            || !self.parser.is_idle()
+        {
+            config.request_timeout
+        } else {
+            config.keep_alive_timeout
+        };
+        drop(config);
+        self.idle_timer = Some(epoller.add_timer(self.as_raw_fd(), timeout));
+    }
+
+    fn handle_request(&mut self, req: HttpReq, epoller: &mut Epoller) {
+        println!(
+            "client asking for {} {}{} HTTP/{}.{} body:{}",
+            req.method,
+            req.path,
+            req.query
+                .as_ref()
+                .map_or(String::new(), |query| format!("?{}", query)),
+            req.major_version,
+            req.minor_version,
+            req.body.len()
+        );
+
+        let live_key = live_stream_key(&req.path).map(str::to_owned);
+        let resp = match (req.method, live_key) {
+            (HttpMethod::Options, _) => HttpResponse::for_request(&req, 204)
+                .header("Allow", "GET, HEAD, POST, PUT, DELETE, OPTIONS"),
+            (HttpMethod::Get, Some(key)) | (HttpMethod::Head, Some(key)) => {
+                match self.start_viewer(&req, &key, epoller) {
+                    Some(resp) => resp,
+                    None => return,
+                }
+            }
+            (HttpMethod::Get, None) | (HttpMethod::Head, None) if req.path.starts_with("/vod/") => {
+                self.start_file(&req)
+            }
+            // ingest with a body was taken over in start_ingest
+            (HttpMethod::Post, Some(_)) | (HttpMethod::Put, Some(_)) => {
+                HttpResponse::for_request(&req, 411).text_body("flv body required\n")
+            }
+            _ => HttpResponse::for_request(&req, 404).text_body("not found\n"),
+        };
+        self.queue_response(resp);
+    }
+
+    /// GET /vod/<name> from the vod_root directory, a single byte range is supported.
+    /// The body is sent from the file with sendfile as the socket takes it.
+    fn start_file(&mut self, req: &HttpReq) -> HttpResponse {
+        let root = current(&self.config).vod_root.clone();
+        let not_found = || HttpResponse::for_request(req, 404).text_body("not found\n");
+        let path = match vod_file_path(&root, &req.path) {
+            Some(path) => path,
+            None => return not_found(),
+        };
+        let (file, metadata) = match File::open(&path).and_then(|file| {
+            let metadata = file.metadata()?;
+            Ok((file, metadata))
+        }) {
+            Ok((file, metadata)) if metadata.is_file() => (file, metadata),
+            Ok(_) => return not_found(),
+            Err(err) => {
+                println!("client:{:?} open {:?} failed:{}", self.stream, path, err);
+                return not_found();
+            }
+        };
+
+        let len = metadata.len();
+        let range = match req.header("Range") {
+            Some(value) => parse_range(value, len),
+            None => Ok(None),
+        };
+        let (resp, first, last) = match range {
+            Ok(Some((first, last))) => (
+                HttpResponse::for_request(req, 206).header(
+                    "Content-Range",
+                    &format!("bytes {}-{}/{}", first, last, len),
+                ),
+                first,
+                last,
+            ),
+            Ok(None) => (
+                HttpResponse::for_request(req, 200),
+                0,
+                len.saturating_sub(1),
+            ),
+            Err(()) => {
+                return HttpResponse::for_request(req, 416)
+                    .header("Content-Range", &format!("bytes */{}", len))
+            }
+        };
+        let mut resp = resp
+            .header("Content-Type", content_type(&path))
+            .header("Accept-Ranges", "bytes");
+        if let Ok(modified) = metadata.modified() {
+            resp = resp.header("Last-Modified", &http_date(modified));
+        }
+        if len == 0 {
+            return resp;
+        }
+        if req.method == HttpMethod::Get {
+            self.file = Some(FileBody::new(file, first, last));
+        }
+        resp.sized_body(last - first + 1)
+    }
+
+    /// GET /live/<key>.flv as HTTP-FLV or WebSocket-FLV.
+    /// Returns the response when the request is refused,
+    /// otherwise the response head and FLV header are queued here.
+    fn start_viewer(
+        &mut self,
+        req: &HttpReq,
+        key: &str,
+        epoller: &mut Epoller,
+    ) -> Option<HttpResponse> {
+        let ws_accept = match upgrade_accept_key(req) {
+            Ok(ws_accept) => ws_accept,
+            Err(err) => {
+                println!("client:{:?} bad upgrade:{}", self.stream, err);
+                return Some(HttpResponse::for_request(req, 400).text_body("bad upgrade\n"));
+            }
+        };
+        let waker = match (epoller.remote(), epoller.token_of(self.as_raw_fd())) {
+            (Ok(remote), Some(token)) => ViewerWaker { remote, token },
+            _ => return Some(HttpResponse::for_request(req, 500)),
+        };
+        let limits = current(&self.config).viewer_limits();
+        let subscription = match Subscription::new(&self.registry, key, waker, limits) {
+            Ok(subscription) => subscription,
+            Err(err) => {
+                println!("client:{:?} play refused:{}", self.stream, err);
+                return Some(HttpResponse::for_request(req, 404).text_body("stream not found\n"));
+            }
+        };
+
+        let resp = match &ws_accept {
+            Some(accept) => HttpResponse::for_request(req, 101)
+                .header("Upgrade", "websocket")
+                .header("Connection", "Upgrade")
+                .header("Sec-WebSocket-Accept", accept),
+            None => HttpResponse::for_request(req, 200)
+                .header("Content-Type", "video/x-flv")
+                .header("Access-Control-Allow-Origin", "*")
+                .stream_body(),
+        };
+        if req.method == HttpMethod::Head {
+            return Some(resp);
+        }
+
+        let framing = match ws_accept {
+            Some(_) => ViewerFraming::WebSocket,
+            None if resp.chunked() => ViewerFraming::Chunked,
+            None => ViewerFraming::Raw,
+        };
+        println!(
+            "client:{:?} response {} play key:{} as {:?}",
+            self.stream,
+            resp.status(),
+            key,
+            framing
+        );
+        // a live response only ends with the stream, the connection is closed then
+        self.output.append(|out| resp.write_to(out));
+
+        let mut ws_parser = WsFrameParser::new();
+        ws_parser.feed(&self.parser.take_remaining());
+        let header = subscription.header().to_bytes();
+        self.viewer = Some(Viewer {
+            subscription,
+            framing,
+            ws_parser,
+            base_timestamp: None,
+        });
+        self.write_viewer_data(&header);
+        self.fill_viewer_output();
+        None
+    }
+
+    fn write_viewer_data(&mut self, data: &[u8]) {
+        let framing = match &self.viewer {
+            Some(viewer) => viewer.framing,
+            None => return,
+        };
+        match framing {
+            ViewerFraming::Chunked => self.output.append(|out| write_chunk(out, data)),
+            ViewerFraming::Raw => self.output.extend_from_slice(data),
+            ViewerFraming::WebSocket => self
+                .output
+                .append(|out| write_frame(out, OPCODE_BINARY, data)),
+        }
+    }
+
+    /// Queue one tag and its PreviousTagSize in the viewer's framing.
+    /// The header is rewritten with the timestamp rebased to the viewer's start,
+    /// the tag body is shared with the other viewers, not copied.
+    fn write_viewer_tag(&mut self, tag: Arc<RawTag>) {
+        let viewer = match &mut self.viewer {
+            Some(viewer) => viewer,
+            None => return,
+        };
+        let timestamp = match viewer.base_timestamp {
+            Some(base) => rebase(tag.timestamp, base),
+            None if is_header(&tag) => 0,
+            None => {
+                viewer.base_timestamp = Some(tag.timestamp);
+                0
+            }
+        };
+        let framing = viewer.framing;
+        let header = tag.header_with_timestamp(timestamp);
+        let prev_tag_size = (tag.data.len() as u32).to_be_bytes();
+        let len = tag.data.len() + prev_tag_size.len();
+        match framing {
+            ViewerFraming::Chunked => self.output.append(|out| {
+                out.extend_from_slice(format!("{:x}\r\n", len).as_bytes());
+                out.extend_from_slice(&header);
+            }),
+            ViewerFraming::Raw => self.output.extend_from_slice(&header),
+            ViewerFraming::WebSocket => self.output.append(|out| {
+                write_frame_header(out, OPCODE_BINARY, len);
+                out.extend_from_slice(&header);
+            }),
+        }
+        self.output.push_shared_from(tag, TAG_HEADER_LEN);
+        self.output.extend_from_slice(&prev_tag_size);
+        if framing == ViewerFraming::Chunked {
+            self.output.extend_from_slice(b"\r\n");
+        }
+    }
+
+    /// Move queued tags into output up to the watermark, and the trailer once
+    /// the stream ended or the viewer fell too far behind.
+    /// Returns true while tags are left in the queue.
+    fn fill_viewer_output(&mut self) -> bool {
+        let queue = match &self.viewer {
+            Some(viewer) => viewer.subscription.queue().clone(),
+            None => return false,
+        };
+        let mut queue = lock(&queue);
+        while !queue.overflowed && self.output.len() < VIEWER_OUTPUT_WATERMARK {
+            match queue.pop() {
+                Some(tag) => self.write_viewer_tag(tag),
+                None => break,
+            }
+        }
+
+        let end = queue.overflowed || (queue.ended && queue.is_empty());
+        if end && !self.close_after_flush {
+            if queue.overflowed {
+                println!(
+                    "client:{:?} too slow for the live stream, dropped frames:{}",
+                    self.stream, queue.dropped_frames
+                );
+            } else {
+                println!("client:{:?} live stream ended", self.stream);
+            }
+            match self.viewer.as_ref().map(|viewer| viewer.framing) {
+                Some(ViewerFraming::Chunked) => self.output.append(write_last_chunk),
+                Some(ViewerFraming::WebSocket) => self
+                    .output
+                    .append(|out| write_close_frame(out, CLOSE_GOING_AWAY)),
+                _ => (),
+            }
+            self.close_after_flush = true;
+        }
+        !queue.overflowed && !queue.is_empty()
+    }
+
+    /// input after a viewer started, only websocket control frames matter
+    fn on_viewer_input(&mut self, data: &[u8]) {
+        let viewer = match self.viewer.as_mut() {
+            Some(viewer) => viewer,
+            None => return,
+        };
+        if viewer.framing != ViewerFraming::WebSocket {
+            return;
+        }
+        viewer.ws_parser.feed(data);
+        loop {
+            let frame = match viewer.ws_parser.next_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(err) => {
+                    println!("client:{:?} websocket error:{}", self.stream, err);
+                    self.output
+                        .append(|out| write_close_frame(out, CLOSE_PROTOCOL_ERROR));
+                    self.close_after_flush = true;
+                    break;
+                }
+            };
+            match frame.opcode {
+                OPCODE_PING => self
+                    .output
+                    .append(|out| write_frame(out, OPCODE_PONG, &frame.payload)),
+                OPCODE_CLOSE => {
+                    // echo the status code back and close
+                    let payload = frame.payload.get(0..2).unwrap_or(&[]);
+                    self.output
+                        .append(|out| write_frame(out, OPCODE_CLOSE, payload));
+                    self.close_after_flush = true;
+                    break;
+                }
+                // players have nothing to say, data frames are dropped
+                _ => (),
+            }
+        }
+    }
+
+    /// POST/PUT /live/<key>.flv: the body is not buffered,
+    /// it is demuxed as it arrives and published under key
+    fn start_ingest(&mut self, req: &HttpReq, key: &str) -> Option<HttpResponse> {
+        let publisher = match Publisher::new(&self.registry, key) {
+            Ok(publisher) => publisher,
+            Err(err) => {
+                println!("client:{:?} publish refused:{}", self.stream, err);
+                // the body is not going to be read, so the connection can not be reused
+                return Some(
+                    HttpResponse::for_request(req, 409)
+                        .text_body("stream key is already being published\n")
+                        .close(),
+                );
+            }
         };
+
+        if req
+            .header("expect")
+            .is_some_and(|val| val.eq_ignore_ascii_case("100-continue"))
+        {
+            self.output
+                .append(|out| HttpResponse::for_request(req, 100).write_to(out));
+        }
+        self.ingest = Some(Ingest {
+            publisher,
+            demuxer: FlvDemuxer::new(),
+        });
+        None
+    }
+
+    fn on_ingest_data(&mut self, data: &[u8]) -> IoResult<()> {
+        let ingest = match self.ingest.as_mut() {
+            Some(ingest) => ingest,
+            None => return Ok(()),
+        };
+        ingest.demuxer.feed(data);
+        while let Some(event) = ingest.demuxer.next_event()? {
+            match event {
+                DemuxEvent::Header(header) => ingest.publisher.on_header(header),
+                DemuxEvent::Tag(tag) => ingest.publisher.on_tag(tag),
+            }
+        }
+        Ok(())
+    }
+
+    /// stop publishing, viewers get the end of stream
+    fn end_ingest(&mut self) -> bool {
+        match self.ingest.take() {
+            Some(ingest) => {
+                println!(
+                    "client:{:?} publish of key:{} finished",
+                    self.stream,
+                    ingest.publisher.key()
+                );
+                drop(ingest);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Shutdown: publishers are ended, their viewers get the end of stream
+    /// and close once it is sent. A request in progress still gets its
+    /// response, idle keep-alive connections close right away.
+    fn on_shutdown(&mut self, epoller: &mut Epoller) -> IoResult<()> {
+        self.draining = true;
+        if self.end_ingest() {
+            self.queue_response(HttpResponse::error(503));
+            return self.flush(epoller);
+        }
+        if self.file.is_some() {
+            // the file being sent is the last response
+            self.close_after_flush = true;
+        }
+        let idle = self.viewer.is_none()
+            && self.file.is_none()
+            && self.current_req.is_none()
+            && self.parser.is_idle()
+            && self.output.is_empty();
+        if idle {
+            return Err(my_error(format!(
+                "client:{:?} server shutting down, close",
+                self.stream
+            )));
+        }
+        Ok(())
+    }
+
+    fn queue_response(&mut self, resp: HttpResponse) {
+        // no new requests once the server is shutting down
+        let resp = if self.draining { resp.close() } else { resp };
+        println!("client:{:?} response {}", self.stream, resp.status());
+        self.output.append(|out| resp.write_to(out));
+        if !resp.keep_alive() {
+            self.close_after_flush = true;
+        }
+    }
+
+    /// Read until WouldBlock, or until read_budget bytes were taken in this wakeup
+    /// and the rest is left for the next round, then send what the input produced.
+    fn read_input(&mut self, epoller: &mut Epoller) -> IoResult<()> {
+        let budget = current(&self.config).read_budget;
+        let mut buf = [0; READ_CHUNK_SIZE];
+        let mut total = 0;
+        while total < budget {
+            let size = match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    // answers to what came before the EOF still go out
+                    self.flush(epoller)?;
+                    return Err(my_error(format!("client:{:?} EOF close", self.stream)));
+                }
+                Ok(size) => size,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return self.flush(epoller),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            total += size;
+            self.on_input(&buf[0..size], epoller)?;
+        }
+        // edge triggered fds are not reported again for what is left
+        epoller.read_again(self.as_raw_fd());
+        self.flush(epoller)
+    }
+
+    fn on_input(&mut self, data: &[u8], epoller: &mut Epoller) -> IoResult<()> {
+        if self.close_after_flush {
+            // the last response is on its way, ignore anything after it
+            return Ok(());
+        }
+        if self.viewer.is_some() {
+            self.on_viewer_input(data);
+            return Ok(());
+        }
+        self.parser.feed(data);
+        self.serve_requests(epoller)
+    }
+
+    /// Handle what the parser has, one read may carry several pipelined requests.
+    /// Requests behind a file response wait until the file is sent.
+    fn serve_requests(&mut self, epoller: &mut Epoller) -> IoResult<()> {
+        while !self.close_after_flush && self.viewer.is_none() && self.file.is_none() {
+            let event = match self.parser.next_event() {
+                Ok(Some(event)) => event,
+                Ok(None) => break,
+                Err(err) => {
+                    println!("client:{:?} bad request:{}", self.stream, err);
+                    self.queue_response(HttpResponse::error(400));
+                    break;
+                }
+            };
+            match event {
+                ParseEvent::Head(req) => {
+                    let ingest_key = match req.method {
+                        HttpMethod::Post | HttpMethod::Put if req.framing != BodyFraming::None => {
+                            live_stream_key(&req.path).map(str::to_owned)
+                        }
+                        _ => None,
+                    };
+                    if let Some(key) = ingest_key {
+                        if let Some(resp) = self.start_ingest(&req, &key) {
+                            self.queue_response(resp);
+                            break;
+                        }
+                    }
+                    self.current_req = Some(req);
+                }
+                ParseEvent::Body(data) if self.ingest.is_some() => {
+                    if let Err(err) = self.on_ingest_data(&data) {
+                        println!("client:{:?} ingest failed:{}", self.stream, err);
+                        self.end_ingest();
+                        self.queue_response(HttpResponse::error(400));
+                        break;
+                    }
+                }
+                ParseEvent::Body(data) => {
+                    let req = self
+                        .current_req
+                        .as_mut()
+                        .ok_or_else(|| my_error("http body without request"))?;
+                    if req.body.len() + data.len() > MAX_BUFFERED_BODY_SIZE {
+                        println!(
+                            "client:{:?} request body exceeds {} bytes",
+                            self.stream, MAX_BUFFERED_BODY_SIZE
+                        );
+                        self.queue_response(HttpResponse::error(413));
+                        break;
+                    }
+                    req.body.extend_from_slice(&data);
+                }
+                ParseEvent::End => match self.current_req.take() {
+                    Some(req) => {
+                        if self.end_ingest() {
+                            self.queue_response(
+                                HttpResponse::for_request(&req, 200)
+                                    .text_body("publish finished\n"),
+                            );
+                        } else {
+                            self.handle_request(req, epoller);
+                        }
+                    }
+                    None => return Err(my_error("http request end without request")),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// send as much of output, topped up from the viewer queue, and then the
+    /// file body as the socket takes and wait for writable only while something is left
+    fn flush(&mut self, epoller: &mut Epoller) -> IoResult<()> {
+        loop {
+            let more_queued = self.fill_viewer_output();
+            while !self.output.is_empty() {
+                match self.output.write_to(self.stream.as_raw_fd()) {
+                    Ok(size) => self.sent += size as u64,
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                    Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                    Err(err) => return Err(err),
+                }
+            }
+            if !more_queued || !self.output.is_empty() {
+                break;
+            }
+        }
+        if self.output.is_empty() {
+            if let Some(file) = &mut self.file {
+                let remaining = file.remaining();
+                let done = file.send_to(self.stream.as_raw_fd())?;
+                self.sent += remaining - file.remaining();
+                if done {
+                    self.file = None;
+                    // pipelined requests waited for the file
+                    self.serve_requests(epoller)?;
+                    return self.flush(epoller);
+                }
+            }
+        }
+
+        if self.output.is_empty() && self.file.is_none() && self.close_after_flush {
+            return Err(my_error(format!(
+                "client:{:?} response done, close",
+                self.stream
+            )));
+        }
+
+        let want_write = !self.output.is_empty() || self.file.is_some();
+        if self.want_write != want_write {
+            let interest = if want_write {
+                Interest::ReadWrite
+            } else {
+                Interest::Read
+            };
+            epoller.set_interest(self.as_raw_fd(), interest)?;
+            self.want_write = want_write;
+        }
+        Ok(())
     }
 }
 
@@ -114,12 +882,26 @@ impl RWHandle for HttpListener {
                         println!("http client:{:?} set non block failed:{}", s, nonblock_err);
                         continue;
                     }
+                    self.accepted += 1;
 
-                    if let Err((conn, wait_read_err)) = epoller.wait_read(HttpStream::new(s)) {
+                    let mut conn = HttpStream::new(s, self.registry.clone(), self.config.clone());
+                    let (request_timeout, trigger) = {
+                        let config = current(&self.config);
+                        let trigger = if config.edge_triggered {
+                            Trigger::Edge
+                        } else {
+                            Trigger::Level
+                        };
+                        (config.request_timeout, trigger)
+                    };
+                    let timer = epoller.add_timer(conn.as_raw_fd(), request_timeout);
+                    conn.idle_timer = Some(timer);
+                    if let Err((conn, wait_read_err)) = epoller.wait_read_with(conn, trigger) {
                         println!(
                             "wait_read for http client:{:?} failed:{}",
                             conn, wait_read_err
                         );
+                        epoller.cancel_timer(timer);
                         continue;
                     }
                 }
@@ -134,143 +916,116 @@ impl RWHandle for HttpListener {
     fn on_write(&mut self, _epoller: &mut Epoller) -> IoResult<()> {
         panic!("http listener should not on write")
     }
-}
 
-impl RWHandle for HttpStream {
-    fn on_read(&mut self, _epoller: &mut Epoller) -> IoResult<()> {
-        let mut buf: [u8; 4096] = [0; 4096];
-        let size = self.stream.read(&mut buf)?;
-        if size == 0 {
-            return Err(my_error(format!("client:{:?} EOF close", self)));
-        }
-        let req = HttpReq::parse(&buf[0..size])?;
-        println!("client asking for {}", req.path);
+    fn on_timer(&mut self, _epoller: &mut Epoller, _timer: TimerId) -> IoResult<()> {
+        self.print_stats();
         Ok(())
     }
 
-    fn on_write(&mut self, _epoller: &mut Epoller) -> IoResult<()> {
-        let raw_fd = self.as_raw_fd();
-        let ptr = &self.output_buf[0] as *const u8 as *const libc::c_void;
-        let sent_size = unsafe { libc::send(raw_fd, ptr, self.output_buf.len(), 0) };
-        if sent_size == -1 {
-            return Err(Error::last_os_error());
+    fn on_message(&mut self, epoller: &mut Epoller, msg: Box<dyn Any>) -> IoResult<()> {
+        if msg.is::<PrintStats>() {
+            self.print_stats();
+        } else if msg.is::<Shutdown>() {
+            println!("http listener {:?} stop accepting", self.listener);
+            self.print_stats();
+            epoller.close(self.as_raw_fd());
         }
-        self.output_buf.drain(0..sent_size as usize);
         Ok(())
     }
 }
 
-enum HttpRequestType {
-    GET,
-    POST,
-}
+impl RWHandle for HttpStream {
+    fn on_read(&mut self, epoller: &mut Epoller) -> IoResult<()> {
+        self.read_input(epoller)?;
+        self.rearm_idle_timer(epoller);
+        Ok(())
+    }
 
-#[allow(dead_code)]
-struct HttpReq<'a> {
-    req_type: HttpRequestType,
-    path: &'a str,
-    major_version: u32,
-    minor_version: u32,
-    addition_param: BTreeMap<&'a str, &'a str>,
-    ori_data: String,
-}
+    fn on_write(&mut self, epoller: &mut Epoller) -> IoResult<()> {
+        let sent = self.sent;
+        self.flush(epoller)?;
+        if self.sent > sent {
+            self.rearm_idle_timer(epoller);
+        }
+        Ok(())
+    }
 
-impl<'a> HttpReq<'a> {
-    fn parse(buffer: &'a [u8]) -> IoResult<Self> {
-        let ori_data = str::from_utf8(buffer)
-            .or_else(|err| Err(my_error(format!("u8 vec to string failed with {}", err))))?;
-        let req_type: HttpRequestType;
-        let path: &str;
-        let major_version: u32;
-        let minor_version: u32;
-        let mut param_map = BTreeMap::new();
-
-        // parse line by line
-        // but first line is special
-        let mut lines = ori_data.lines();
-        match lines.next() {
-            Some(first_line) => {
-                let mut parts = first_line.split_ascii_whitespace();
-                match parts.next() {
-                    Some(req_type_str) => match req_type_str.as_ref() {
-                        "GET" => req_type = HttpRequestType::GET,
-                        "POST" => req_type = HttpRequestType::POST,
-                        _ => {
-                            return Err(my_error(format!(
-                                "http req has unknow request type:{}",
-                                req_type_str
-                            )))
-                        }
-                    },
-                    None => return Err(my_error("http req missing request type field")),
-                }
+    fn on_timer(&mut self, epoller: &mut Epoller, timer: TimerId) -> IoResult<()> {
+        if self.idle_timer != Some(timer) {
+            return Ok(());
+        }
+        self.idle_timer = None;
 
-                match parts.next() {
-                    Some(path_str) => path = path_str,
-                    None => return Err(my_error("http req has no path field")),
-                }
+        if self.ingest.is_some() {
+            return Err(my_error(format!(
+                "client:{:?} publisher sent nothing for {:?}",
+                self.stream,
+                current(&self.config).ingest_timeout
+            )));
+        }
+        if self.viewer.is_some() && self.output.is_empty() && !self.close_after_flush {
+            // nothing to send is not the viewer's fault
+            self.rearm_idle_timer(epoller);
+            return Ok(());
+        }
+        if self.close_after_flush || self.viewer.is_some() || self.file.is_some() {
+            return Err(my_error(format!(
+                "client:{:?} not reading its response, close",
+                self.stream
+            )));
+        }
+        if self.current_req.is_some() || !self.parser.is_idle() {
+            println!("client:{:?} request timeout", self.stream);
+            self.queue_response(HttpResponse::error(408));
+            self.rearm_idle_timer(epoller);
+            return self.flush(epoller);
+        }
+        Err(my_error(format!(
+            "client:{:?} idle for {:?}, close",
+            self.stream,
+            current(&self.config).keep_alive_timeout
+        )))
+    }
 
-                match parts.next() {
-                    Some(version_str) => {
-                        if version_str.len() < 5 {
-                            return Err(my_error(format!(
-                                "http req version parts not valid:{}",
-                                version_str
-                            )));
-                        }
-                        if &version_str[0..5] == "HTTP/" {
-                            let mut num_strs = version_str[5..].split('.');
-                            let get_num = |iter: Option<&str>| match iter {
-                                Some(num_str) => num_str.parse::<u32>().or_else(|err| {
-                                    Err(my_error(format!(
-                                        "http req parse num:{} failed with:{}",
-                                        num_str, err
-                                    )))
-                                }),
-                                None => Err(my_error("http req missing major version number")),
-                            };
-                            major_version = get_num(num_strs.next())?;
-                            minor_version = get_num(num_strs.next())?;
-                            let rest = num_strs.collect::<Vec<&str>>().join(".");
-                            if rest.len() > 0 {
-                                println!("http req ignore strings after minor_version: {}", rest);
-                            }
-                        } else {
-                            return Err(my_error(format!(
-                                "http req version field invalid:{}",
-                                version_str
-                            )));
-                        }
-                    }
-                    None => return Err(my_error("http req has no version field")),
-                }
+    fn on_message(&mut self, epoller: &mut Epoller, msg: Box<dyn Any>) -> IoResult<()> {
+        if msg.is::<ViewerWakeup>() {
+            // viewers woken in the same round go out in one send batch
+            self.fill_viewer_output();
+            if !self.want_write && !self.output.is_empty() {
+                epoller.send_batched(self.as_raw_fd());
             }
-            None => return Err(my_error("http req missing first line")),
+            return Ok(());
+        }
+        if msg.is::<Shutdown>() {
+            return self.on_shutdown(epoller);
         }
+        Ok(())
+    }
 
-        for line in lines {
-            if line.len() == 0 {
-                break;
-            }
+    fn batched_output(&self) -> Vec<IoSlice<'_>> {
+        self.output.io_slices()
+    }
 
-            match line.split_once(':') {
-                Some((name, value)) => {
-                    param_map.insert(name, value);
-                    ()
-                }
-                None => println!("unknow line without ':', ignored: {}", line),
+    fn on_batch_sent(&mut self, epoller: &mut Epoller, result: IoResult<usize>) -> IoResult<()> {
+        let sent = self.sent;
+        match result {
+            Ok(sent_size) => {
+                self.output.consume(sent_size);
+                self.sent += sent_size as u64;
             }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => (),
+            Err(err) => return Err(err),
         }
+        // the rest is sent or waits for writable as usual
+        self.flush(epoller)?;
+        if self.sent > sent {
+            self.rearm_idle_timer(epoller);
+        }
+        Ok(())
+    }
 
-        // TODO body
-
-        return Ok(Self {
-            req_type: req_type,
-            path: path,
-            major_version: major_version,
-            minor_version: minor_version,
-            addition_param: param_map,
-            ori_data: ori_data.to_owned(),
-        });
+    fn on_close(&mut self, _epoller: &mut Epoller) {
+        // a publish cut short by the connection going away
+        self.end_ingest();
     }
 }
@@ -0,0 +1,558 @@
+use crate::my_error::my_error;
+use std::fmt;
+use std::io::Result as IoResult;
+use std::str;
+
+/// max bytes of request line + headers before we give up on a client
+pub const MAX_HEADER_SIZE: usize = 8 * 1024;
+/// max number of header lines in one request
+pub const MAX_HEADER_COUNT: usize = 100;
+/// max length of a chunk size line (size + extensions)
+const MAX_CHUNK_LINE_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpMethod {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+}
+
+impl HttpMethod {
+    fn parse(method_str: &str) -> IoResult<Self> {
+        match method_str {
+            "GET" => Ok(Self::Get),
+            "HEAD" => Ok(Self::Head),
+            "POST" => Ok(Self::Post),
+            "PUT" => Ok(Self::Put),
+            "DELETE" => Ok(Self::Delete),
+            "OPTIONS" => Ok(Self::Options),
+            _ => Err(my_error(format!(
+                "http req has unknow request type:{}",
+                method_str
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for HttpMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Get => "GET",
+            Self::Head => "HEAD",
+            Self::Post => "POST",
+            Self::Put => "PUT",
+            Self::Delete => "DELETE",
+            Self::Options => "OPTIONS",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// How the body of a request is delimited on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyFraming {
+    None,
+    ContentLength(usize),
+    Chunked,
+}
+
+/// Request line and headers of one http request.
+/// Header names keep the case the client sent, lookups ignore it.
+#[derive(Debug)]
+pub struct HttpReq {
+    pub method: HttpMethod,
+    pub path: String,
+    pub query: Option<String>,
+    pub major_version: u32,
+    pub minor_version: u32,
+    pub headers: Vec<(String, String)>,
+    pub framing: BodyFraming,
+    pub body: Vec<u8>,
+}
+
+impl HttpReq {
+    /// first header value with the given name, case insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, val)| val.as_str())
+    }
+
+    fn parse_head(head: &[u8]) -> IoResult<Self> {
+        let head = str::from_utf8(head)
+            .map_err(|err| my_error(format!("u8 vec to string failed with {}", err)))?;
+
+        // parse line by line
+        // but first line is special
+        let mut lines = head
+            .split('\n')
+            .map(|line| line.strip_suffix('\r').unwrap_or(line));
+        let first_line = match lines.next() {
+            Some(line) if !line.is_empty() => line,
+            _ => return Err(my_error("http req missing first line")),
+        };
+
+        let mut parts = first_line.split(' ');
+        let method = match parts.next() {
+            Some(method_str) => HttpMethod::parse(method_str)?,
+            None => return Err(my_error("http req missing request type field")),
+        };
+
+        let target = match parts.next() {
+            Some(target) if !target.is_empty() => target,
+            _ => return Err(my_error("http req has no path field")),
+        };
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path.to_owned(), Some(query.to_owned())),
+            None => (target.to_owned(), None),
+        };
+
+        let (major_version, minor_version) = match parts.next() {
+            Some(version_str) => Self::parse_version(version_str)?,
+            None => return Err(my_error("http req has no version field")),
+        };
+        if parts.next().is_some() {
+            return Err(my_error(format!(
+                "http req first line has extra fields:{}",
+                first_line
+            )));
+        }
+
+        let mut headers = Vec::new();
+        for line in lines {
+            if line.is_empty() {
+                break;
+            }
+            if line.starts_with(' ') || line.starts_with('\t') {
+                return Err(my_error(
+                    "http req obsolete header line folding not allowed",
+                ));
+            }
+            match line.split_once(':') {
+                Some((name, value)) => {
+                    if name.is_empty() || name.contains(|c: char| c.is_ascii_whitespace()) {
+                        return Err(my_error(format!("http req invalid header name:{}", name)));
+                    }
+                    if headers.len() >= MAX_HEADER_COUNT {
+                        return Err(my_error(format!(
+                            "http req too many headers, limit {}",
+                            MAX_HEADER_COUNT
+                        )));
+                    }
+                    headers.push((name.to_owned(), value.trim().to_owned()));
+                }
+                None => println!("unknow line without ':', ignored: {}", line),
+            }
+        }
+
+        let mut req = Self {
+            method,
+            path,
+            query,
+            major_version,
+            minor_version,
+            headers,
+            framing: BodyFraming::None,
+            body: Vec::new(),
+        };
+        req.framing = req.parse_framing()?;
+        Ok(req)
+    }
+
+    fn parse_version(version_str: &str) -> IoResult<(u32, u32)> {
+        let num_str = version_str
+            .strip_prefix("HTTP/")
+            .ok_or_else(|| my_error(format!("http req version field invalid:{}", version_str)))?;
+        let (major_str, minor_str) = num_str
+            .split_once('.')
+            .ok_or_else(|| my_error(format!("http req version parts not valid:{}", version_str)))?;
+        let get_num = |num_str: &str| {
+            num_str.parse::<u32>().map_err(|err| {
+                my_error(format!(
+                    "http req parse num:{} failed with:{}",
+                    num_str, err
+                ))
+            })
+        };
+        let version = (get_num(major_str)?, get_num(minor_str)?);
+        if version.0 != 1 {
+            return Err(my_error(format!(
+                "http req version not supported:{}",
+                version_str
+            )));
+        }
+        Ok(version)
+    }
+
+    fn parse_framing(&self) -> IoResult<BodyFraming> {
+        if let Some(encoding) = self.header("transfer-encoding") {
+            if self.header("content-length").is_some() {
+                return Err(my_error(
+                    "http req has both transfer-encoding and content-length",
+                ));
+            }
+            // chunked must be the final coding, anything else we can not decode
+            if !encoding.trim().eq_ignore_ascii_case("chunked") {
+                return Err(my_error(format!(
+                    "http req transfer-encoding not supported:{}",
+                    encoding
+                )));
+            }
+            return Ok(BodyFraming::Chunked);
+        }
+
+        let mut content_length = None;
+        for (_, val) in self
+            .headers
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case("content-length"))
+        {
+            let len = val.parse::<usize>().map_err(|err| {
+                my_error(format!("http req content-length:{} invalid:{}", val, err))
+            })?;
+            if content_length.is_some_and(|prev| prev != len) {
+                return Err(my_error("http req has conflicting content-length"));
+            }
+            content_length = Some(len);
+        }
+
+        Ok(match content_length {
+            Some(0) | None => BodyFraming::None,
+            Some(len) => BodyFraming::ContentLength(len),
+        })
+    }
+}
+
+/// What the parser produced from the bytes fed so far
+#[derive(Debug)]
+pub enum ParseEvent {
+    /// request line and headers are complete
+    Head(HttpReq),
+    /// a piece of the decoded body
+    Body(Vec<u8>),
+    /// the current request is complete, next bytes belong to the next one
+    End,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ParseState {
+    Head,
+    Fixed(usize),
+    ChunkSize,
+    ChunkData(usize),
+    ChunkDataEnd,
+    Trailer,
+    Done,
+}
+
+/// Incremental http/1.x request parser.
+/// Bytes are fed as they arrive from the socket, complete pieces are
+/// pulled out with next_event. Pipelined requests come out one after another.
+#[derive(Debug)]
+pub struct HttpReqParser {
+    buffer: Vec<u8>,
+    state: ParseState,
+    // how far the buffer was already searched for the end of head
+    scanned: usize,
+}
+
+impl HttpReqParser {
+    pub fn new() -> Self {
+        Self {
+            buffer: Vec::new(),
+            state: ParseState::Head,
+            scanned: 0,
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// nothing of a next request received yet
+    pub fn is_idle(&self) -> bool {
+        self.state == ParseState::Head && self.buffer.is_empty()
+    }
+
+    /// bytes after the last parsed request, once the connection switched protocol
+    pub fn take_remaining(&mut self) -> Vec<u8> {
+        self.scanned = 0;
+        std::mem::take(&mut self.buffer)
+    }
+
+    /// Ok(None) means more data is needed
+    pub fn next_event(&mut self) -> IoResult<Option<ParseEvent>> {
+        loop {
+            match self.state {
+                ParseState::Head => return self.parse_head(),
+                ParseState::Fixed(0) => {
+                    self.state = ParseState::Done;
+                }
+                ParseState::Fixed(rest) => {
+                    if self.buffer.is_empty() {
+                        return Ok(None);
+                    }
+                    let size = rest.min(self.buffer.len());
+                    self.state = ParseState::Fixed(rest - size);
+                    return Ok(Some(ParseEvent::Body(self.take(size))));
+                }
+                ParseState::ChunkSize => match self.take_line(MAX_CHUNK_LINE_SIZE)? {
+                    Some(line) => {
+                        let size = Self::parse_chunk_size(&line)?;
+                        self.state = if size == 0 {
+                            ParseState::Trailer
+                        } else {
+                            ParseState::ChunkData(size)
+                        };
+                    }
+                    None => return Ok(None),
+                },
+                ParseState::ChunkData(rest) => {
+                    if self.buffer.is_empty() {
+                        return Ok(None);
+                    }
+                    let size = rest.min(self.buffer.len());
+                    self.state = if size == rest {
+                        ParseState::ChunkDataEnd
+                    } else {
+                        ParseState::ChunkData(rest - size)
+                    };
+                    return Ok(Some(ParseEvent::Body(self.take(size))));
+                }
+                ParseState::ChunkDataEnd => match self.take_line(2)? {
+                    Some(line) if line.is_empty() => self.state = ParseState::ChunkSize,
+                    Some(_) => return Err(my_error("http req chunk data not ended by CRLF")),
+                    None => return Ok(None),
+                },
+                // trailer fields are read and dropped
+                ParseState::Trailer => match self.take_line(MAX_HEADER_SIZE)? {
+                    Some(line) if line.is_empty() => self.state = ParseState::Done,
+                    Some(_) => (),
+                    None => return Ok(None),
+                },
+                ParseState::Done => {
+                    self.state = ParseState::Head;
+                    return Ok(Some(ParseEvent::End));
+                }
+            }
+        }
+    }
+
+    fn parse_head(&mut self) -> IoResult<Option<ParseEvent>> {
+        // tolerate empty lines before a request line
+        while self.buffer.starts_with(b"\r\n") || self.buffer.starts_with(b"\n") {
+            let size = if self.buffer[0] == b'\r' { 2 } else { 1 };
+            self.buffer.drain(0..size);
+        }
+
+        let head_len = match Self::find_head_end(&self.buffer, self.scanned) {
+            Some(len) => len,
+            None => {
+                if self.buffer.len() > MAX_HEADER_SIZE {
+                    return Err(my_error(format!(
+                        "http req header exceeds {} bytes",
+                        MAX_HEADER_SIZE
+                    )));
+                }
+                // the terminator may straddle the next read
+                self.scanned = self.buffer.len().saturating_sub(3);
+                return Ok(None);
+            }
+        };
+        if head_len > MAX_HEADER_SIZE {
+            return Err(my_error(format!(
+                "http req header exceeds {} bytes",
+                MAX_HEADER_SIZE
+            )));
+        }
+
+        let head = self.take(head_len);
+        self.scanned = 0;
+        let req = HttpReq::parse_head(&head)?;
+        self.state = match req.framing {
+            BodyFraming::None => ParseState::Done,
+            BodyFraming::ContentLength(len) => ParseState::Fixed(len),
+            BodyFraming::Chunked => ParseState::ChunkSize,
+        };
+        Ok(Some(ParseEvent::Head(req)))
+    }
+
+    /// length of head including the empty line, accepts bare LF too
+    fn find_head_end(buffer: &[u8], from: usize) -> Option<usize> {
+        let mut pos = from;
+        while pos < buffer.len() {
+            if buffer[pos] == b'\n' {
+                match &buffer[pos + 1..] {
+                    [b'\n', ..] => return Some(pos + 2),
+                    [b'\r', b'\n', ..] => return Some(pos + 3),
+                    _ => (),
+                }
+            }
+            pos += 1;
+        }
+        None
+    }
+
+    fn take(&mut self, size: usize) -> Vec<u8> {
+        let rest = self.buffer.split_off(size);
+        std::mem::replace(&mut self.buffer, rest)
+    }
+
+    /// pop one line without its line ending
+    fn take_line(&mut self, limit: usize) -> IoResult<Option<String>> {
+        match self.buffer.iter().position(|byte| *byte == b'\n') {
+            Some(pos) => {
+                let mut line = self.take(pos + 1);
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                String::from_utf8(line)
+                    .map(Some)
+                    .map_err(|err| my_error(format!("http req line not utf8:{}", err)))
+            }
+            None if self.buffer.len() > limit => {
+                Err(my_error(format!("http req line exceeds {} bytes", limit)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn parse_chunk_size(line: &str) -> IoResult<usize> {
+        // chunk extensions after ';' are ignored
+        let size_str = line.split(';').next().unwrap_or("").trim();
+        if size_str.is_empty() || size_str.len() > 15 {
+            return Err(my_error(format!("http req chunk size invalid:{}", line)));
+        }
+        usize::from_str_radix(size_str, 16)
+            .map_err(|err| my_error(format!("http req chunk size:{} invalid:{}", line, err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(parser: &mut HttpReqParser) -> Vec<ParseEvent> {
+        let mut events = Vec::new();
+        while let Some(event) = parser.next_event().unwrap() {
+            events.push(event);
+        }
+        events
+    }
+
+    fn collect_body(events: &[ParseEvent]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for event in events {
+            if let ParseEvent::Body(data) = event {
+                body.extend_from_slice(data);
+            }
+        }
+        body
+    }
+
+    #[test]
+    fn test_parse_across_reads() {
+        let raw = b"GET /live/test.flv?token=1 HTTP/1.1\r\nHost: a\r\nX-Some:  val \r\n\r\n";
+        let mut parser = HttpReqParser::new();
+        for byte in raw.iter() {
+            parser.feed(&[*byte]);
+            if parser.buffer.len() < raw.len() {
+                // head is not complete until the last byte
+                if let Some(ParseEvent::Head(_)) = parser.next_event().unwrap() {
+                    panic!("head parsed too early");
+                }
+            }
+        }
+        let events = parse_all(&mut parser);
+        assert_eq!(events.len(), 2);
+        match &events[0] {
+            ParseEvent::Head(req) => {
+                assert_eq!(req.method, HttpMethod::Get);
+                assert_eq!(req.path, "/live/test.flv");
+                assert_eq!(req.query.as_deref(), Some("token=1"));
+                assert_eq!(req.minor_version, 1);
+                assert_eq!(req.header("HOST"), Some("a"));
+                assert_eq!(req.header("x-some"), Some("val"));
+            }
+            _ => panic!("expect head"),
+        }
+        assert!(matches!(events[1], ParseEvent::End));
+    }
+
+    #[test]
+    fn test_pipelined_with_content_length() {
+        let raw = b"PUT /a HTTP/1.1\r\ncontent-length: 5\r\n\r\nhelloHEAD /b HTTP/1.0\r\n\r\n";
+        let mut parser = HttpReqParser::new();
+        parser.feed(raw);
+        let events = parse_all(&mut parser);
+        assert_eq!(events.len(), 5);
+        assert_eq!(collect_body(&events[0..3]), b"hello");
+        match &events[3] {
+            ParseEvent::Head(req) => {
+                assert_eq!(req.method, HttpMethod::Head);
+                assert_eq!(req.minor_version, 0);
+            }
+            _ => panic!("expect second head"),
+        }
+        assert!(matches!(events[4], ParseEvent::End));
+        assert_eq!(parser.buffer.len(), 0);
+    }
+
+    #[test]
+    fn test_chunked_body() {
+        let raw = b"POST /live/a.flv HTTP/1.1\r\nTransfer-Encoding: Chunked\r\n\r\n\
+                    3;ext=1\r\nFLV\r\n2\r\n\x01\x05\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        let mut parser = HttpReqParser::new();
+        let mut events = Vec::new();
+        for piece in raw.chunks(4) {
+            parser.feed(piece);
+            events.extend(parse_all(&mut parser));
+        }
+        assert!(matches!(&events[0], ParseEvent::Head(req) if req.framing == BodyFraming::Chunked));
+        assert_eq!(collect_body(&events), b"FLV\x01\x05");
+        assert!(matches!(events.last(), Some(ParseEvent::End)));
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        let cases: &[&[u8]] = &[
+            b"BREW /pot HTTP/1.1\r\n\r\n",
+            b"GET /a HTTP/2.0\r\n\r\n",
+            b"GET /a HTTP/1.1\r\nBad Name: 1\r\n\r\n",
+            b"POST /a HTTP/1.1\r\nContent-Length: 1\r\nTransfer-Encoding: chunked\r\n\r\n",
+            b"POST /a HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n",
+            b"POST /a HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n",
+            b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+        ];
+        for raw in cases {
+            let mut parser = HttpReqParser::new();
+            parser.feed(raw);
+            let mut result = Ok(None);
+            for _ in 0..3 {
+                result = parser.next_event();
+                if result.is_err() {
+                    break;
+                }
+            }
+            assert!(result.is_err(), "{}", String::from_utf8_lossy(raw));
+        }
+    }
+
+    #[test]
+    fn test_header_size_limit() {
+        let mut parser = HttpReqParser::new();
+        parser.feed(b"GET / HTTP/1.1\r\n");
+        while parser.buffer.len() <= MAX_HEADER_SIZE {
+            parser.feed(b"X-Filler: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n");
+            if parser.next_event().is_err() {
+                return;
+            }
+        }
+        assert!(parser.next_event().is_err());
+    }
+}
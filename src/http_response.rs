@@ -0,0 +1,348 @@
+use crate::http_request::{HttpMethod, HttpReq};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SERVER_NAME: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        416 => "Range Not Satisfiable",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+/// Whether the connection stays open after this request.
+/// HTTP/1.1 is persistent unless the client says close,
+/// HTTP/1.0 only when the client explicitly asks for keep-alive.
+pub fn request_keep_alive(req: &HttpReq) -> bool {
+    let has_token = |token: &str| {
+        req.headers
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case("connection"))
+            .flat_map(|(_, val)| val.split(','))
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    };
+    if req.minor_version == 0 {
+        has_token("keep-alive")
+    } else {
+        !has_token("close")
+    }
+}
+
+/// Where the body of a response comes from
+#[derive(Debug)]
+pub enum HttpBody {
+    Empty,
+    Bytes(Vec<u8>),
+    /// length unknown, data follows with write_chunk until write_last_chunk.
+    /// HTTP/1.0 peers get the raw data and the end is marked by closing.
+    Stream,
+    /// length known, the caller sends the data after the head, e.g. from a file
+    Sized(u64),
+}
+
+/// Status line, headers and body of one http response.
+/// Date, Server, Connection and the framing headers are filled in by write_to.
+#[derive(Debug)]
+pub struct HttpResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: HttpBody,
+    minor_version: u32,
+    keep_alive: bool,
+    head_only: bool,
+}
+
+impl HttpResponse {
+    /// a response to req, taking over its version and keep-alive wish
+    pub fn for_request(req: &HttpReq, status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: HttpBody::Empty,
+            minor_version: req.minor_version,
+            keep_alive: request_keep_alive(req),
+            head_only: req.method == HttpMethod::Head,
+        }
+    }
+
+    /// a response when we could not even parse the request, always closes
+    pub fn error(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: HttpBody::Empty,
+            minor_version: 1,
+            keep_alive: false,
+            head_only: false,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn body(mut self, data: Vec<u8>) -> Self {
+        self.body = HttpBody::Bytes(data);
+        self
+    }
+
+    pub fn text_body(self, text: &str) -> Self {
+        self.header("Content-Type", "text/plain; charset=utf-8")
+            .body(text.as_bytes().to_vec())
+    }
+
+    pub fn sized_body(mut self, len: u64) -> Self {
+        self.body = HttpBody::Sized(len);
+        self
+    }
+
+    pub fn stream_body(mut self) -> Self {
+        self.body = HttpBody::Stream;
+        self
+    }
+
+    pub fn close(mut self) -> Self {
+        self.keep_alive = false;
+        self
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// whether the connection may serve another request after this response
+    pub fn keep_alive(&self) -> bool {
+        match self.body {
+            HttpBody::Stream => self.keep_alive && self.chunked(),
+            _ => self.keep_alive,
+        }
+    }
+
+    /// whether a streamed body is sent with chunked transfer encoding
+    pub fn chunked(&self) -> bool {
+        matches!(self.body, HttpBody::Stream) && self.minor_version >= 1 && !self.head_only
+    }
+
+    fn has_header(&self, name: &str) -> bool {
+        self.headers
+            .iter()
+            .any(|(key, _)| key.eq_ignore_ascii_case(name))
+    }
+
+    /// serialize status line, headers and a fixed body into out
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        let mut head = format!(
+            "HTTP/1.{} {} {}\r\n",
+            self.minor_version,
+            self.status,
+            reason_phrase(self.status)
+        );
+
+        let mut push_header = |name: &str, value: &str| {
+            head.push_str(name);
+            head.push_str(": ");
+            head.push_str(value);
+            head.push_str("\r\n");
+        };
+
+        push_header("Date", &http_date(SystemTime::now()));
+        push_header("Server", SERVER_NAME);
+        if self.status >= 200 && !self.has_header("cache-control") {
+            push_header("Cache-Control", "no-cache");
+        }
+        for (name, value) in &self.headers {
+            push_header(name, value);
+        }
+
+        // 1xx and 204 never carry a body or framing headers
+        let bodiless = self.status < 200 || self.status == 204;
+        if !bodiless {
+            match &self.body {
+                HttpBody::Empty => push_header("Content-Length", "0"),
+                HttpBody::Bytes(data) => push_header("Content-Length", &data.len().to_string()),
+                HttpBody::Stream if self.chunked() => push_header("Transfer-Encoding", "chunked"),
+                HttpBody::Stream => (),
+                HttpBody::Sized(len) => push_header("Content-Length", &len.to_string()),
+            }
+        }
+
+        // interim responses leave the connection alone
+        if self.status >= 200 {
+            match (self.keep_alive(), self.minor_version) {
+                (true, 0) => push_header("Connection", "keep-alive"),
+                (false, _) => push_header("Connection", "close"),
+                _ => (),
+            }
+        }
+
+        head.push_str("\r\n");
+        out.extend_from_slice(head.as_bytes());
+
+        if let HttpBody::Bytes(data) = &self.body {
+            if !self.head_only && !bodiless {
+                out.extend_from_slice(data);
+            }
+        }
+    }
+}
+
+/// append one chunk of a chunked body, empty data is skipped
+/// because a zero sized chunk would end the body
+pub fn write_chunk(out: &mut Vec<u8>, data: &[u8]) {
+    if data.is_empty() {
+        return;
+    }
+    out.extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+    out.extend_from_slice(data);
+    out.extend_from_slice(b"\r\n");
+}
+
+pub fn write_last_chunk(out: &mut Vec<u8>) {
+    out.extend_from_slice(b"0\r\n\r\n");
+}
+
+/// IMF-fixdate, e.g. Sun, 06 Nov 1994 08:49:37 GMT
+pub fn http_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let days = (secs / 86400) as i64;
+    let secs_of_day = secs % 86400;
+
+    // days since epoch to civil date, Howard Hinnant's algorithm
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_request::{HttpReqParser, ParseEvent};
+    use std::time::Duration;
+
+    fn parse_req(raw: &[u8]) -> HttpReq {
+        let mut parser = HttpReqParser::new();
+        parser.feed(raw);
+        match parser.next_event().unwrap() {
+            Some(ParseEvent::Head(req)) => req,
+            _ => panic!("expect head"),
+        }
+    }
+
+    #[test]
+    fn test_http_date() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(1709210096);
+        assert_eq!(http_date(leap_day), "Thu, 29 Feb 2024 12:34:56 GMT");
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        assert!(request_keep_alive(&parse_req(b"GET / HTTP/1.1\r\n\r\n")));
+        assert!(!request_keep_alive(&parse_req(
+            b"GET / HTTP/1.1\r\nConnection: Upgrade, Close\r\n\r\n"
+        )));
+        assert!(!request_keep_alive(&parse_req(b"GET / HTTP/1.0\r\n\r\n")));
+        assert!(request_keep_alive(&parse_req(
+            b"GET / HTTP/1.0\r\nconnection: keep-alive\r\n\r\n"
+        )));
+    }
+
+    #[test]
+    fn test_write_response() {
+        let req = parse_req(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n");
+        let mut out = Vec::new();
+        HttpResponse::for_request(&req, 200)
+            .text_body("hi")
+            .write_to(&mut out);
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(text.contains("\r\nContent-Length: 2\r\n"));
+        assert!(text.contains("\r\nConnection: keep-alive\r\n"));
+        assert!(text.contains("\r\nCache-Control: no-cache\r\n"));
+        assert!(text.ends_with("\r\n\r\nhi"));
+
+        let req = parse_req(b"HEAD / HTTP/1.1\r\n\r\n");
+        let mut out = Vec::new();
+        HttpResponse::for_request(&req, 200)
+            .text_body("hi")
+            .write_to(&mut out);
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("\r\nContent-Length: 2\r\n"));
+        assert!(text.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_stream_response() {
+        let req = parse_req(b"GET /live/a.flv HTTP/1.1\r\n\r\n");
+        let resp = HttpResponse::for_request(&req, 200).stream_body();
+        assert!(resp.chunked());
+        assert!(resp.keep_alive());
+        let mut out = Vec::new();
+        resp.write_to(&mut out);
+        write_chunk(&mut out, b"FLV");
+        write_chunk(&mut out, b"");
+        write_last_chunk(&mut out);
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("\r\nTransfer-Encoding: chunked\r\n"));
+        assert!(!text.contains("Connection:"));
+        assert!(text.ends_with("\r\n\r\n3\r\nFLV\r\n0\r\n\r\n"));
+
+        // 1.0 peers can not do chunked, body ends with close
+        let req = parse_req(b"GET /live/a.flv HTTP/1.0\r\nConnection: keep-alive\r\n\r\n");
+        let resp = HttpResponse::for_request(&req, 200).stream_body();
+        assert!(!resp.chunked());
+        assert!(!resp.keep_alive());
+        let mut out = Vec::new();
+        resp.write_to(&mut out);
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("\r\nConnection: close\r\n"));
+        assert!(!text.contains("Transfer-Encoding"));
+    }
+}
@@ -0,0 +1,635 @@
+use crate::flv_demuxer::{FlvHeader, RawTag, TAG_TYPE_AUDIO, TAG_TYPE_SCRIPT, TAG_TYPE_VIDEO};
+use crate::my_error::my_error;
+use crate::remote::Remote;
+use crate::slab::Token;
+use crate::timestamp::TimestampNormalizer;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Result as IoResult;
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// tags of the current GOP kept for late joiners,
+/// capped so a stream without keyframes can not grow forever
+const MAX_GOP_CACHE_TAGS: usize = 2048;
+
+/// Stream key from a live url path, /live/<key>.flv
+pub fn live_stream_key(path: &str) -> Option<&str> {
+    let key = path.strip_prefix("/live/")?.strip_suffix(".flv")?;
+    if key.is_empty() || key.contains('/') {
+        return None;
+    }
+    Some(key)
+}
+
+/// lock ignoring poisoning, a panicked worker must not take the streams of the others down
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Message sent to a viewer's handle when its queue got tags after being empty,
+/// or when the stream ended
+#[derive(Debug)]
+pub struct ViewerWakeup;
+
+/// How a publisher reaches a viewer handle, which may live on another worker thread
+#[derive(Debug, Clone)]
+pub struct ViewerWaker {
+    pub remote: Remote,
+    pub token: Token,
+}
+
+impl ViewerWaker {
+    fn wake(&self) {
+        if let Err(err) = self.remote.send(self.token, Box::new(ViewerWakeup)) {
+            println!("wake viewer {:?} failed:{}", self.token, err);
+        }
+    }
+}
+
+/// What to do when a viewer's queue goes over its limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowViewerPolicy {
+    /// drop queued video inter frames and further ones until the next keyframe,
+    /// skip to the next GOP when that is not enough
+    DropFrames,
+    /// drop queued audio and video and continue at the next keyframe
+    SkipGop,
+    /// end the viewer's response
+    Disconnect,
+}
+
+impl SlowViewerPolicy {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "drop_frames" => Some(SlowViewerPolicy::DropFrames),
+            "skip_gop" => Some(SlowViewerPolicy::SkipGop),
+            "disconnect" => Some(SlowViewerPolicy::Disconnect),
+            _ => None,
+        }
+    }
+}
+
+/// How far one viewer may fall behind the publisher
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ViewerLimits {
+    pub max_bytes: usize,
+    /// between the first and the last queued audio/video tag
+    pub max_duration_ms: u32,
+    pub policy: SlowViewerPolicy,
+}
+
+/// metadata and sequence headers, a viewer can not decode without them
+pub fn is_header(tag: &RawTag) -> bool {
+    tag.tag_type == TAG_TYPE_SCRIPT || tag.is_sequence_header()
+}
+
+fn is_inter_frame(tag: &RawTag) -> bool {
+    tag.tag_type == TAG_TYPE_VIDEO && !tag.is_video_keyframe()
+}
+
+fn is_gop_start(tag: &RawTag) -> bool {
+    tag.is_video_keyframe() && !tag.is_sequence_header()
+}
+
+/// Tags waiting to be sent to one viewer,
+/// filled by the publisher and drained by the viewer connection.
+/// Tags over the limits are dropped here as the policy says.
+#[derive(Debug)]
+pub struct ViewerQueue {
+    waker: ViewerWaker,
+    limits: ViewerLimits,
+    tags: VecDeque<Arc<RawTag>>,
+    bytes: usize,
+    // tags the policy drops are not queued until the next keyframe
+    skip_to_keyframe: bool,
+    /// the publisher is gone, nothing more will be queued
+    pub ended: bool,
+    /// over the limits with nothing left to drop, the viewer is to be disconnected
+    pub overflowed: bool,
+    pub dropped_frames: u64,
+    pub dropped_bytes: u64,
+}
+
+impl ViewerQueue {
+    fn new(waker: ViewerWaker, limits: ViewerLimits, tags: VecDeque<Arc<RawTag>>) -> Self {
+        Self {
+            waker,
+            limits,
+            bytes: tags.iter().map(|tag| tag.data.len()).sum(),
+            tags,
+            skip_to_keyframe: false,
+            ended: false,
+            overflowed: false,
+            dropped_frames: 0,
+            dropped_bytes: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+
+    pub fn pop(&mut self) -> Option<Arc<RawTag>> {
+        let tag = self.tags.pop_front()?;
+        self.bytes -= tag.data.len();
+        Some(tag)
+    }
+
+    /// queue a live tag, the viewer is woken when its queue was empty or it overflowed
+    fn push(&mut self, tag: &Arc<RawTag>) {
+        if self.overflowed {
+            return;
+        }
+        if self.skip_to_keyframe {
+            if is_gop_start(tag) {
+                self.skip_to_keyframe = false;
+            } else if self.droppable(tag) {
+                self.dropped_frames += 1;
+                self.dropped_bytes += tag.data.len() as u64;
+                return;
+            }
+        }
+        let was_empty = self.tags.is_empty();
+        self.bytes += tag.data.len();
+        self.tags.push_back(tag.clone());
+        if self.over_limits() {
+            self.shed();
+        }
+        if self.overflowed || (was_empty && !self.tags.is_empty()) {
+            self.waker.wake();
+        }
+    }
+
+    fn droppable(&self, tag: &RawTag) -> bool {
+        match self.limits.policy {
+            SlowViewerPolicy::DropFrames => is_inter_frame(tag),
+            SlowViewerPolicy::SkipGop => !is_header(tag),
+            SlowViewerPolicy::Disconnect => false,
+        }
+    }
+
+    /// timestamp span of the queued audio and video
+    fn duration_ms(&self) -> u32 {
+        let mut media = self.tags.iter().filter(|tag| !is_header(tag));
+        match (media.next(), media.next_back()) {
+            (Some(first), Some(last)) => last.timestamp.saturating_sub(first.timestamp),
+            _ => 0,
+        }
+    }
+
+    fn over_limits(&self) -> bool {
+        self.bytes > self.limits.max_bytes || self.duration_ms() > self.limits.max_duration_ms
+    }
+
+    fn shed(&mut self) {
+        let dropped = self.dropped_frames;
+        if self.limits.policy == SlowViewerPolicy::DropFrames {
+            self.drop_tags(|_, tag| is_inter_frame(tag));
+            // later inter frames refer to the dropped ones
+            self.skip_to_keyframe = true;
+        }
+        if self.limits.policy != SlowViewerPolicy::Disconnect && self.over_limits() {
+            self.skip_gop();
+        }
+        if self.over_limits() {
+            self.overflowed = true;
+            return;
+        }
+        println!(
+            "viewer {:?} too slow, dropped {} frames",
+            self.waker.token,
+            self.dropped_frames - dropped
+        );
+    }
+
+    /// continue at the newest queued keyframe, or at the next one to come
+    fn skip_gop(&mut self) {
+        if let Some(start) = self.tags.iter().rposition(|tag| is_gop_start(tag)) {
+            self.drop_tags(|index, tag| index < start && !is_header(tag));
+        }
+        if self.over_limits() {
+            self.drop_tags(|_, tag| !is_header(tag));
+            self.skip_to_keyframe = true;
+        }
+    }
+
+    fn drop_tags<F: FnMut(usize, &RawTag) -> bool>(&mut self, mut should_drop: F) {
+        for (index, tag) in mem::take(&mut self.tags).into_iter().enumerate() {
+            if should_drop(index, &tag) {
+                self.bytes -= tag.data.len();
+                self.dropped_frames += 1;
+                self.dropped_bytes += tag.data.len() as u64;
+            } else {
+                self.tags.push_back(tag);
+            }
+        }
+    }
+}
+
+pub type SharedViewerQueue = Arc<Mutex<ViewerQueue>>;
+
+/// State of one published stream
+#[derive(Debug)]
+pub struct LiveStream {
+    pub header: Option<FlvHeader>,
+    pub metadata: Option<Arc<RawTag>>,
+    pub video_seq_header: Option<Arc<RawTag>>,
+    pub audio_seq_header: Option<Arc<RawTag>>,
+    pub gop_cache: Vec<Arc<RawTag>>,
+    pub tag_count: u64,
+    pub byte_count: u64,
+    pub last_timestamp: u32,
+    timestamps: TimestampNormalizer,
+    viewers: Vec<SharedViewerQueue>,
+}
+
+impl LiveStream {
+    fn new() -> Self {
+        Self {
+            header: None,
+            metadata: None,
+            video_seq_header: None,
+            audio_seq_header: None,
+            gop_cache: Vec::new(),
+            tag_count: 0,
+            byte_count: 0,
+            last_timestamp: 0,
+            timestamps: TimestampNormalizer::new(),
+            viewers: Vec::new(),
+        }
+    }
+
+    /// the tag as viewers get it, with its timestamp normalized
+    fn push_tag(&mut self, mut tag: RawTag) -> Arc<RawTag> {
+        // the timeline starts at 0, wrapping only after 49 days
+        tag.set_timestamp(self.timestamps.normalize(tag.timestamp) as u32);
+        let tag = Arc::new(tag);
+        self.tag_count += 1;
+        self.byte_count += tag.data.len() as u64;
+        self.last_timestamp = tag.timestamp;
+
+        match tag.tag_type {
+            TAG_TYPE_SCRIPT => self.metadata = Some(tag.clone()),
+            TAG_TYPE_VIDEO if tag.is_sequence_header() => self.video_seq_header = Some(tag.clone()),
+            TAG_TYPE_AUDIO if tag.is_sequence_header() => self.audio_seq_header = Some(tag.clone()),
+            _ => {
+                if tag.is_video_keyframe() {
+                    self.gop_cache.clear();
+                }
+                if self.gop_cache.len() < MAX_GOP_CACHE_TAGS {
+                    self.gop_cache.push(tag.clone());
+                }
+            }
+        }
+        tag
+    }
+
+    /// what a new viewer gets before live tags: metadata, sequence headers and the current GOP
+    fn startup_tags(&self) -> VecDeque<Arc<RawTag>> {
+        let mut tags = VecDeque::new();
+        tags.extend(self.metadata.iter().cloned());
+        tags.extend(self.video_seq_header.iter().cloned());
+        tags.extend(self.audio_seq_header.iter().cloned());
+        tags.extend(self.gop_cache.iter().cloned());
+        tags
+    }
+
+    /// mark every viewer ended and wake it up to send its trailer
+    fn end_viewers(&mut self) {
+        for viewer in self.viewers.drain(..) {
+            let mut queue = lock(&viewer);
+            queue.ended = true;
+            queue.waker.wake();
+        }
+    }
+}
+
+pub type SharedStream = Arc<Mutex<LiveStream>>;
+
+/// All streams currently being published, by stream key.
+/// Shared by all worker threads, each stream has its own lock
+/// so publishers on different workers do not contend.
+#[derive(Debug)]
+pub struct StreamRegistry {
+    streams: BTreeMap<String, SharedStream>,
+}
+
+pub type SharedRegistry = Arc<Mutex<StreamRegistry>>;
+
+impl StreamRegistry {
+    pub fn new() -> Self {
+        Self {
+            streams: BTreeMap::new(),
+        }
+    }
+
+    /// number of published streams and of their viewers
+    pub fn stats(&self) -> (usize, usize) {
+        let viewers = self
+            .streams
+            .values()
+            .map(|stream| lock(stream).viewers.len())
+            .sum();
+        (self.streams.len(), viewers)
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, key: &str) -> Option<SharedStream> {
+        self.streams.get(key).cloned()
+    }
+}
+
+/// The right to publish one stream key.
+/// The stream is removed from the registry when the publisher is dropped.
+#[derive(Debug)]
+pub struct Publisher {
+    registry: SharedRegistry,
+    key: String,
+    stream: SharedStream,
+}
+
+impl Publisher {
+    pub fn new(registry: &SharedRegistry, key: &str) -> IoResult<Self> {
+        let mut streams = lock(registry);
+        if streams.streams.contains_key(key) {
+            return Err(my_error(format!(
+                "stream key:{} is already being published",
+                key
+            )));
+        }
+        let stream = Arc::new(Mutex::new(LiveStream::new()));
+        streams.streams.insert(key.to_owned(), stream.clone());
+        println!("stream key:{} publish start", key);
+        Ok(Self {
+            registry: registry.clone(),
+            key: key.to_owned(),
+            stream,
+        })
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn on_header(&mut self, header: FlvHeader) {
+        println!(
+            "stream key:{} flv version:{} HasVideo:{} HasAudio:{}",
+            self.key, header.version, header.has_video, header.has_audio
+        );
+        lock(&self.stream).header = Some(header);
+    }
+
+    /// cache the tag and queue it to every viewer,
+    /// viewers whose queue was empty are sent a ViewerWakeup
+    pub fn on_tag(&mut self, tag: RawTag) {
+        let mut stream = lock(&self.stream);
+        let tag = stream.push_tag(tag);
+        for viewer in &stream.viewers {
+            lock(viewer).push(&tag);
+        }
+    }
+}
+
+impl Drop for Publisher {
+    /// the stream ends, viewers finish their responses
+    fn drop(&mut self) {
+        lock(&self.registry).streams.remove(&self.key);
+        let mut stream = lock(&self.stream);
+        stream.end_viewers();
+        println!(
+            "stream key:{} publish end, tags:{} bytes:{} last timestamp:{} discontinuities:{}",
+            self.key,
+            stream.tag_count,
+            stream.byte_count,
+            stream.last_timestamp,
+            stream.timestamps.discontinuities
+        );
+    }
+}
+
+/// A viewer of one stream key, removed from the stream when dropped
+#[derive(Debug)]
+pub struct Subscription {
+    key: String,
+    stream: SharedStream,
+    header: FlvHeader,
+    queue: SharedViewerQueue,
+}
+
+impl Subscription {
+    /// start watching key for the handle reached through waker,
+    /// the queue is pre-filled with the startup tags
+    pub fn new(
+        registry: &SharedRegistry,
+        key: &str,
+        waker: ViewerWaker,
+        limits: ViewerLimits,
+    ) -> IoResult<Self> {
+        let shared_stream = lock(registry)
+            .streams
+            .get(key)
+            .cloned()
+            .ok_or_else(|| my_error(format!("stream key:{} is not published", key)))?;
+        let mut stream = lock(&shared_stream);
+
+        let token = waker.token;
+        let queue = Arc::new(Mutex::new(ViewerQueue::new(
+            waker,
+            limits,
+            stream.startup_tags(),
+        )));
+        stream.viewers.push(queue.clone());
+        println!(
+            "stream key:{} new viewer {:?}, viewers:{}",
+            key,
+            token,
+            stream.viewers.len()
+        );
+
+        let header = stream.header.clone().unwrap_or(FlvHeader {
+            version: 1,
+            has_audio: true,
+            has_video: true,
+        });
+        drop(stream);
+        Ok(Self {
+            key: key.to_owned(),
+            stream: shared_stream,
+            header,
+            queue,
+        })
+    }
+
+    pub fn header(&self) -> &FlvHeader {
+        &self.header
+    }
+
+    pub fn queue(&self) -> &SharedViewerQueue {
+        &self.queue
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        lock(&self.stream)
+            .viewers
+            .retain(|viewer| !Arc::ptr_eq(viewer, &self.queue));
+        let queue = lock(&self.queue);
+        println!(
+            "stream key:{} viewer {:?} left, dropped frames:{} bytes:{}",
+            self.key, queue.waker.token, queue.dropped_frames, queue.dropped_bytes
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epoller::Epoller;
+
+    const NO_LIMITS: ViewerLimits = ViewerLimits {
+        max_bytes: usize::MAX,
+        max_duration_ms: u32::MAX,
+        policy: SlowViewerPolicy::Disconnect,
+    };
+
+    fn media_tag(tag_type: u8, timestamp: u32, body: &[u8]) -> Arc<RawTag> {
+        Arc::new(RawTag::new(tag_type, timestamp, body))
+    }
+
+    #[test]
+    fn test_live_stream_key() {
+        assert_eq!(live_stream_key("/live/abc.flv"), Some("abc"));
+        assert_eq!(live_stream_key("/live/.flv"), None);
+        assert_eq!(live_stream_key("/live/a/b.flv"), None);
+        assert_eq!(live_stream_key("/vod/abc.flv"), None);
+        assert_eq!(live_stream_key("/live/abc.mp4"), None);
+    }
+
+    #[test]
+    fn test_publish_twice() {
+        let registry = Arc::new(Mutex::new(StreamRegistry::new()));
+        let publisher = Publisher::new(&registry, "abc").unwrap();
+        assert!(Publisher::new(&registry, "abc").is_err());
+        assert!(lock(&registry).get("abc").is_some());
+        drop(publisher);
+        assert!(lock(&registry).get("abc").is_none());
+        assert!(Publisher::new(&registry, "abc").is_ok());
+    }
+
+    #[test]
+    fn test_subscribe_gets_startup_tags() {
+        let mut epoller = Epoller::create().unwrap();
+        let waker = ViewerWaker {
+            remote: epoller.remote().unwrap(),
+            token: Token(100),
+        };
+        let registry = Arc::new(Mutex::new(StreamRegistry::new()));
+        assert!(Subscription::new(&registry, "abc", waker.clone(), NO_LIMITS).is_err());
+
+        let mut publisher = Publisher::new(&registry, "abc").unwrap();
+        let tag = |tag_type: u8, body: &[u8]| {
+            let mut data = vec![tag_type, 0, 0, body.len() as u8, 0, 0, 0, 0, 0, 0, 0];
+            data.extend_from_slice(body);
+            RawTag {
+                tag_type,
+                timestamp: 0,
+                data,
+            }
+        };
+        publisher.on_tag(tag(TAG_TYPE_SCRIPT, &[2, 0, 0]));
+        publisher.on_tag(tag(TAG_TYPE_VIDEO, &[0x17, 0]));
+        publisher.on_tag(tag(TAG_TYPE_VIDEO, &[0x27, 1]));
+        publisher.on_tag(tag(TAG_TYPE_VIDEO, &[0x17, 1]));
+        publisher.on_tag(tag(TAG_TYPE_AUDIO, &[0xaf, 1]));
+
+        let subscription = Subscription::new(&registry, "abc", waker, NO_LIMITS).unwrap();
+        let types: Vec<u8> = lock(subscription.queue())
+            .tags
+            .iter()
+            .map(|tag| tag.data[11])
+            .collect();
+        // the inter frame before the last keyframe is not sent
+        assert_eq!(types, vec![2, 0x17, 0x17, 0xaf]);
+        let stream = lock(&registry).get("abc").unwrap();
+        assert_eq!(lock(&stream).viewers.len(), 1);
+
+        drop(publisher);
+        assert!(lock(subscription.queue()).ended);
+        drop(subscription);
+        assert!(lock(&stream).viewers.is_empty());
+    }
+
+    #[test]
+    fn test_slow_viewer_policies() {
+        let mut epoller = Epoller::create().unwrap();
+        let waker = ViewerWaker {
+            remote: epoller.remote().unwrap(),
+            token: Token(100),
+        };
+        let queue = |max_bytes, max_duration_ms, policy| {
+            let limits = ViewerLimits {
+                max_bytes,
+                max_duration_ms,
+                policy,
+            };
+            ViewerQueue::new(waker.clone(), limits, VecDeque::new())
+        };
+        let types = |queue: &ViewerQueue| -> Vec<(u8, u32)> {
+            queue
+                .tags
+                .iter()
+                .map(|tag| (tag.data[11], tag.timestamp))
+                .collect()
+        };
+        // 111 bytes of video, 21 bytes of audio
+        let key = |timestamp| media_tag(TAG_TYPE_VIDEO, timestamp, &[0x17; 100]);
+        let inter = |timestamp| media_tag(TAG_TYPE_VIDEO, timestamp, &[0x27; 100]);
+        let audio = |timestamp| media_tag(TAG_TYPE_AUDIO, timestamp, &[0xaf; 10]);
+
+        // inter frames go first, then until the next keyframe
+        let mut viewer = queue(300, u32::MAX, SlowViewerPolicy::DropFrames);
+        for tag in &[key(0), audio(20), inter(40), audio(60), inter(80)] {
+            viewer.push(tag);
+        }
+        assert_eq!(types(&viewer), vec![(0x17, 0), (0xaf, 20), (0xaf, 60)]);
+        for tag in &[inter(120), audio(140), key(160)] {
+            viewer.push(tag);
+        }
+        assert_eq!(viewer.dropped_frames, 3);
+        assert_eq!(viewer.dropped_bytes, 333);
+        assert_eq!(viewer.bytes, 285);
+        assert_eq!(viewer.pop().unwrap().timestamp, 0);
+        assert_eq!(types(&viewer).last(), Some(&(0x17, 160)));
+
+        // skip to the newest keyframe queued, else to the next one, headers stay
+        let mut viewer = queue(usize::MAX, 1000, SlowViewerPolicy::SkipGop);
+        let seq_header = media_tag(TAG_TYPE_VIDEO, 0, &[0x17, 0]);
+        for tag in &[
+            seq_header,
+            key(0),
+            inter(400),
+            inter(800),
+            key(1000),
+            inter(1200),
+        ] {
+            viewer.push(tag);
+        }
+        assert_eq!(types(&viewer), vec![(0x17, 0), (0x17, 1000), (0x27, 1200)]);
+        assert_eq!(viewer.dropped_frames, 3);
+        for tag in &[audio(5000), inter(5040), audio(5060), key(6000)] {
+            viewer.push(tag);
+        }
+        assert_eq!(types(&viewer), vec![(0x17, 0), (0x17, 6000)]);
+        assert_eq!(viewer.dropped_frames, 8);
+        assert!(!viewer.overflowed);
+
+        let mut viewer = queue(200, u32::MAX, SlowViewerPolicy::Disconnect);
+        viewer.push(&key(0));
+        assert!(!viewer.overflowed);
+        viewer.push(&inter(40));
+        assert!(viewer.overflowed);
+        viewer.push(&inter(80));
+        assert_eq!(viewer.tags.len(), 2);
+    }
+}
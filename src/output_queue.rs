@@ -0,0 +1,202 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Error;
+use std::io::IoSlice;
+use std::io::Result as IoResult;
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+
+/// iovecs handed to one writev
+const MAX_IOVECS: usize = 64;
+
+enum Buffer {
+    Owned(Vec<u8>),
+    // and where the bytes to send start
+    Shared(Arc<dyn AsRef<[u8]> + Send + Sync>, usize),
+}
+
+impl Buffer {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            Buffer::Owned(data) => data,
+            Buffer::Shared(data, start) => &(**data).as_ref()[*start..],
+        }
+    }
+}
+
+/// Output of a connection as a queue of buffers written with writev.
+/// Shared buffers, e.g. live tags, are queued without copying them,
+/// small pieces in between are collected in owned buffers.
+pub struct OutputQueue {
+    buffers: VecDeque<Buffer>,
+    // bytes of the front buffer already written
+    offset: usize,
+    len: usize,
+}
+
+impl OutputQueue {
+    pub fn new() -> Self {
+        Self {
+            buffers: VecDeque::new(),
+            offset: 0,
+            len: 0,
+        }
+    }
+
+    /// bytes not written yet
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// let write append bytes to the owned buffer at the end of the queue
+    pub fn append<F: FnOnce(&mut Vec<u8>)>(&mut self, write: F) {
+        if !matches!(self.buffers.back(), Some(Buffer::Owned(_))) {
+            self.buffers.push_back(Buffer::Owned(Vec::new()));
+        }
+        if let Some(Buffer::Owned(tail)) = self.buffers.back_mut() {
+            let before = tail.len();
+            write(tail);
+            self.len += tail.len() - before;
+            if tail.is_empty() {
+                self.buffers.pop_back();
+            }
+        }
+    }
+
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        self.append(|tail| tail.extend_from_slice(data));
+    }
+
+    /// queue data from start on, e.g. a tag body behind a rewritten header
+    pub fn push_shared_from(&mut self, data: Arc<dyn AsRef<[u8]> + Send + Sync>, start: usize) {
+        let len = (*data).as_ref().len().saturating_sub(start);
+        if len > 0 {
+            self.buffers.push_back(Buffer::Shared(data, start));
+            self.len += len;
+        }
+    }
+
+    /// drop size written bytes from the front, across buffer boundaries
+    pub fn consume(&mut self, mut size: usize) {
+        size = size.min(self.len);
+        self.len -= size;
+        while size > 0 {
+            let left = match self.buffers.front() {
+                Some(buffer) => buffer.as_slice().len() - self.offset,
+                None => return,
+            };
+            if size < left {
+                self.offset += size;
+                return;
+            }
+            size -= left;
+            self.offset = 0;
+            self.buffers.pop_front();
+        }
+    }
+
+    /// the unwritten bytes of up to MAX_IOVECS front buffers, for one writev or sendmsg
+    pub fn io_slices(&self) -> Vec<IoSlice<'_>> {
+        self.buffers
+            .iter()
+            .take(MAX_IOVECS)
+            .enumerate()
+            .map(|(index, buffer)| match index {
+                0 => IoSlice::new(&buffer.as_slice()[self.offset..]),
+                _ => IoSlice::new(buffer.as_slice()),
+            })
+            .collect()
+    }
+
+    /// one writev of the front buffers to fd, returns the bytes written
+    pub fn write_to(&mut self, fd: RawFd) -> IoResult<usize> {
+        let iovecs = self.io_slices();
+        // IoSlice has the layout of iovec
+        let ptr = iovecs.as_ptr() as *const libc::iovec;
+        let res = unsafe { libc::writev(fd, ptr, iovecs.len() as libc::c_int) };
+        if res == -1 {
+            return Err(Error::last_os_error());
+        }
+        self.consume(res as usize);
+        Ok(res as usize)
+    }
+}
+
+impl fmt::Debug for OutputQueue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "OutputQueue {{ len: {}, buffers: {} }}",
+            self.len,
+            self.buffers.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+    use std::io::Read;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn test_consume_across_buffers() {
+        let mut queue = OutputQueue::new();
+        queue.extend_from_slice(b"ab");
+        queue.push_shared_from(Arc::new(b"xxcdef".to_vec()), 2);
+        queue.extend_from_slice(b"g");
+        queue.append(|tail| tail.push(b'h'));
+        assert_eq!(queue.len(), 8);
+        assert_eq!(queue.buffers.len(), 3);
+
+        let slices = queue.io_slices();
+        let slices: Vec<&[u8]> = slices.iter().map(|slice| &slice[..]).collect();
+        assert_eq!(slices, [&b"ab"[..], b"cdef", b"gh"]);
+
+        queue.consume(3);
+        assert_eq!(&*queue.io_slices()[0], b"def");
+        queue.consume(4);
+        assert_eq!(&*queue.io_slices()[0], b"h");
+        assert_eq!(queue.len(), 1);
+        queue.consume(1);
+        assert!(queue.is_empty());
+        assert!(queue.io_slices().is_empty());
+    }
+
+    #[test]
+    fn test_partial_writev() {
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        ours.set_nonblocking(true).unwrap();
+        let shared: Arc<Vec<u8>> = Arc::new((0..100_000u32).map(|i| i as u8).collect());
+        let mut queue = OutputQueue::new();
+        let mut expected = Vec::new();
+        for round in 0..20u8 {
+            queue.extend_from_slice(&[round; 3]);
+            queue.push_shared_from(shared.clone(), 0);
+            expected.extend_from_slice(&[round; 3]);
+            expected.extend_from_slice(&shared);
+        }
+
+        let mut received = Vec::new();
+        let mut buf = vec![0; 64 * 1024];
+        while !queue.is_empty() {
+            match queue.write_to(ours.as_raw_fd()) {
+                Ok(_) => (),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    let size = theirs.read(&mut buf).unwrap();
+                    received.extend_from_slice(&buf[..size]);
+                }
+                Err(err) => panic!("{}", err),
+            }
+        }
+        drop(ours);
+        theirs.read_to_end(&mut received).unwrap();
+        assert_eq!(received, expected);
+    }
+}
@@ -0,0 +1,184 @@
+use crate::epoller::{Epoller, RWHandle};
+use crate::slab::Token;
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Result as IoResult;
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// closure posted from another thread, run on the epoller's thread
+pub type Task = Box<dyn FnOnce(&mut Epoller) + Send>;
+
+struct Shared {
+    event_fd: RawFd,
+    tasks: Mutex<VecDeque<Task>>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.event_fd) };
+    }
+}
+
+/// Handle for other threads to reach one Epoller.
+/// Posting a task wakes a blocked run() through an eventfd,
+/// the task is then run on the epoller's thread.
+#[derive(Clone)]
+pub struct Remote {
+    shared: Arc<Shared>,
+}
+
+impl Remote {
+    /// create the eventfd and register its reading end in epoller,
+    /// Epoller::remote keeps one for all users
+    pub fn register(epoller: &mut Epoller) -> IoResult<Self> {
+        let event_fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if event_fd == -1 {
+            return Err(Error::last_os_error());
+        }
+        let shared = Arc::new(Shared {
+            event_fd,
+            tasks: Mutex::new(VecDeque::new()),
+        });
+        let receiver = RemoteReceiver {
+            shared: shared.clone(),
+        };
+        if let Err((_, err)) = epoller.wait_read(receiver) {
+            return Err(err);
+        }
+        Ok(Self { shared })
+    }
+
+    /// make the epoller return from epoll_wait
+    pub fn wake(&self) -> IoResult<()> {
+        let one: u64 = 1;
+        let res = unsafe {
+            libc::write(
+                self.shared.event_fd,
+                &one as *const u64 as *const libc::c_void,
+                mem::size_of::<u64>(),
+            )
+        };
+        if res == -1 {
+            let err = Error::last_os_error();
+            // the counter is saturated, the epoller is woken anyway
+            if err.kind() != ErrorKind::WouldBlock {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// run task on the epoller's thread, in posting order
+    pub fn post<F>(&self, task: F) -> IoResult<()>
+    where
+        F: FnOnce(&mut Epoller) + Send + 'static,
+    {
+        self.shared
+            .tasks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push_back(Box::new(task));
+        self.wake()
+    }
+
+    /// deliver msg to the on_message of the handle with token
+    pub fn send(&self, token: Token, msg: Box<dyn Any + Send>) -> IoResult<()> {
+        self.post(move |epoller| {
+            if !epoller.send(token, msg) {
+                println!("remote message to {:?} dropped, handle is gone", token);
+            }
+        })
+    }
+}
+
+impl fmt::Debug for Remote {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Remote {{ event_fd: {} }}", self.shared.event_fd)
+    }
+}
+
+/// the eventfd as registered in the epoller
+struct RemoteReceiver {
+    shared: Arc<Shared>,
+}
+
+impl AsRawFd for RemoteReceiver {
+    fn as_raw_fd(&self) -> RawFd {
+        self.shared.event_fd
+    }
+}
+
+impl RWHandle for RemoteReceiver {
+    fn on_read(&mut self, epoller: &mut Epoller) -> IoResult<()> {
+        let mut count: u64 = 0;
+        let res = unsafe {
+            libc::read(
+                self.shared.event_fd,
+                &mut count as *mut u64 as *mut libc::c_void,
+                mem::size_of::<u64>(),
+            )
+        };
+        if res == -1 {
+            let err = Error::last_os_error();
+            if err.kind() != ErrorKind::WouldBlock {
+                return Err(err);
+            }
+        }
+
+        // tasks may post more tasks, run until the queue stays empty
+        loop {
+            let tasks = mem::take(
+                &mut *self
+                    .shared
+                    .tasks
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner),
+            );
+            if tasks.is_empty() {
+                return Ok(());
+            }
+            for task in tasks {
+                task(epoller);
+            }
+        }
+    }
+
+    fn on_write(&mut self, _epoller: &mut Epoller) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn test_post_from_thread() {
+        let mut epoller = Epoller::create().unwrap();
+        let remote = epoller.remote().unwrap();
+        let ran = Arc::new(AtomicUsize::new(0));
+
+        let thread_ran = ran.clone();
+        let worker = thread::spawn(move || {
+            for _ in 0..3 {
+                let ran = thread_ran.clone();
+                remote
+                    .post(move |_| {
+                        ran.fetch_add(1, Ordering::SeqCst);
+                    })
+                    .unwrap();
+            }
+        });
+        worker.join().unwrap();
+
+        // would block forever without the eventfd wake up
+        epoller.run(-1).unwrap();
+        assert_eq!(ran.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,85 @@
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result as IoResult;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+
+fn sigset(signals: &[libc::c_int]) -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for signal in signals {
+            libc::sigaddset(&mut set, *signal);
+        }
+        set
+    }
+}
+
+/// Block signals for the calling thread and the threads it spawns later,
+/// so they are only seen through a SignalFd.
+/// Call it in main before any thread is started.
+pub fn block_signals(signals: &[libc::c_int]) -> IoResult<()> {
+    let set = sigset(signals);
+    let res = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) };
+    match res {
+        0 => Ok(()),
+        err => Err(Error::from_raw_os_error(err)),
+    }
+}
+
+/// signalfd delivering blocked signals as readable events
+#[derive(Debug)]
+pub struct SignalFd {
+    fd: RawFd,
+}
+
+impl SignalFd {
+    pub fn create(signals: &[libc::c_int]) -> IoResult<Self> {
+        let set = sigset(signals);
+        let fd = unsafe { libc::signalfd(-1, &set, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC) };
+        match fd {
+            -1 => Err(Error::last_os_error()),
+            _ => Ok(Self { fd }),
+        }
+    }
+
+    /// next pending signal number, None when there is none
+    pub fn read_signal(&mut self) -> IoResult<Option<libc::c_int>> {
+        let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+        let size = mem::size_of::<libc::signalfd_siginfo>();
+        let res = unsafe {
+            libc::read(
+                self.fd,
+                &mut info as *mut libc::signalfd_siginfo as *mut libc::c_void,
+                size,
+            )
+        };
+        if res == -1 {
+            let err = Error::last_os_error();
+            if err.kind() == ErrorKind::WouldBlock {
+                return Ok(None);
+            }
+            return Err(err);
+        }
+        if res as usize != size {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("signalfd short read {}", res),
+            ));
+        }
+        Ok(Some(info.ssi_signo as libc::c_int))
+    }
+}
+
+impl AsRawFd for SignalFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for SignalFd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
@@ -0,0 +1,140 @@
+/// Address of a value in a Slab.
+/// Low 32 bits are the slot index, high 32 bits the generation of the slot,
+/// so a token of a removed value never reaches a later value in the same slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Token(pub u64);
+
+impl Token {
+    fn new(index: usize, generation: u32) -> Self {
+        Token((generation as u64) << 32 | index as u64)
+    }
+
+    fn index(self) -> usize {
+        (self.0 & 0xffff_ffff) as usize
+    }
+
+    fn generation(self) -> u32 {
+        (self.0 >> 32) as u32
+    }
+}
+
+#[derive(Debug)]
+struct Slot<T> {
+    generation: u32,
+    allocated: bool,
+    // None while allocated means the value is taken out for a moment
+    value: Option<T>,
+}
+
+/// Values addressed by Token, slots of removed values are reused
+#[derive(Debug)]
+pub struct Slab<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<usize>,
+}
+
+impl<T> Slab<T> {
+    pub fn new() -> Self {
+        Self {
+            slots: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    /// the token the next insert will return
+    pub fn next_token(&self) -> Token {
+        match self.free.last() {
+            Some(&index) => Token::new(index, self.slots[index].generation),
+            None => Token::new(self.slots.len(), 0),
+        }
+    }
+
+    pub fn insert(&mut self, value: T) -> Token {
+        match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index];
+                slot.allocated = true;
+                slot.value = Some(value);
+                Token::new(index, slot.generation)
+            }
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    allocated: true,
+                    value: Some(value),
+                });
+                Token::new(self.slots.len() - 1, 0)
+            }
+        }
+    }
+
+    fn slot_mut(&mut self, token: Token) -> Option<&mut Slot<T>> {
+        self.slots
+            .get_mut(token.index())
+            .filter(|slot| slot.allocated && slot.generation == token.generation())
+    }
+
+    /// whether token is allocated, even while its value is taken
+    pub fn contains(&self, token: Token) -> bool {
+        self.slots
+            .get(token.index())
+            .is_some_and(|slot| slot.allocated && slot.generation == token.generation())
+    }
+
+    pub fn get(&self, token: Token) -> Option<&T> {
+        self.slots
+            .get(token.index())
+            .filter(|slot| slot.allocated && slot.generation == token.generation())?
+            .value
+            .as_ref()
+    }
+
+    /// move the value out but keep the slot, put it back with restore
+    pub fn take(&mut self, token: Token) -> Option<T> {
+        self.slot_mut(token)?.value.take()
+    }
+
+    pub fn restore(&mut self, token: Token, value: T) {
+        if let Some(slot) = self.slot_mut(token) {
+            slot.value = Some(value);
+        }
+    }
+
+    /// free the slot, returns the value unless it is taken
+    pub fn remove(&mut self, token: Token) -> Option<T> {
+        let slot = self.slot_mut(token)?;
+        let value = slot.value.take();
+        slot.allocated = false;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(token.index());
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stale_token() {
+        let mut slab = Slab::new();
+        let first = slab.insert("a");
+        let second = slab.insert("b");
+        assert_eq!(slab.remove(first), Some("a"));
+        assert_eq!(slab.remove(first), None);
+
+        // the slot is reused under a new generation
+        let next = slab.next_token();
+        let third = slab.insert("c");
+        assert_eq!(next, third);
+        assert_ne!(third, first);
+        assert_eq!(slab.get(first), None);
+        assert_eq!(slab.get(third), Some(&"c"));
+
+        assert_eq!(slab.take(second), Some("b"));
+        assert!(slab.contains(second));
+        assert_eq!(slab.get(second), None);
+        slab.restore(second, "b2");
+        assert_eq!(slab.get(second), Some(&"b2"));
+    }
+}
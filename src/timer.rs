@@ -0,0 +1,243 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+
+/// resolution of timers, deadlines are rounded up to a whole tick
+const TICK: Duration = Duration::from_millis(10);
+const WHEEL_SLOTS: u64 = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+#[derive(Debug)]
+struct TimerEntry {
+    id: TimerId,
+    expire_tick: u64,
+    period: Option<Duration>,
+    fd: RawFd,
+}
+
+/// Hashed timing wheel.
+/// A timer lives in slot expire_tick % WHEEL_SLOTS, timers further away than
+/// one turn simply stay in their slot until the wheel comes round again.
+/// Insert and cancel are O(1), advancing costs one slot per elapsed tick.
+#[derive(Debug)]
+pub struct TimerWheel {
+    slots: Vec<Vec<TimerEntry>>,
+    start: Instant,
+    // every tick before this one has been processed
+    current_tick: u64,
+    next_id: u64,
+    // slot of each pending timer, for cancel
+    locations: BTreeMap<TimerId, usize>,
+    by_fd: BTreeMap<RawFd, BTreeSet<TimerId>>,
+}
+
+impl TimerWheel {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            slots: (0..WHEEL_SLOTS).map(|_| Vec::new()).collect(),
+            start: now,
+            current_tick: 0,
+            next_id: 0,
+            locations: BTreeMap::new(),
+            by_fd: BTreeMap::new(),
+        }
+    }
+
+    fn tick_of(&self, time: Instant) -> u64 {
+        let elapsed = time.saturating_duration_since(self.start);
+        (elapsed.as_nanos() / TICK.as_nanos()) as u64
+    }
+
+    fn insert(&mut self, entry: TimerEntry) {
+        let slot = (entry.expire_tick % WHEEL_SLOTS) as usize;
+        self.locations.insert(entry.id, slot);
+        self.by_fd.entry(entry.fd).or_default().insert(entry.id);
+        self.slots[slot].push(entry);
+    }
+
+    /// fire once after delay, or every period after that when period is set
+    pub fn add(
+        &mut self,
+        now: Instant,
+        fd: RawFd,
+        delay: Duration,
+        period: Option<Duration>,
+    ) -> TimerId {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+        // round up so a timer never fires early
+        let expire_tick = (self.tick_of(now + delay) + 1).max(self.current_tick);
+        self.insert(TimerEntry {
+            id,
+            expire_tick,
+            period,
+            fd,
+        });
+        id
+    }
+
+    /// false when the timer already fired or was cancelled
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        let slot = match self.locations.remove(&id) {
+            Some(slot) => slot,
+            None => return false,
+        };
+        if let Some(pos) = self.slots[slot].iter().position(|entry| entry.id == id) {
+            let entry = self.slots[slot].swap_remove(pos);
+            self.forget_fd(entry.fd, id);
+        }
+        true
+    }
+
+    /// cancel every timer owned by fd, used when its handle goes away
+    pub fn cancel_fd(&mut self, fd: RawFd) {
+        if let Some(ids) = self.by_fd.remove(&fd) {
+            for id in ids {
+                if let Some(slot) = self.locations.remove(&id) {
+                    self.slots[slot].retain(|entry| entry.id != id);
+                }
+            }
+        }
+    }
+
+    fn forget_fd(&mut self, fd: RawFd, id: TimerId) {
+        if let Some(ids) = self.by_fd.get_mut(&fd) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                self.by_fd.remove(&fd);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.locations.is_empty()
+    }
+
+    /// advance to now and return the timers due, in expire order.
+    /// Periodic timers are put back for their next round.
+    pub fn expire(&mut self, now: Instant) -> Vec<(TimerId, RawFd)> {
+        let now_tick = self.tick_of(now);
+        // with nothing pending skip straight to now instead of walking empty slots
+        if self.is_empty() {
+            self.current_tick = self.current_tick.max(now_tick + 1);
+            return Vec::new();
+        }
+
+        let mut fired = Vec::new();
+        while self.current_tick <= now_tick {
+            let slot = (self.current_tick % WHEEL_SLOTS) as usize;
+            let tick = self.current_tick;
+            let entries = std::mem::take(&mut self.slots[slot]);
+            for entry in entries {
+                if entry.expire_tick > tick {
+                    self.slots[slot].push(entry);
+                    continue;
+                }
+                self.locations.remove(&entry.id);
+                self.forget_fd(entry.fd, entry.id);
+                fired.push((entry.expire_tick, entry.id, entry.fd));
+                if let Some(period) = entry.period {
+                    let period_ticks = (period.as_nanos() / TICK.as_nanos()).max(1) as u64;
+                    self.insert(TimerEntry {
+                        expire_tick: now_tick.max(tick) + period_ticks,
+                        ..entry
+                    });
+                }
+            }
+            self.current_tick += 1;
+        }
+
+        fired.sort();
+        fired.into_iter().map(|(_, id, fd)| (id, fd)).collect()
+    }
+
+    /// how long until the next slot holding a timer comes due,
+    /// None when there are no timers at all
+    pub fn next_timeout(&self, now: Instant) -> Option<Duration> {
+        if self.is_empty() {
+            return None;
+        }
+        let now_tick = self.tick_of(now);
+        let mut tick = self.current_tick;
+        // at most one turn ahead, timers further away are found on a later wake up
+        while tick < self.current_tick + WHEEL_SLOTS {
+            if !self.slots[(tick % WHEEL_SLOTS) as usize].is_empty() {
+                break;
+            }
+            tick += 1;
+        }
+        if tick <= now_tick {
+            return Some(Duration::from_millis(0));
+        }
+        let due = self.start + TICK * tick as u32;
+        Some(due.saturating_duration_since(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn test_one_shot_and_cancel() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::new(start);
+        let first = wheel.add(start, 3, ms(50), None);
+        let second = wheel.add(start, 4, ms(20), None);
+        let cancelled = wheel.add(start, 5, ms(30), None);
+        assert!(wheel.cancel(cancelled));
+        assert!(!wheel.cancel(cancelled));
+
+        assert!(wheel.expire(start + ms(15)).is_empty());
+        assert_eq!(wheel.expire(start + ms(35)), vec![(second, 4)]);
+        assert_eq!(wheel.expire(start + ms(100)), vec![(first, 3)]);
+        assert!(wheel.is_empty());
+        assert_eq!(wheel.next_timeout(start + ms(100)), None);
+    }
+
+    #[test]
+    fn test_periodic_and_far_timers() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::new(start);
+        let periodic = wheel.add(start, 3, ms(100), Some(ms(100)));
+        // more than one turn of the wheel away
+        let far = wheel.add(start, 4, ms(7000), None);
+
+        let mut periodic_count = 0;
+        let mut far_fired = false;
+        for step in 1..=80 {
+            for (id, _) in wheel.expire(start + ms(step * 100 + 5)) {
+                if id == periodic {
+                    periodic_count += 1;
+                } else if id == far {
+                    far_fired = true;
+                    assert!(step >= 70);
+                }
+            }
+        }
+        assert!(far_fired);
+        assert!(periodic_count >= 79);
+        assert!(wheel.cancel(periodic));
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn test_next_timeout_and_cancel_fd() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::new(start);
+        wheel.add(start, 3, ms(200), None);
+        wheel.add(start, 3, ms(300), Some(ms(300)));
+        let timeout = wheel.next_timeout(start).unwrap();
+        assert!(timeout >= ms(200) && timeout <= ms(220), "{:?}", timeout);
+
+        wheel.cancel_fd(3);
+        assert!(wheel.is_empty());
+        assert!(wheel.expire(start + ms(1000)).is_empty());
+    }
+}
@@ -0,0 +1,162 @@
+/// audio and video may arrive this far out of order, a bigger step back is a restart
+const MAX_JITTER_MS: i64 = 1000;
+/// a bigger step forward is a discontinuity, e.g. a publisher that paused
+const MAX_GAP_MS: i64 = 5000;
+/// how far a discontinuity moves the stream before any frame interval is known
+const DEFAULT_STEP_MS: u64 = 40;
+/// muxers writing only the lower 24 bits of the timestamp wrap here
+const WRAP_24_BITS: u32 = 1 << 24;
+
+/// Timestamps of a live stream as sent on to viewers: starting at 0 and
+/// without jumps, whatever the publisher sends.
+/// One offset is added to every track so their spacing is kept, it only
+/// changes when restarts and large gaps are bridged by one frame interval.
+#[derive(Debug)]
+pub struct TimestampNormalizer {
+    // latest input timestamp and its output, out - in is the offset
+    last_in: Option<u32>,
+    last_out: u64,
+    // the last regular forward step, used to bridge discontinuities
+    step: u64,
+    pub discontinuities: u64,
+}
+
+impl TimestampNormalizer {
+    pub fn new() -> Self {
+        Self {
+            last_in: None,
+            last_out: 0,
+            step: DEFAULT_STEP_MS,
+            discontinuities: 0,
+        }
+    }
+
+    /// the output timestamp of a tag stamped ts by the publisher
+    pub fn normalize(&mut self, ts: u32) -> u64 {
+        let last_in = match self.last_in {
+            Some(last_in) => last_in,
+            None => {
+                self.last_in = Some(ts);
+                return self.last_out;
+            }
+        };
+        match forward_delta(last_in, ts) {
+            Some(delta) if delta >= 0 => {
+                self.last_in = Some(ts);
+                if delta > 0 {
+                    self.step = delta as u64;
+                }
+                self.last_out += delta as u64;
+            }
+            // the other track is a bit behind, same offset
+            Some(delta) => return self.last_out.saturating_sub(delta.unsigned_abs()),
+            None => {
+                self.discontinuities += 1;
+                self.last_in = Some(ts);
+                self.last_out += self.step;
+            }
+        }
+        self.last_out
+    }
+}
+
+/// ts - last_in across a 32 or 24 bit wraparound,
+/// None for jumps too large to be regular
+fn forward_delta(last_in: u32, ts: u32) -> Option<i64> {
+    let delta = ts.wrapping_sub(last_in) as i32 as i64;
+    if (-MAX_JITTER_MS..=MAX_GAP_MS).contains(&delta) {
+        return Some(delta);
+    }
+    if last_in < WRAP_24_BITS && ts < last_in {
+        let delta = (ts + WRAP_24_BITS - last_in) as i64;
+        if delta <= MAX_GAP_MS {
+            return Some(delta);
+        }
+    }
+    None
+}
+
+/// ts of a viewer whose stream starts at base, tags from before it are at 0.
+/// Normalized timestamps only wrap after 49 days, so the difference is taken modulo 2^32.
+pub fn rebase(ts: u32, base: u32) -> u32 {
+    let delta = ts.wrapping_sub(base);
+    if (delta as i32) < 0 {
+        0
+    } else {
+        delta
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize_all(input: &[u32]) -> (Vec<u64>, u64) {
+        let mut normalizer = TimestampNormalizer::new();
+        let output = input.iter().map(|&ts| normalizer.normalize(ts)).collect();
+        (output, normalizer.discontinuities)
+    }
+
+    #[test]
+    fn test_starts_at_zero() {
+        assert_eq!(
+            normalize_all(&[3_600_000, 3_600_040, 3_600_080]),
+            (vec![0, 40, 80], 0)
+        );
+    }
+
+    #[test]
+    fn test_backward_jump() {
+        // the publisher restarted at 0
+        assert_eq!(
+            normalize_all(&[100_000, 100_040, 0, 40]),
+            (vec![0, 40, 80, 120], 1)
+        );
+    }
+
+    #[test]
+    fn test_large_gap() {
+        assert_eq!(
+            normalize_all(&[1000, 1020, 61_020, 61_040]),
+            (vec![0, 20, 40, 60], 1)
+        );
+    }
+
+    #[test]
+    fn test_interleaving_keeps_spacing() {
+        // audio a little behind video keeps its own timestamps
+        assert_eq!(
+            normalize_all(&[0, 0, 40, 23, 80, 46, 69, 120]),
+            (vec![0, 0, 40, 23, 80, 46, 69, 120], 0)
+        );
+        assert_eq!(
+            normalize_all(&[3_600_000, 3_600_040, 3_600_023, 3_600_080]),
+            (vec![0, 40, 23, 80], 0)
+        );
+        // and the offset of a restart
+        assert_eq!(
+            normalize_all(&[100_000, 100_040, 0, 40, 23]),
+            (vec![0, 40, 80, 120, 103], 1)
+        );
+    }
+
+    #[test]
+    fn test_wraparound() {
+        assert_eq!(
+            normalize_all(&[u32::MAX - 39, u32::MAX, 39, 79]),
+            (vec![0, 39, 79, 119], 0)
+        );
+        // only the lower 24 bits written
+        assert_eq!(
+            normalize_all(&[0xff_ffd8, 0xff_fff0, 0x10, 0x38]),
+            (vec![0, 24, 56, 96], 0)
+        );
+    }
+
+    #[test]
+    fn test_rebase() {
+        assert_eq!(rebase(5040, 5000), 40);
+        assert_eq!(rebase(0, 5000), 0);
+        assert_eq!(rebase(10, u32::MAX - 9), 20);
+    }
+}
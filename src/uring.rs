@@ -0,0 +1,606 @@
+use crate::backend::{msghdr, Backend, EpollBackend, Event};
+use crate::epoller::{Interest, Trigger};
+use crate::my_error::my_error;
+use crate::slab::Token;
+use std::collections::BTreeMap;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::IoSlice;
+use std::io::Result as IoResult;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// from linux/io_uring.h, libc has only the syscall numbers
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x800_0000;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_ENTER_EXT_ARG: u32 = 1 << 3;
+const IORING_FEAT_EXT_ARG: u32 = 1 << 8;
+const IORING_OP_POLL_ADD: u8 = 6;
+const IORING_OP_POLL_REMOVE: u8 = 7;
+const IORING_OP_SENDMSG: u8 = 9;
+
+/// user_data of send completions, the low bits are the index in the batch
+const SEND_TAG: u64 = 1 << 63;
+/// user_data of poll removals, their completions are not interesting
+const REMOVE_TAG: u64 = u64::MAX;
+/// failed waits for a send batch before the ring is given up
+const SEND_WAIT_RETRIES: u32 = 8;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+/// submission queue entry, only the fields the ops used here need are named
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    // poll32_events or msg_flags, depending on opcode
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct GeteventsArg {
+    sigmask: u64,
+    sigmask_sz: u32,
+    pad: u32,
+    ts: u64,
+}
+
+/// a ring region shared with the kernel, unmapped on drop
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    fn map(fd: RawFd, len: usize, offset: libc::off_t) -> IoResult<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+        Ok(Self {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+
+    /// pointer to the T at byte offset, the offsets come from the kernel
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { self.ptr.add(offset as usize) as *mut T }
+    }
+
+    fn atomic(&self, offset: u32) -> &AtomicU32 {
+        unsafe { &*(self.at::<AtomicU32>(offset)) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+/// a registered fd, its poll is armed again after each completion
+#[derive(Debug)]
+struct Poll {
+    token: Token,
+    events: u32,
+    // user_data of the poll in flight
+    armed: Option<u64>,
+}
+
+/// Backend on io_uring: readiness comes from one-shot POLL_ADD requests,
+/// a send batch goes to the kernel with a single io_uring_enter.
+/// A poll is armed again only when the epoller waits next, so every trigger
+/// mode is reported level style, which handlers written for edge cope with too.
+pub struct UringBackend {
+    fd: RawFd,
+    params: Params,
+    sq_ring: Mapping,
+    cq_ring: Mapping,
+    sqes: Mapping,
+    polls: BTreeMap<RawFd, Poll>,
+    // user_data of armed polls to their fd
+    armed: BTreeMap<u64, RawFd>,
+    next_user_data: u64,
+    // poll completions reaped while a send batch waited
+    ready: Vec<Event>,
+    // for the epoll backend taking over once the ring failed
+    event_buffer_size: usize,
+    failed: bool,
+}
+
+impl UringBackend {
+    /// ring with room for about entries requests in flight
+    pub fn create(entries: usize) -> IoResult<Self> {
+        let event_buffer_size = entries;
+        let entries = entries.clamp(64, 4096).next_power_of_two() as u32;
+        let mut params = Params::default();
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                entries,
+                &mut params as *mut Params,
+            )
+        } as RawFd;
+        if fd == -1 {
+            return Err(my_error(format!(
+                "io_uring setup failed:{}",
+                Error::last_os_error()
+            )));
+        }
+        if params.features & IORING_FEAT_EXT_ARG == 0 {
+            unsafe { libc::close(fd) };
+            return Err(my_error("io_uring without wait timeouts, kernel too old"));
+        }
+
+        let map = |len, offset| {
+            Mapping::map(fd, len, offset).inspect_err(|_| {
+                unsafe { libc::close(fd) };
+            })
+        };
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len =
+            params.cq_off.cqes as usize + params.cq_entries as usize * mem::size_of::<Cqe>();
+        let sqes_len = params.sq_entries as usize * mem::size_of::<Sqe>();
+        let sq_ring = map(sq_len, IORING_OFF_SQ_RING)?;
+        let cq_ring = map(cq_len, IORING_OFF_CQ_RING)?;
+        let sqes = map(sqes_len, IORING_OFF_SQES)?;
+        Ok(Self {
+            fd,
+            params,
+            sq_ring,
+            cq_ring,
+            sqes,
+            polls: BTreeMap::new(),
+            armed: BTreeMap::new(),
+            next_user_data: 1,
+            ready: Vec::new(),
+            event_buffer_size,
+            failed: false,
+        })
+    }
+
+    /// entries filled but not consumed by the kernel yet
+    fn unsubmitted(&self) -> u32 {
+        let tail = self
+            .sq_ring
+            .atomic(self.params.sq_off.tail)
+            .load(Ordering::Relaxed);
+        let head = self
+            .sq_ring
+            .atomic(self.params.sq_off.head)
+            .load(Ordering::Acquire);
+        tail.wrapping_sub(head)
+    }
+
+    fn push(&mut self, sqe: Sqe) -> IoResult<()> {
+        if self.unsubmitted() == self.params.sq_entries {
+            self.enter(0, None)?;
+            if self.unsubmitted() == self.params.sq_entries {
+                return Err(Error::from_raw_os_error(libc::EBUSY));
+            }
+        }
+        let off = &self.params.sq_off;
+        let tail = self.sq_ring.atomic(off.tail).load(Ordering::Relaxed);
+        let index = tail & self.params.sq_entries.wrapping_sub(1);
+        unsafe {
+            *self.sqes.at::<Sqe>(index * mem::size_of::<Sqe>() as u32) = sqe;
+            *self.sq_ring.at::<u32>(off.array + index * 4) = index;
+        }
+        // the entry is written before the kernel may see the new tail
+        self.sq_ring
+            .atomic(off.tail)
+            .store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Submit what is queued, then wait for min_complete completions
+    /// or until timeout ms passed. A wait ending by the timeout is no error.
+    fn enter(&mut self, min_complete: u32, timeout: Option<i32>) -> IoResult<()> {
+        let mut flags = 0;
+        if min_complete > 0 {
+            flags |= IORING_ENTER_GETEVENTS;
+        }
+        let ts = timeout.map(|ms| libc::timespec {
+            tv_sec: (ms / 1000) as libc::time_t,
+            tv_nsec: (ms % 1000) as libc::c_long * 1_000_000,
+        });
+        let arg = ts.as_ref().map(|ts| GeteventsArg {
+            sigmask: 0,
+            sigmask_sz: 0,
+            pad: 0,
+            ts: ts as *const libc::timespec as u64,
+        });
+        let (arg_ptr, arg_size) = match &arg {
+            Some(arg) => {
+                flags |= IORING_ENTER_EXT_ARG;
+                (
+                    arg as *const GeteventsArg as *const libc::c_void,
+                    mem::size_of::<GeteventsArg>(),
+                )
+            }
+            None => (ptr::null(), 0),
+        };
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_enter,
+                self.fd,
+                self.unsubmitted(),
+                min_complete,
+                flags,
+                arg_ptr,
+                arg_size,
+            )
+        };
+        if res == -1 {
+            let err = Error::last_os_error();
+            if err.raw_os_error() != Some(libc::ETIME) {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Take every completion: polls go to events, sends to results.
+    fn reap(&mut self, events: &mut Vec<Event>, results: &mut [Option<IoResult<usize>>]) {
+        let off = self.params.cq_off;
+        loop {
+            let head = self.cq_ring.atomic(off.head).load(Ordering::Relaxed);
+            let tail = self.cq_ring.atomic(off.tail).load(Ordering::Acquire);
+            if head == tail {
+                return;
+            }
+            let index = head & self.params.cq_entries.wrapping_sub(1);
+            let cqe = unsafe {
+                *self
+                    .cq_ring
+                    .at::<Cqe>(off.cqes + index * mem::size_of::<Cqe>() as u32)
+            };
+            self.cq_ring
+                .atomic(off.head)
+                .store(head.wrapping_add(1), Ordering::Release);
+
+            if cqe.user_data == REMOVE_TAG {
+                continue;
+            }
+            if cqe.user_data & SEND_TAG != 0 {
+                let index = (cqe.user_data & !SEND_TAG) as usize;
+                if let Some(result) = results.get_mut(index) {
+                    *result = Some(if cqe.res < 0 {
+                        Err(Error::from_raw_os_error(-cqe.res))
+                    } else {
+                        Ok(cqe.res as usize)
+                    });
+                }
+                continue;
+            }
+            // a poll removed or modified since has no entry any more
+            let raw_fd = match self.armed.remove(&cqe.user_data) {
+                Some(raw_fd) => raw_fd,
+                None => continue,
+            };
+            if let Some(poll) = self.polls.get_mut(&raw_fd) {
+                poll.armed = None;
+                let ready = match cqe.res {
+                    res if res >= 0 => res as u32,
+                    res if -res == libc::ECANCELED => continue,
+                    _ => libc::EPOLLERR as u32,
+                };
+                events.push(Event {
+                    token: poll.token,
+                    events: ready,
+                });
+            }
+        }
+    }
+
+    /// cancel the poll in flight for raw_fd, its completion is ignored
+    fn disarm(&mut self, raw_fd: RawFd) -> IoResult<()> {
+        let user_data = match self
+            .polls
+            .get_mut(&raw_fd)
+            .and_then(|poll| poll.armed.take())
+        {
+            Some(user_data) => user_data,
+            None => return Ok(()),
+        };
+        self.armed.remove(&user_data);
+        self.push(Sqe {
+            opcode: IORING_OP_POLL_REMOVE,
+            fd: -1,
+            addr: user_data,
+            user_data: REMOVE_TAG,
+            ..Sqe::default()
+        })
+    }
+}
+
+impl Backend for UringBackend {
+    fn name(&self) -> &'static str {
+        "io_uring"
+    }
+
+    fn register(
+        &mut self,
+        raw_fd: RawFd,
+        token: Token,
+        interest: Interest,
+        _trigger: Trigger,
+    ) -> IoResult<()> {
+        if self.polls.contains_key(&raw_fd) {
+            return Err(Error::from_raw_os_error(libc::EEXIST));
+        }
+        self.polls.insert(
+            raw_fd,
+            Poll {
+                token,
+                events: Trigger::Level.events(interest),
+                armed: None,
+            },
+        );
+        Ok(())
+    }
+
+    fn modify(
+        &mut self,
+        raw_fd: RawFd,
+        token: Token,
+        interest: Interest,
+        _trigger: Trigger,
+    ) -> IoResult<()> {
+        if !self.polls.contains_key(&raw_fd) {
+            return Err(Error::from_raw_os_error(libc::ENOENT));
+        }
+        self.disarm(raw_fd)?;
+        if let Some(poll) = self.polls.get_mut(&raw_fd) {
+            poll.token = token;
+            poll.events = Trigger::Level.events(interest);
+        }
+        Ok(())
+    }
+
+    fn deregister(&mut self, raw_fd: RawFd) -> IoResult<()> {
+        if !self.polls.contains_key(&raw_fd) {
+            return Err(Error::from_raw_os_error(libc::ENOENT));
+        }
+        self.disarm(raw_fd)?;
+        self.polls.remove(&raw_fd);
+        // the poll holds the file open, remove it before the fd gets closed
+        self.enter(0, None)
+    }
+
+    fn poll(&mut self, events: &mut Vec<Event>, timeout: i32) -> IoResult<()> {
+        let idle: Vec<RawFd> = self
+            .polls
+            .iter()
+            .filter(|(_, poll)| poll.armed.is_none())
+            .map(|(raw_fd, _)| *raw_fd)
+            .collect();
+        for raw_fd in idle {
+            let user_data = self.next_user_data;
+            self.next_user_data = (self.next_user_data + 1) & !SEND_TAG;
+            let poll_events = self.polls[&raw_fd].events;
+            self.push(Sqe {
+                opcode: IORING_OP_POLL_ADD,
+                fd: raw_fd,
+                op_flags: poll_events,
+                user_data,
+                ..Sqe::default()
+            })?;
+            self.armed.insert(user_data, raw_fd);
+            if let Some(poll) = self.polls.get_mut(&raw_fd) {
+                poll.armed = Some(user_data);
+            }
+        }
+
+        events.append(&mut self.ready);
+        match timeout {
+            _ if !events.is_empty() => self.enter(0, None)?,
+            0 => self.enter(0, None)?,
+            timeout if timeout < 0 => self.enter(1, None)?,
+            timeout => self.enter(1, Some(timeout))?,
+        }
+        self.reap(events, &mut []);
+        Ok(())
+    }
+
+    fn send_batch(&mut self, sends: &[(RawFd, &[IoSlice])]) -> Vec<IoResult<usize>> {
+        let mut results: Vec<Option<IoResult<usize>>> = sends.iter().map(|_| None).collect();
+        // like the buffers, the headers are read by the kernel until completion
+        let headers: Vec<libc::msghdr> = sends.iter().map(|(_, data)| msghdr(data)).collect();
+        let mut submitted = 0;
+        for (index, ((raw_fd, _), header)) in sends.iter().zip(&headers).enumerate() {
+            let pushed = self.push(Sqe {
+                opcode: IORING_OP_SENDMSG,
+                fd: *raw_fd,
+                addr: header as *const libc::msghdr as u64,
+                len: 1,
+                // MSG_DONTWAIT makes a full socket complete with EAGAIN instead of waiting
+                op_flags: (libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL) as u32,
+                user_data: SEND_TAG | index as u64,
+                ..Sqe::default()
+            });
+            if let Err(err) = pushed {
+                results[index] = Some(Err(err));
+                break;
+            }
+            submitted = index + 1;
+        }
+
+        // the buffers must outlive the requests, wait for every completion
+        let mut ready = mem::take(&mut self.ready);
+        let mut failures = 0;
+        while results[..submitted].iter().any(Option::is_none) {
+            match self.enter(1, None) {
+                Ok(()) => failures = 0,
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => {
+                    failures += 1;
+                    println!("io_uring send batch wait failed:{}", err);
+                    if failures == SEND_WAIT_RETRIES {
+                        self.failed = true;
+                        break;
+                    }
+                }
+            }
+            self.reap(&mut ready, &mut results);
+        }
+        self.ready = ready;
+        if self.failed {
+            mem::forget(headers);
+        }
+
+        results
+            .into_iter()
+            .enumerate()
+            .map(|(index, result)| match result {
+                Some(result) => result,
+                // the kernel may still read the buffer
+                None if index < submitted => Err(Error::from_raw_os_error(libc::EINPROGRESS)),
+                None => Err(my_error("send not submitted")),
+            })
+            .collect()
+    }
+
+    fn fallback(&mut self) -> Option<IoResult<Box<dyn Backend>>> {
+        if !self.failed {
+            return None;
+        }
+        println!("io_uring failed, go on with epoll");
+        let backend = EpollBackend::create(self.event_buffer_size);
+        Some(backend.map(|backend| Box::new(backend) as Box<dyn Backend>))
+    }
+}
+
+impl Drop for UringBackend {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn test_uring_poll_and_send() {
+        let mut backend = match UringBackend::create(8) {
+            Ok(backend) => backend,
+            Err(err) => {
+                println!("skipped, {}", err);
+                return;
+            }
+        };
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        ours.set_nonblocking(true).unwrap();
+        let fd = ours.as_raw_fd();
+        backend
+            .register(fd, Token(7), Interest::Read, Trigger::Edge)
+            .unwrap();
+
+        let mut events = Vec::new();
+        backend.poll(&mut events, 0).unwrap();
+        assert!(events.is_empty());
+        theirs.write_all(b"ping").unwrap();
+        backend.poll(&mut events, 1000).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].token, Token(7));
+        assert_ne!(events[0].events & libc::EPOLLIN as u32, 0);
+
+        // reported again while unread
+        events.clear();
+        backend.poll(&mut events, 1000).unwrap();
+        assert_eq!(events.len(), 1);
+
+        let hello = [IoSlice::new(b"he"), IoSlice::new(b"llo")];
+        let world = [IoSlice::new(b" world")];
+        let results = backend.send_batch(&[(fd, &hello), (fd, &world)]);
+        assert_eq!(results[0].as_ref().unwrap(), &5);
+        assert_eq!(results[1].as_ref().unwrap(), &6);
+        let mut buf = [0; 11];
+        theirs.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello world");
+
+        backend.deregister(fd).unwrap();
+        events.clear();
+        backend.poll(&mut events, 0).unwrap();
+        assert!(events.is_empty());
+    }
+}
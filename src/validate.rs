@@ -0,0 +1,634 @@
+use crate::flv::{
+    next_tag_boundary, parse_script_data, ScriptData, FLV_HEADER_LEN, PRE_TAG_SIZE_LEN,
+    TAG_HEADER_LEN,
+};
+use crate::flv_demuxer::{
+    SOUND_FORMAT_AAC, TAG_TYPE_AUDIO, TAG_TYPE_SCRIPT, TAG_TYPE_VIDEO, VIDEO_CODEC_AVC,
+};
+use std::convert::TryInto;
+use std::fmt;
+
+/// how far onMetaData duration may be off the timestamps
+const DURATION_TOLERANCE_MS: f64 = 1000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// players cope, but the file is not what it claims to be
+    Warning,
+    /// the file breaks the spec
+    Error,
+}
+
+impl Severity {
+    pub fn name(self) -> &'static str {
+        match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+/// One problem found in a file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Issue {
+    /// of the byte or the tag the issue is about
+    pub offset: usize,
+    pub severity: Severity,
+    /// short name of the check, e.g. prev_tag_size
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>10} {:<7} {:<20} {}",
+            self.offset,
+            self.severity.name(),
+            self.code,
+            self.message
+        )
+    }
+}
+
+/// All issues of a file, ordered by offset
+#[derive(Debug, Default)]
+pub struct Report {
+    pub tags: usize,
+    pub issues: Vec<Issue>,
+}
+
+impl Report {
+    pub fn count(&self, severity: Severity) -> usize {
+        self.issues
+            .iter()
+            .filter(|issue| issue.severity == severity)
+            .count()
+    }
+
+    fn add(&mut self, offset: usize, severity: Severity, code: &'static str, message: String) {
+        self.issues.push(Issue {
+            offset,
+            severity,
+            code,
+            message,
+        });
+    }
+}
+
+/// the audio or the video tags seen so far
+#[derive(Debug, Default)]
+struct Track {
+    tags: usize,
+    codec: Option<u8>,
+    sequence_header: bool,
+    // a missing sequence header is reported once per track
+    missing_reported: bool,
+    first_timestamp: Option<u32>,
+    last_timestamp: u32,
+}
+
+impl Track {
+    fn add(&mut self, report: &mut Report, name: &str, offset: usize, timestamp: u32, codec: u8) {
+        if self.tags > 0 && timestamp < self.last_timestamp {
+            report.add(
+                offset,
+                Severity::Error,
+                "timestamp_backward",
+                format!(
+                    "{} timestamp {} goes back from {}",
+                    name, timestamp, self.last_timestamp
+                ),
+            );
+        }
+        match self.codec {
+            Some(last) if last != codec => report.add(
+                offset,
+                Severity::Warning,
+                "codec_change",
+                format!("{} codec changes from {} to {}", name, last, codec),
+            ),
+            _ => self.codec = Some(codec),
+        }
+        self.tags += 1;
+        self.first_timestamp.get_or_insert(timestamp);
+        self.last_timestamp = self.last_timestamp.max(timestamp);
+    }
+
+    /// packet_type 0 is the sequence header, 1 the coded frames that need it
+    fn packet(&mut self, report: &mut Report, name: &str, offset: usize, packet_type: u8) {
+        if packet_type == 0 {
+            self.sequence_header = true;
+        } else if packet_type == 1 && !self.sequence_header && !self.missing_reported {
+            self.missing_reported = true;
+            report.add(
+                offset,
+                Severity::Error,
+                "missing_sequence_header",
+                format!("{} frames before any sequence header", name),
+            );
+        }
+    }
+}
+
+fn be_u32(data: &[u8]) -> u32 {
+    u32::from_be_bytes(data[..4].try_into().unwrap())
+}
+
+/// Check a whole file, going on after every problem the framing survives
+pub fn validate(file: &[u8]) -> Report {
+    let mut report = Report::default();
+    if file.len() < FLV_HEADER_LEN || &file[0..3] != b"FLV" {
+        report.add(0, Severity::Error, "header", "no FLV header".to_owned());
+        return report;
+    }
+    if file[3] != 1 {
+        report.add(
+            3,
+            Severity::Warning,
+            "version",
+            format!("version {}, expect 1", file[3]),
+        );
+    }
+    let flags = file[4];
+    if flags & 0b1111_1010 != 0 {
+        report.add(
+            4,
+            Severity::Error,
+            "reserved_bits",
+            format!("header flags {:#010b} have reserved bits set", flags),
+        );
+    }
+    let data_offset = be_u32(&file[5..9]) as usize;
+    if data_offset < FLV_HEADER_LEN || data_offset + PRE_TAG_SIZE_LEN > file.len() {
+        report.add(
+            5,
+            Severity::Error,
+            "header",
+            format!("data offset {} is outside the file", data_offset),
+        );
+        return report;
+    }
+    if be_u32(&file[data_offset..]) != 0 {
+        report.add(
+            data_offset,
+            Severity::Warning,
+            "prev_tag_size",
+            format!(
+                "first PreviousTagSize {} is not 0",
+                be_u32(&file[data_offset..])
+            ),
+        );
+    }
+
+    let mut audio = Track::default();
+    let mut video = Track::default();
+    let mut metadata = None;
+    let mut pos = data_offset + PRE_TAG_SIZE_LEN;
+    while pos < file.len() {
+        if file.len() - pos < TAG_HEADER_LEN {
+            report.add(
+                pos,
+                Severity::Error,
+                "truncated",
+                format!("{} bytes after the last tag", file.len() - pos),
+            );
+            break;
+        }
+        let header = &file[pos..pos + TAG_HEADER_LEN];
+        let tag_type = header[0] & 0x1f;
+        let data_size = be_u32(&[0, header[1], header[2], header[3]]) as usize;
+        let timestamp = be_u32(&[header[7], header[4], header[5], header[6]]);
+        let tag_len = TAG_HEADER_LEN + data_size;
+        let fits = file.len() - pos >= tag_len + PRE_TAG_SIZE_LEN;
+        if tag_type != TAG_TYPE_AUDIO && tag_type != TAG_TYPE_VIDEO && tag_type != TAG_TYPE_SCRIPT {
+            report.add(
+                pos,
+                Severity::Error,
+                "tag_type",
+                format!("unknown tag type {}", tag_type),
+            );
+            // only a matching PreviousTagSize shows the size can be trusted
+            if !fits || be_u32(&file[pos + tag_len..]) as usize != tag_len {
+                match resync(&mut report, file, pos) {
+                    Some(next) => pos = next,
+                    None => break,
+                }
+                continue;
+            }
+        }
+        if !fits {
+            // a broken DataSize, unless nothing that looks like a tag follows
+            if next_tag_boundary(file, pos).is_some() {
+                report.add(
+                    pos,
+                    Severity::Error,
+                    "data_size",
+                    format!("tag of {} bytes runs past the end of the file", tag_len),
+                );
+                pos = resync(&mut report, file, pos).unwrap_or(file.len());
+                continue;
+            }
+            report.add(
+                pos,
+                Severity::Error,
+                "truncated",
+                format!("tag of {} bytes runs past the end of the file", tag_len),
+            );
+            break;
+        }
+        let pre_tag_size = be_u32(&file[pos + tag_len..]) as usize;
+        if header[0] & 0xc0 != 0 {
+            report.add(
+                pos,
+                Severity::Error,
+                "reserved_bits",
+                format!("tag type byte {:#010b} has reserved bits set", header[0]),
+            );
+        }
+        if header[0] & 0x20 != 0 {
+            report.add(
+                pos,
+                Severity::Warning,
+                "encrypted",
+                "tag is filtered (encrypted)".to_owned(),
+            );
+        }
+        if header[8..11] != [0, 0, 0] {
+            report.add(
+                pos + 8,
+                Severity::Error,
+                "stream_id",
+                format!(
+                    "StreamID {} is not 0",
+                    be_u32(&[0, header[8], header[9], header[10]])
+                ),
+            );
+        }
+        if pre_tag_size != tag_len {
+            report.add(
+                pos + tag_len,
+                Severity::Error,
+                "prev_tag_size",
+                format!(
+                    "PreviousTagSize {} is not the tag size {}",
+                    pre_tag_size, tag_len
+                ),
+            );
+        }
+
+        let body = &file[pos + TAG_HEADER_LEN..pos + tag_len];
+        match tag_type {
+            TAG_TYPE_VIDEO => check_video(&mut report, &mut video, pos, timestamp, body),
+            TAG_TYPE_AUDIO => check_audio(&mut report, &mut audio, pos, timestamp, body),
+            TAG_TYPE_SCRIPT => match parse_script_data(body) {
+                Ok(data) if data.name == "onMetaData" && metadata.is_none() => {
+                    metadata = Some((pos, data));
+                }
+                Ok(_) => (),
+                Err(err) => report.add(
+                    pos,
+                    Severity::Error,
+                    "script_data",
+                    format!("script data does not parse: {}", err),
+                ),
+            },
+            _ => (),
+        }
+        report.tags += 1;
+        pos += tag_len + PRE_TAG_SIZE_LEN;
+    }
+
+    check_header_flags(&mut report, flags, &audio, &video);
+    match metadata {
+        Some((offset, data)) => {
+            check_metadata(&mut report, offset, &data, file.len(), &audio, &video)
+        }
+        None => report.add(0, Severity::Warning, "metadata", "no onMetaData".to_owned()),
+    }
+    report.issues.sort_by_key(|issue| issue.offset);
+    report
+}
+
+/// Report the bytes from pos the framing does not explain,
+/// returns the next tag boundary to go on from
+fn resync(report: &mut Report, file: &[u8], pos: usize) -> Option<usize> {
+    let next = next_tag_boundary(file, pos);
+    let message = match next {
+        Some(next) => format!("{} bytes skipped to the next tag boundary", next - pos),
+        None => "no tag boundary to go on from, the rest of the file is not checked".to_owned(),
+    };
+    report.add(pos, Severity::Error, "framing", message);
+    next
+}
+
+fn check_video(report: &mut Report, track: &mut Track, offset: usize, timestamp: u32, body: &[u8]) {
+    let flags = match body.first() {
+        Some(&flags) => flags,
+        None => {
+            report.add(
+                offset,
+                Severity::Error,
+                "body",
+                "empty video tag".to_owned(),
+            );
+            return;
+        }
+    };
+    let frame_type = flags >> 4;
+    let codec = flags & 0x0f;
+    if !(1..=5).contains(&frame_type) {
+        report.add(
+            offset,
+            Severity::Error,
+            "body",
+            format!("video frame type {}", frame_type),
+        );
+    }
+    track.add(report, "video", offset, timestamp, codec);
+    // command frames carry no packet
+    if codec != VIDEO_CODEC_AVC || frame_type == 5 {
+        return;
+    }
+    if body.len() < 5 {
+        report.add(
+            offset,
+            Severity::Error,
+            "body",
+            format!("AVC video tag of {} bytes", body.len()),
+        );
+        return;
+    }
+    if body[1] > 2 {
+        report.add(
+            offset,
+            Severity::Error,
+            "body",
+            format!("AVC packet type {}", body[1]),
+        );
+    }
+    track.packet(report, "video", offset, body[1]);
+}
+
+fn check_audio(report: &mut Report, track: &mut Track, offset: usize, timestamp: u32, body: &[u8]) {
+    let flags = match body.first() {
+        Some(&flags) => flags,
+        None => {
+            report.add(
+                offset,
+                Severity::Error,
+                "body",
+                "empty audio tag".to_owned(),
+            );
+            return;
+        }
+    };
+    let format = flags >> 4;
+    track.add(report, "audio", offset, timestamp, format);
+    if format != SOUND_FORMAT_AAC {
+        return;
+    }
+    if body.len() < 2 {
+        report.add(
+            offset,
+            Severity::Error,
+            "body",
+            "AAC audio tag of 1 byte".to_owned(),
+        );
+        return;
+    }
+    if body[1] > 1 {
+        report.add(
+            offset,
+            Severity::Error,
+            "body",
+            format!("AAC packet type {}", body[1]),
+        );
+    }
+    track.packet(report, "audio", offset, body[1]);
+}
+
+fn check_header_flags(report: &mut Report, flags: u8, audio: &Track, video: &Track) {
+    for (name, flag, track) in &[("audio", 0b100, audio), ("video", 0b001, video)] {
+        match (flags & flag != 0, track.tags > 0) {
+            (true, false) => report.add(
+                4,
+                Severity::Warning,
+                "header_flags",
+                format!("header flags {} but there are no {} tags", name, name),
+            ),
+            (false, true) => report.add(
+                4,
+                Severity::Error,
+                "header_flags",
+                format!("{} {} tags but header flags no {}", track.tags, name, name),
+            ),
+            _ => (),
+        }
+    }
+}
+
+fn check_metadata(
+    report: &mut Report,
+    offset: usize,
+    metadata: &ScriptData,
+    file_size: usize,
+    audio: &Track,
+    video: &Track,
+) {
+    let mut mismatch = |code, message| report.add(offset, Severity::Warning, code, message);
+
+    let first = audio
+        .first_timestamp
+        .into_iter()
+        .chain(video.first_timestamp)
+        .min();
+    let last = audio.last_timestamp.max(video.last_timestamp);
+    if let (Some(&duration), Some(first)) = (metadata.numbers.get("duration"), first) {
+        let actual = (last - first) as f64;
+        if (duration * 1000.0 - actual).abs() > DURATION_TOLERANCE_MS {
+            mismatch(
+                "metadata_duration",
+                format!(
+                    "duration {}s, the timestamps span {}s",
+                    duration,
+                    actual / 1000.0
+                ),
+            );
+        }
+    }
+    if let Some(&filesize) = metadata.numbers.get("filesize") {
+        if filesize > 0.0 && filesize as usize != file_size {
+            mismatch(
+                "metadata_filesize",
+                format!("filesize {}, the file has {} bytes", filesize, file_size),
+            );
+        }
+    }
+    for (name, track) in &[("Audio", audio), ("Video", video)] {
+        let key = format!("has{}", name);
+        if let Some(&has) = metadata.booleans.get(&key) {
+            if has != (track.tags > 0) {
+                mismatch(
+                    "metadata_tracks",
+                    format!(
+                        "{} {}, there are {} {} tags",
+                        key,
+                        has,
+                        track.tags,
+                        name.to_lowercase()
+                    ),
+                );
+            }
+        }
+        let key = format!("{}codecid", name.to_lowercase());
+        if let (Some(&codec), Some(actual)) = (metadata.numbers.get(&key), track.codec) {
+            if codec != actual as f64 {
+                mismatch(
+                    "metadata_codec",
+                    format!("{} {}, the tags have codec {}", key, codec, actual),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flv_demuxer::{make_tag, FlvHeader};
+
+    /// onMetaData with duration and hasAudio as an ECMA array
+    fn metadata(duration: f64, has_audio: bool) -> Vec<u8> {
+        let mut body = vec![2, 0, 10];
+        body.extend_from_slice(b"onMetaData");
+        body.extend_from_slice(&[8, 0, 0, 0, 2]);
+        body.extend_from_slice(&[0, 8]);
+        body.extend_from_slice(b"duration");
+        body.push(0);
+        body.extend_from_slice(&duration.to_be_bytes());
+        body.extend_from_slice(&[0, 8]);
+        body.extend_from_slice(b"hasAudio");
+        body.extend_from_slice(&[1, has_audio as u8]);
+        body.extend_from_slice(&[0, 0, 9]);
+        make_tag(TAG_TYPE_SCRIPT, 0, &body)
+    }
+
+    fn codes(report: &Report) -> Vec<(usize, &'static str)> {
+        report
+            .issues
+            .iter()
+            .map(|issue| (issue.offset, issue.code))
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_file() {
+        let mut file = FlvHeader {
+            version: 1,
+            has_audio: true,
+            has_video: true,
+        }
+        .to_bytes();
+        file.extend(metadata(0.04, true));
+        file.extend(make_tag(TAG_TYPE_VIDEO, 0, &[0x17, 0, 0, 0, 0, 1]));
+        file.extend(make_tag(TAG_TYPE_AUDIO, 0, &[0xaf, 0, 0x12, 0x10]));
+        file.extend(make_tag(TAG_TYPE_VIDEO, 0, &[0x17, 1, 0, 0, 0, 1]));
+        file.extend(make_tag(TAG_TYPE_AUDIO, 20, &[0xaf, 1, 9]));
+        file.extend(make_tag(TAG_TYPE_VIDEO, 40, &[0x27, 1, 0, 0, 0, 1]));
+        let report = validate(&file);
+        assert_eq!(report.tags, 6);
+        assert_eq!(report.issues, vec![]);
+    }
+
+    #[test]
+    fn test_all_issues_reported() {
+        let mut file = FlvHeader {
+            version: 1,
+            has_audio: false,
+            has_video: true,
+        }
+        .to_bytes();
+        let metadata_at = file.len();
+        file.extend(metadata(60.0, false));
+        // frames before the sequence header
+        let nalu_at = file.len();
+        file.extend(make_tag(TAG_TYPE_VIDEO, 40, &[0x17, 1, 0, 0, 0, 1]));
+        // mp3 audio the header flags leave out
+        file.extend(make_tag(TAG_TYPE_AUDIO, 0, &[0x2f, 1]));
+        // stream id 1 and a PreviousTagSize one too big
+        let bad_at = file.len();
+        let mut tag = make_tag(TAG_TYPE_VIDEO, 0, &[0x27, 1, 0, 0, 0, 1]);
+        tag[10] = 1;
+        let last = tag.len() - 1;
+        tag[last] += 1;
+        file.extend(tag);
+        file.extend(make_tag(TAG_TYPE_VIDEO, 80, &[0x17, 0, 0, 0, 0, 1]));
+        // half a tag header at the end
+        let end = file.len();
+        file.extend(&[9, 0, 0]);
+
+        let report = validate(&file);
+        assert_eq!(report.tags, 5);
+        assert_eq!(
+            codes(&report),
+            vec![
+                (4, "header_flags"),
+                (metadata_at, "metadata_duration"),
+                (metadata_at, "metadata_tracks"),
+                (nalu_at, "missing_sequence_header"),
+                (bad_at, "timestamp_backward"),
+                (bad_at + 8, "stream_id"),
+                (bad_at + 17, "prev_tag_size"),
+                (end, "truncated"),
+            ]
+        );
+        assert_eq!(report.count(Severity::Error), 6);
+    }
+
+    #[test]
+    fn test_lost_framing() {
+        let mut file = FlvHeader {
+            version: 1,
+            has_audio: true,
+            has_video: false,
+        }
+        .to_bytes();
+        file[4] |= 0x80;
+        file.extend(make_tag(TAG_TYPE_AUDIO, 100, &[0x2f, 1]));
+        let garbage_at = file.len();
+        file.extend(&[0x55; 40]);
+        // checked after the garbage
+        let backward_at = file.len();
+        file.extend(make_tag(TAG_TYPE_AUDIO, 50, &[0x2f, 1]));
+        // a DataSize past the end of the file
+        let oversized_at = file.len();
+        let mut tag = make_tag(TAG_TYPE_AUDIO, 60, &[0x2f, 1]);
+        tag[1..4].copy_from_slice(&[0xff; 3]);
+        file.extend(tag);
+        file.extend(make_tag(TAG_TYPE_AUDIO, 150, &[0xaf]));
+        let trailing_at = file.len();
+        file.extend(&[0x55; 20]);
+        let report = validate(&file);
+        assert_eq!(report.tags, 3);
+        assert_eq!(
+            codes(&report),
+            vec![
+                (0, "metadata"),
+                (4, "reserved_bits"),
+                (garbage_at, "tag_type"),
+                (garbage_at, "framing"),
+                (backward_at, "timestamp_backward"),
+                (oversized_at, "data_size"),
+                (oversized_at, "framing"),
+                (oversized_at + 17, "codec_change"),
+                (oversized_at + 17, "body"),
+                (trailing_at, "tag_type"),
+                (trailing_at, "framing"),
+            ]
+        );
+        assert!(report.issues[3].message.starts_with("40 bytes skipped"));
+        assert!(report.issues[10].message.contains("not checked"));
+    }
+}
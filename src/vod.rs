@@ -0,0 +1,251 @@
+use crate::my_error::my_error;
+use std::fs::File;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result as IoResult;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Component, Path, PathBuf};
+use std::ptr;
+
+/// bytes handed to one sendfile/splice call
+const SEND_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// The file for GET /vod/<name>, a path below root.
+/// None for other paths or names trying to leave root.
+pub fn vod_file_path(root: &str, path: &str) -> Option<PathBuf> {
+    let name = path.strip_prefix("/vod/")?;
+    if name.is_empty() {
+        return None;
+    }
+    let relative = Path::new(name);
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return None;
+    }
+    Some(Path::new(root).join(relative))
+}
+
+pub fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("flv") => "video/x-flv",
+        Some("mp4") | Some("m4v") => "video/mp4",
+        Some("m4a") => "audio/mp4",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Range header value of a file of len bytes to the inclusive byte range asked for.
+/// Ok(None) means the whole file: no or unsupported range, e.g. several of them.
+/// Err when nothing of the file is in range, answered with 416.
+pub fn parse_range(value: &str, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match value.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+    let (first, last) = match spec.split_once('-') {
+        Some(pair) => pair,
+        None => return Ok(None),
+    };
+    let range = match (first.parse::<u64>(), last.parse::<u64>()) {
+        (Ok(first), Ok(last)) if first <= last => (first, last.min(len.saturating_sub(1))),
+        (Ok(first), Err(_)) if last.is_empty() => (first, len.saturating_sub(1)),
+        // the last n bytes
+        (Err(_), Ok(suffix)) if first.is_empty() && suffix > 0 => {
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        }
+        _ => return Ok(None),
+    };
+    if range.0 >= len {
+        return Err(());
+    }
+    Ok(Some(range))
+}
+
+/// Part of a file sent to a socket with sendfile, resumed after partial sends.
+/// Files sendfile refuses go through a pipe with splice instead.
+#[derive(Debug)]
+pub struct FileBody {
+    file: File,
+    offset: u64,
+    end: u64,
+    // read end, write end and bytes waiting in it, once splice is used
+    pipe: Option<(RawFd, RawFd, usize)>,
+}
+
+impl FileBody {
+    /// bytes first..=last of file
+    pub fn new(file: File, first: u64, last: u64) -> Self {
+        Self {
+            file,
+            offset: first,
+            end: last + 1,
+            pipe: None,
+        }
+    }
+
+    /// bytes not sent yet
+    pub fn remaining(&self) -> u64 {
+        self.end - self.offset + self.pipe.map_or(0, |(_, _, piped)| piped as u64)
+    }
+
+    /// Send until the socket would block, true once everything is sent.
+    pub fn send_to(&mut self, socket_fd: RawFd) -> IoResult<bool> {
+        while self.remaining() > 0 {
+            let sent = match self.pipe {
+                Some(_) => self.splice_to(socket_fd),
+                None => self.sendfile_to(socket_fd),
+            };
+            match sent {
+                Ok(()) => (),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(true)
+    }
+
+    fn sendfile_to(&mut self, socket_fd: RawFd) -> IoResult<()> {
+        let count = (self.end - self.offset).min(SEND_CHUNK_SIZE as u64) as usize;
+        let mut offset = self.offset as libc::off_t;
+        let res = unsafe { libc::sendfile(socket_fd, self.file.as_raw_fd(), &mut offset, count) };
+        if res == -1 {
+            let err = Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::EINVAL) | Some(libc::ENOSYS) => self.open_pipe(),
+                _ => Err(err),
+            };
+        }
+        if res == 0 {
+            return Err(my_error("file shorter than its response"));
+        }
+        self.offset = offset as u64;
+        Ok(())
+    }
+
+    fn open_pipe(&mut self) -> IoResult<()> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } == -1 {
+            return Err(Error::last_os_error());
+        }
+        self.pipe = Some((fds[0], fds[1], 0));
+        Ok(())
+    }
+
+    /// move data file -> pipe -> socket, the pipe is drained first
+    fn splice_to(&mut self, socket_fd: RawFd) -> IoResult<()> {
+        let (read_fd, write_fd, piped) = match self.pipe {
+            Some(pipe) => pipe,
+            None => return Err(my_error("splice without pipe")),
+        };
+        let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+        if piped == 0 {
+            let count = (self.end - self.offset).min(SEND_CHUNK_SIZE as u64) as usize;
+            let mut offset = self.offset as libc::loff_t;
+            let res = unsafe {
+                libc::splice(
+                    self.file.as_raw_fd(),
+                    &mut offset,
+                    write_fd,
+                    ptr::null_mut(),
+                    count,
+                    flags,
+                )
+            };
+            match res {
+                -1 => return Err(Error::last_os_error()),
+                0 => return Err(my_error("file shorter than its response")),
+                _ => (),
+            }
+            self.offset = offset as u64;
+            self.pipe = Some((read_fd, write_fd, res as usize));
+            return Ok(());
+        }
+        let res = unsafe {
+            libc::splice(
+                read_fd,
+                ptr::null_mut(),
+                socket_fd,
+                ptr::null_mut(),
+                piped,
+                flags,
+            )
+        };
+        if res == -1 {
+            return Err(Error::last_os_error());
+        }
+        self.pipe = Some((read_fd, write_fd, piped - res as usize));
+        Ok(())
+    }
+}
+
+impl Drop for FileBody {
+    fn drop(&mut self) {
+        if let Some((read_fd, write_fd, _)) = self.pipe {
+            unsafe {
+                libc::close(read_fd);
+                libc::close(write_fd);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn test_vod_file_path() {
+        assert_eq!(
+            vod_file_path("/srv/vod", "/vod/a/b.flv"),
+            Some(PathBuf::from("/srv/vod/a/b.flv"))
+        );
+        assert_eq!(vod_file_path("/srv/vod", "/vod/../etc/passwd"), None);
+        assert_eq!(vod_file_path("/srv/vod", "/vod//etc/passwd"), None);
+        assert_eq!(vod_file_path("/srv/vod", "/vod/"), None);
+        assert_eq!(vod_file_path("/srv/vod", "/live/a.flv"), None);
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Ok(Some((0, 99))));
+        assert_eq!(parse_range("bytes=900-", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=990-2000", 1000), Ok(Some((990, 999))));
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000), Ok(None));
+        assert_eq!(parse_range("items=0-1", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=1000-", 1000), Err(()));
+    }
+
+    #[test]
+    fn test_send_file_both_ways() {
+        let path = std::env::temp_dir().join(format!("vod-test-{}.flv", std::process::id()));
+        let data: Vec<u8> = (0..300_000u32).map(|i| i as u8).collect();
+        fs::write(&path, &data).unwrap();
+
+        for splice in [false, true] {
+            let (ours, mut theirs) = UnixStream::pair().unwrap();
+            ours.set_nonblocking(true).unwrap();
+            let mut body = FileBody::new(File::open(&path).unwrap(), 10, 250_009);
+            if splice {
+                body.open_pipe().unwrap();
+            }
+            let mut received = Vec::new();
+            let mut buf = vec![0; 64 * 1024];
+            // the socket buffer fills up long before the end, sending resumes after reads
+            while !body.send_to(ours.as_raw_fd()).unwrap() {
+                let size = theirs.read(&mut buf).unwrap();
+                received.extend_from_slice(&buf[..size]);
+            }
+            drop(ours);
+            theirs.read_to_end(&mut received).unwrap();
+            assert_eq!(received, &data[10..250_010]);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,313 @@
+use crate::http_request::HttpReq;
+use crate::my_error::my_error;
+use std::convert::TryInto;
+use std::io::Result as IoResult;
+
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// frames from players are tiny control frames, anything bigger is refused
+const MAX_CLIENT_FRAME_SIZE: usize = 64 * 1024;
+
+pub const OPCODE_BINARY: u8 = 0x2;
+pub const OPCODE_CLOSE: u8 = 0x8;
+pub const OPCODE_PING: u8 = 0x9;
+pub const OPCODE_PONG: u8 = 0xa;
+
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+
+/// Sec-WebSocket-Accept for a Sec-WebSocket-Key when req is a valid upgrade request,
+/// None when it is a plain http request
+pub fn upgrade_accept_key(req: &HttpReq) -> IoResult<Option<String>> {
+    let upgrade = match req.header("upgrade") {
+        Some(upgrade) => upgrade,
+        None => return Ok(None),
+    };
+    if !upgrade.eq_ignore_ascii_case("websocket") {
+        return Err(my_error(format!("upgrade to {} not supported", upgrade)));
+    }
+    let has_upgrade_token = req
+        .headers
+        .iter()
+        .filter(|(key, _)| key.eq_ignore_ascii_case("connection"))
+        .flat_map(|(_, val)| val.split(','))
+        .any(|item| item.trim().eq_ignore_ascii_case("upgrade"));
+    if !has_upgrade_token || req.minor_version == 0 {
+        return Err(my_error("websocket upgrade without Connection: Upgrade"));
+    }
+    if req.header("sec-websocket-version") != Some("13") {
+        return Err(my_error(format!(
+            "websocket version {:?} not supported",
+            req.header("sec-websocket-version")
+        )));
+    }
+    match req.header("sec-websocket-key") {
+        Some(key) if !key.is_empty() => Ok(Some(accept_key(key))),
+        _ => Err(my_error("websocket upgrade without Sec-WebSocket-Key")),
+    }
+}
+
+pub fn accept_key(key: &str) -> String {
+    let mut data = key.as_bytes().to_vec();
+    data.extend_from_slice(WEBSOCKET_GUID.as_bytes());
+    base64_encode(&sha1(&data))
+}
+
+/// append one unmasked, unfragmented server frame
+pub fn write_frame(out: &mut Vec<u8>, opcode: u8, payload: &[u8]) {
+    write_frame_header(out, opcode, payload.len());
+    out.extend_from_slice(payload);
+}
+
+/// head of a final frame whose len bytes of payload follow separately
+pub fn write_frame_header(out: &mut Vec<u8>, opcode: u8, len: usize) {
+    out.push(0x80 | opcode);
+    match len {
+        len if len < 126 => out.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            out.push(126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+}
+
+pub fn write_close_frame(out: &mut Vec<u8>, code: u16) {
+    write_frame(out, OPCODE_CLOSE, &code.to_be_bytes());
+}
+
+/// One frame from the client, payload already unmasked
+#[derive(Debug)]
+pub struct WsFrame {
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Incremental parser for client to server frames
+#[derive(Debug)]
+pub struct WsFrameParser {
+    buffer: Vec<u8>,
+}
+
+impl WsFrameParser {
+    pub fn new() -> Self {
+        Self { buffer: Vec::new() }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Ok(None) means more data is needed
+    pub fn next_frame(&mut self) -> IoResult<Option<WsFrame>> {
+        if self.buffer.len() < 2 {
+            return Ok(None);
+        }
+        let fin = self.buffer[0] & 0x80 != 0;
+        if self.buffer[0] & 0x70 != 0 {
+            return Err(my_error("websocket frame reserved bits set"));
+        }
+        let opcode = self.buffer[0] & 0x0f;
+        if self.buffer[1] & 0x80 == 0 {
+            return Err(my_error("websocket client frame not masked"));
+        }
+
+        let (payload_len, mut pos) = match self.buffer[1] & 0x7f {
+            126 => {
+                if self.buffer.len() < 4 {
+                    return Ok(None);
+                }
+                (
+                    u16::from_be_bytes([self.buffer[2], self.buffer[3]]) as u64,
+                    4,
+                )
+            }
+            127 => {
+                if self.buffer.len() < 10 {
+                    return Ok(None);
+                }
+                (
+                    u64::from_be_bytes(self.buffer[2..10].try_into().unwrap()),
+                    10,
+                )
+            }
+            len => (len as u64, 2),
+        };
+        if payload_len > MAX_CLIENT_FRAME_SIZE as u64 {
+            return Err(my_error(format!(
+                "websocket client frame too large:{}",
+                payload_len
+            )));
+        }
+        let payload_len = payload_len as usize;
+        if opcode >= OPCODE_CLOSE && (!fin || payload_len > 125) {
+            return Err(my_error("websocket control frame fragmented or too large"));
+        }
+
+        if self.buffer.len() < pos + 4 + payload_len {
+            return Ok(None);
+        }
+        let mask: [u8; 4] = self.buffer[pos..pos + 4].try_into().unwrap();
+        pos += 4;
+        let mut payload = self.buffer[pos..pos + payload_len].to_vec();
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        self.buffer.drain(0..pos + payload_len);
+
+        Ok(Some(WsFrame { opcode, payload }))
+    }
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+
+    let mut digest = [0u8; 20];
+    for (i, word) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for group in data.chunks(3) {
+        let bytes = [
+            group[0],
+            group.get(1).copied().unwrap_or(0),
+            group.get(2).copied().unwrap_or(0),
+        ];
+        let triple = ((bytes[0] as u32) << 16) | ((bytes[1] as u32) << 8) | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= group.len() {
+                let index = (triple >> (18 - 6 * i)) & 0x3f;
+                encoded.push(ALPHABET[index as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(data: &[u8]) -> String {
+        data.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn test_sha1_and_base64() {
+        assert_eq!(
+            hex(&sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(
+            hex(&sha1(&[b'a'; 1000])),
+            "291e9a6c66994949b57ba5e650361e98fc36b1ba"
+        );
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // example from RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_parse_masked_frames() {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut raw = vec![0x89, 0x85];
+        raw.extend_from_slice(&mask);
+        for (i, byte) in b"Hello".iter().enumerate() {
+            raw.push(byte ^ mask[i % 4]);
+        }
+        raw.extend_from_slice(&[0x88, 0x82, 0, 0, 0, 0, 0x03, 0xe8]);
+
+        let mut parser = WsFrameParser::new();
+        parser.feed(&raw[0..4]);
+        assert!(parser.next_frame().unwrap().is_none());
+        parser.feed(&raw[4..]);
+        let ping = parser.next_frame().unwrap().unwrap();
+        assert_eq!(ping.opcode, OPCODE_PING);
+        assert_eq!(ping.payload, b"Hello");
+        let close = parser.next_frame().unwrap().unwrap();
+        assert_eq!(close.opcode, OPCODE_CLOSE);
+        assert_eq!(close.payload, 1000u16.to_be_bytes());
+        assert!(parser.next_frame().unwrap().is_none());
+
+        let mut parser = WsFrameParser::new();
+        parser.feed(&[0x82, 0x01, 0xff]);
+        assert!(parser.next_frame().is_err());
+    }
+
+    #[test]
+    fn test_write_frame_lengths() {
+        let mut out = Vec::new();
+        write_frame(&mut out, OPCODE_BINARY, &[1; 125]);
+        assert_eq!(&out[0..2], &[0x82, 125]);
+        let mut out = Vec::new();
+        write_frame(&mut out, OPCODE_BINARY, &[1; 300]);
+        assert_eq!(&out[0..4], &[0x82, 126, 0x01, 0x2c]);
+        let mut out = Vec::new();
+        write_frame(&mut out, OPCODE_BINARY, &[1; 70000]);
+        assert_eq!(&out[0..10], &[0x82, 127, 0, 0, 0, 0, 0, 1, 0x11, 0x70]);
+        assert_eq!(out.len(), 70010);
+    }
+}